@@ -0,0 +1,11 @@
+(define (fact n)
+  (if (= n 0)
+      1
+      (* n (fact (- n 1)))))
+
+(define (repeat n)
+  (if (= n 0)
+      (fact 20)
+      (begin (fact 20) (repeat (- n 1)))))
+
+(repeat 20000)
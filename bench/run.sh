@@ -0,0 +1,31 @@
+#!/bin/sh
+# Wall time of each bench/*.scm under each given rust-scm binary, as the
+# median of ROUNDS runs (default 9). Runs are interleaved across binaries,
+# so a change in machine load affects them all alike.
+#
+#     bench/run.sh target/release/rust-scm /tmp/old/rust-scm
+set -e
+rounds=${ROUNDS:-9}
+dir=$(dirname "$0")
+out=$(mktemp)
+trap 'rm -f "$out"' EXIT
+
+for i in $(seq "$rounds"); do
+    for file in "$dir"/*.scm; do
+        for bin in "$@"; do
+            start=$(date +%s%N)
+            "$bin" "$file" >/dev/null
+            end=$(date +%s%N)
+            echo "$(basename "$file") $bin $(((end - start) / 1000000))" >>"$out"
+        done
+    done
+done
+
+for file in "$dir"/*.scm; do
+    name=$(basename "$file")
+    for bin in "$@"; do
+        median=$(awk -v f="$name" -v b="$bin" '$1 == f && $2 == b { print $3 }' "$out" |
+            sort -n | sed -n "$(((rounds + 1) / 2))p")
+        printf '%-10s %-40s %6s ms\n' "$name" "$bin" "$median"
+    done
+done
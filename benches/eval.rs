@@ -0,0 +1,84 @@
+#[macro_use]
+extern crate bencher;
+extern crate rust_scm;
+
+use bencher::Bencher;
+use rust_scm::env::{Env, Globals};
+use rust_scm::{Expr, Interpreter, Symbol};
+use std::rc::Rc;
+
+fn run(b: &mut Bencher, defs: &str, exp: &str) {
+    let mut interp = Interpreter::new();
+    interp.eval_str(defs).unwrap();
+    b.iter(|| interp.eval_str(exp).unwrap());
+}
+
+fn fib(b: &mut Bencher) {
+    run(
+        b,
+        "(define (fib n) (if (< n 2) n (+ (fib (- n 1)) (fib (- n 2)))))",
+        "(fib 20)",
+    );
+}
+
+fn fact(b: &mut Bencher) {
+    run(
+        b,
+        "(define (fact n) (if (= n 0) 1 (* n (fact (- n 1)))))",
+        "(fact 20)",
+    );
+}
+
+fn lambda(b: &mut Bencher) {
+    run(
+        b,
+        "",
+        "((lambda (x y) (if (= y 0) 1 (* y (x x (- y 1)))))
+          (lambda (x y) (if (= y 0) 1 (* y (x x (- y 1))))) 5)",
+    );
+}
+
+fn eval(b: &mut Bencher) {
+    b.iter(|| {
+        let mut interp = Interpreter::new();
+        interp
+            .eval_str(
+                "(define (sum l) (if (null? l) 0 (+ (car l) (sum (cdr l)))))
+                 (let ((x 1) (y 2))
+                   (cond ((> x y) 'bigger)
+                         (else (sum (cons x (cons y '(3 4 5)))))))",
+            )
+            .unwrap()
+    });
+}
+
+fn globals(b: &mut Bencher) {
+    let mut globals = Globals::new();
+    let names: Vec<Symbol> = (0..1000).map(|i| Symbol::intern(&i.to_string())).collect();
+    for &name in &names {
+        globals.def_var(name, Expr::new_str("world"));
+    }
+    let slots: Vec<usize> = names.iter().map(|&name| globals.slot(name)).collect();
+    b.iter(|| {
+        for &slot in &slots {
+            assert!(globals.get(slot).unwrap().as_str().unwrap() == "world");
+        }
+    });
+}
+
+fn locals(b: &mut Bencher) {
+    let names = Rc::new(vec![Symbol::intern("x")]);
+    let mut env = Env::top();
+    for i in 0..10 {
+        env = Env::extend(&env, names.clone(), vec![Expr::Int(i)]);
+    }
+    b.iter(|| {
+        let env = env.borrow();
+        for depth in 0..10 {
+            assert!(env.get(depth, 0).is_some());
+        }
+    });
+}
+
+benchmark_group!(benches, fib, fact, lambda, eval, globals, locals);
+benchmark_main!(benches);
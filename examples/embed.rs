@@ -0,0 +1,67 @@
+//! Embeds the interpreter in a Rust program: exposes some host state and
+//! functions to a script, runs it, and reads its results back.
+//!
+//!     cargo run --example embed
+
+extern crate rust_scm;
+
+use rust_scm::{Arity, Expr, Interpreter, SchemeError};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::rc::Rc;
+
+const SCRIPT: &str = "
+(define (restock! item n)
+  (stock-set! item (+ (stock item) n)))
+
+(define (low-stock items)
+  (cond ((null? items) '())
+        ((< (stock (car items)) threshold)
+         (cons (car items) (low-stock (cdr items))))
+        (else (low-stock (cdr items)))))
+
+(restock! \"bolts\" 5)
+";
+
+fn main() {
+    let stock: Rc<RefCell<HashMap<String, i64>>> = Rc::new(RefCell::new(
+        vec![("bolts".to_string(), 2), ("nuts".to_string(), 40)]
+            .into_iter()
+            .collect(),
+    ));
+
+    let mut interp = Interpreter::new();
+    interp.define_global("threshold", 10);
+
+    let db = stock.clone();
+    interp.register_fn("stock", Arity::Exactly(1), move |_, args| {
+        let item = String::try_from(args[0].clone())?;
+        match db.borrow().get(&item) {
+            Some(&n) => Ok(Expr::from(n)),
+            None => Err(SchemeError::wrong_type("known item", &args[0])),
+        }
+    });
+    let db = stock.clone();
+    interp.register_fn("stock-set!", Arity::Exactly(2), move |_, args| {
+        let item = String::try_from(args[0].clone())?;
+        db.borrow_mut().insert(item, i64::try_from(args[1].clone())?);
+        Ok(Expr::from(true))
+    });
+
+    if let Err(err) = interp.eval_str(SCRIPT) {
+        eprintln!("script failed: {}", err);
+        return;
+    }
+    println!("bolts after restocking: {}", stock.borrow()["bolts"]);
+
+    let items = Expr::from(vec!["bolts", "nuts"]);
+    let low = interp.call("low-stock", vec![items]).unwrap();
+    let low = Vec::<String>::try_from(low).unwrap();
+    println!("low on stock: {:?}", low);
+
+    match interp.eval_str("(stock \"washers\")") {
+        Ok(val) => println!("washers: {}", val),
+        Err(err) => println!("error: {}", err),
+    }
+}
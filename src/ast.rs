@@ -1,274 +1,677 @@
+use compile::Lambda;
 use env;
+use error::SchemeError;
+use gc;
+use num;
+use num_bigint::BigInt;
+use num_rational::BigRational;
 use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
 use std::fmt;
 use std::rc::Rc;
+use symbol::{self, Symbol};
+use vm::{Continuation, Vm};
 
 #[derive(Clone, PartialEq, Debug)]
 pub enum Expr {
     Int(isize),
+    BigInt(BigInt),
+    Rational(BigRational),
+    Float(f64),
+    /// A string literal or other string that cannot be changed.
     Str(String),
-    Sym(String),
+    /// A string made by a procedure such as `make-string` or
+    /// `string-append`, which `string-set!` can change. It holds its
+    /// characters, so indexing one takes constant time.
+    MutStr(Rc<RefCell<Vec<char>>>),
+    Sym(Symbol),
     Bool(bool),
     Char(char),
-    Pair(Vec<Expr>),
-    Proc(ProcFunc),
-    CompProc(Vec<Expr>, Rc<RefCell<env::Env>>),
+    Pair(Rc<RefCell<[Expr; 2]>>, Loc),
+    Vector(Rc<RefCell<Vec<Expr>>>),
+    Bytevector(Rc<RefCell<Vec<u8>>>),
+    Proc(Rc<Builtin>),
+    CompProc(Rc<Lambda>, Rc<RefCell<env::Env>>),
+    Control(Control),
+    Cont(Rc<Continuation>),
     Nil,
 }
 
-#[derive(Clone)]
-pub struct ProcFunc(fn(Expr) -> Expr);
+/// Built-in procedures that work on the evaluator's control state rather
+/// than on their arguments alone, so the evaluator applies them itself.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Control {
+    CallCc,
+    DynamicWind,
+    Apply,
+}
+
+/// The Rust side of a builtin procedure. It is given the machine that
+/// calls it, so that it can call back into Scheme, and the arguments,
+/// whose number the machine has already checked.
+pub type NativeFn = dyn Fn(&mut Vm, &[Expr]) -> Result<Expr, SchemeError>;
+
+/// A procedure implemented in Rust. The function is a closure, so it can
+/// carry state of its own, such as a handle to a host resource or a
+/// counter; values it captures are held outside the heap and stay alive
+/// as long as the builtin does.
+pub struct Builtin {
+    pub name: Symbol,
+    pub arity: Arity,
+    pub func: Box<NativeFn>,
+}
 
-impl PartialEq for ProcFunc {
-    fn eq(&self, o: &ProcFunc) -> bool {
-        let _o: *const () = unsafe { ::std::mem::transmute(o) };
-        let _s: *const () = unsafe { ::std::mem::transmute(self) };
-        _s == _o
+impl Builtin {
+    pub fn new<F>(name: &str, arity: Arity, func: F) -> Builtin
+    where
+        F: Fn(&mut Vm, &[Expr]) -> Result<Expr, SchemeError> + 'static,
+    {
+        Builtin {
+            name: Symbol::intern(name),
+            arity,
+            func: Box::new(func),
+        }
     }
-    fn ne(&self, o: &ProcFunc) -> bool {
-        !self.eq(o)
+}
+
+/// Builtins are equal only to themselves.
+impl PartialEq for Builtin {
+    fn eq(&self, other: &Builtin) -> bool {
+        ::std::ptr::eq(self, other)
+    }
+}
+
+impl fmt::Debug for Builtin {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "builtin {}", self.name)
     }
 }
 
-impl ProcFunc {
-    pub fn func(&self) -> fn(Expr) -> Expr {
+/// How many arguments a procedure accepts.
+#[derive(Clone, PartialEq, Debug)]
+pub enum Arity {
+    Exactly(usize),
+    AtLeast(usize),
+    /// Any count one of the alternatives accepts, as for a `case-lambda`.
+    OneOf(Vec<Arity>),
+}
+
+impl Arity {
+    pub fn accepts(&self, argc: usize) -> bool {
         match *self {
-            ProcFunc(fun) => fun,
+            Arity::Exactly(n) => argc == n,
+            Arity::AtLeast(n) => argc >= n,
+            Arity::OneOf(ref arities) => arities.iter().any(|arity| arity.accepts(argc)),
         }
     }
 }
 
-impl fmt::Debug for ProcFunc {
+impl fmt::Display for Arity {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "proc")
+        match *self {
+            Arity::Exactly(n) => write!(f, "{}", n),
+            Arity::AtLeast(n) => write!(f, "at least {}", n),
+            Arity::OneOf(ref arities) => {
+                for (i, arity) in arities.iter().enumerate() {
+                    write!(f, "{}{}", if i == 0 { "" } else { " or " }, arity)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Where a datum was read from: the source name, the 1-based line and
+/// column of its first character, and its byte range in the source.
+#[derive(Clone, PartialEq, Debug)]
+pub struct Span {
+    pub file: Rc<str>,
+    pub line: usize,
+    pub col: usize,
+    pub start: usize,
+    pub end: usize,
+}
+
+impl fmt::Display for Span {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}:{}", self.file, self.line, self.col)
+    }
+}
+
+/// The span of a pair read by the parser, if it came from a named source.
+/// All `Loc`s compare equal so that positions never affect how values
+/// compare.
+#[derive(Clone, Debug, Default)]
+pub struct Loc(pub Option<Rc<Span>>);
+
+impl PartialEq for Loc {
+    fn eq(&self, _: &Loc) -> bool {
+        true
     }
 }
 
 impl Expr {
+    /// Allocates a new pair. Copies of the returned value share the cell,
+    /// so `set-car!` through one of them is seen by all.
     pub fn new_pair(car: Expr, cdr: Expr) -> Expr {
-        Expr::Pair(vec![car, cdr])
+        let cell = Rc::new(RefCell::new([car, cdr]));
+        gc::track_pair(&cell);
+        Expr::Pair(cell, Loc::default())
+    }
+
+    pub fn new_vector(items: Vec<Expr>) -> Expr {
+        let vec = Rc::new(RefCell::new(items));
+        gc::track_vector(&vec);
+        Expr::Vector(vec)
+    }
+
+    /// The source span of a pair, if it has one. Atoms are located through
+    /// the pair that contains them.
+    pub fn span(&self) -> Option<Rc<Span>> {
+        match *self {
+            Expr::Pair(_, ref loc) => loc.0.clone(),
+            _ => None,
+        }
+    }
+
+    /// Attaches `span` to a pair that does not have one yet.
+    pub fn with_span(self, span: Option<Rc<Span>>) -> Expr {
+        match self {
+            Expr::Pair(vec, Loc(None)) => Expr::Pair(vec, Loc(span)),
+            exp => exp,
+        }
     }
 
     pub fn new_str(val: &str) -> Expr {
         Expr::Str(val.to_string())
     }
 
+    pub fn new_mut_str(val: &str) -> Expr {
+        Expr::MutStr(Rc::new(RefCell::new(val.chars().collect())))
+    }
+
     pub fn new_sym(val: &str) -> Expr {
-        Expr::Sym(val.to_string())
+        Expr::Sym(Symbol::intern(val))
     }
 
-    pub fn new_proc(func: fn(Expr) -> Expr) -> Expr {
-        Expr::Proc(ProcFunc(func))
+    /// A builtin procedure called `name` that runs `func` on arguments
+    /// `arity` accepts.
+    pub fn new_proc<F>(name: &str, arity: Arity, func: F) -> Expr
+    where
+        F: Fn(&mut Vm, &[Expr]) -> Result<Expr, SchemeError> + 'static,
+    {
+        Expr::Proc(Rc::new(Builtin::new(name, arity, func)))
     }
 
-    pub fn new_cproc(params: Expr, body: Expr, env: Rc<RefCell<env::Env>>) -> Expr {
-        Expr::CompProc(vec![params, body], env)
+    pub fn new_cproc(lambda: Rc<Lambda>, env: Rc<RefCell<env::Env>>) -> Expr {
+        Expr::CompProc(lambda, env)
     }
 
+    /// Everything except `#f` counts as true.
     pub fn is_true(&self) -> bool {
-        self.as_bool()
+        !self.is_false()
     }
 
     pub fn is_false(&self) -> bool {
-        !self.as_bool()
+        matches!(*self, Expr::Bool(false))
     }
 
     pub fn is_pair(&self) -> bool {
-        match *self {
-            Expr::Pair(_) | Expr::Nil => true,
-            _ => false,
-        }
+        matches!(*self, Expr::Pair(..) | Expr::Nil)
     }
 
     pub fn is_empty(&self) -> bool {
-        match *self {
-            Expr::Nil => true,
-            _ => false,
-        }
+        matches!(*self, Expr::Nil)
     }
 
     pub fn is_cproc(&self) -> bool {
-        match *self {
-            Expr::CompProc(_, _) => true,
-            _ => false,
-        }
+        matches!(*self, Expr::CompProc(_, _))
     }
 
-    pub fn is_last(&self) -> bool {
-        assert!(self.is_pair());
-        self.cdr().is_empty()
+    /// True for anything that can be applied, continuations included.
+    pub fn is_procedure(&self) -> bool {
+        self.is_proc() || self.is_cproc() || matches!(*self, Expr::Control(_) | Expr::Cont(_))
     }
 
     pub fn is_self(&self) -> bool {
+        self.is_number()
+            || matches!(
+                *self,
+                Expr::Bool(_)
+                    | Expr::Char(_)
+                    | Expr::Str(_)
+                    | Expr::MutStr(_)
+                    | Expr::Vector(_)
+                    | Expr::Bytevector(_)
+            )
+    }
+
+    pub fn is_number(&self) -> bool {
+        matches!(
+            *self,
+            Expr::Int(_) | Expr::BigInt(_) | Expr::Rational(_) | Expr::Float(_)
+        )
+    }
+
+    /// True for exact integers and for inexact numbers with no fractional
+    /// part, as R7RS `integer?` requires.
+    pub fn is_integer(&self) -> bool {
+        num::is_integer(self)
+    }
+
+    pub fn is_rational(&self) -> bool {
         match *self {
-            Expr::Bool(_) | Expr::Int(_) | Expr::Char(_) | Expr::Str(_) => true,
-            _ => false,
+            Expr::Float(f) => f.is_finite(),
+            _ => self.is_number(),
         }
     }
 
-    pub fn is_tagged(&self, tag: Expr) -> bool {
-        if self.is_pair() {
-            let car = self.car();
-            return car.is_sym() && car == tag;
+    pub fn is_tagged(&self, tag: Symbol) -> bool {
+        self.head() == Some(tag)
+    }
+
+    /// The symbol in the operator position of a form, if there is one.
+    pub fn head(&self) -> Option<Symbol> {
+        match *self {
+            Expr::Pair(ref cell, _) => match cell.borrow()[0] {
+                Expr::Sym(sym) => Some(sym),
+                _ => None,
+            },
+            _ => None,
         }
-        false
     }
 
-    pub fn as_int(&self) -> isize {
+    pub fn as_int(&self) -> Result<isize, SchemeError> {
         match *self {
-            Expr::Int(ref val) => return *val,
-            _ => panic!("expect Int"),
+            Expr::Int(ref val) => Ok(*val),
+            _ => Err(SchemeError::wrong_type("integer", self)),
         }
     }
 
-    pub fn as_bool(&self) -> bool {
+    #[cfg(test)]
+    pub fn as_bool(&self) -> Result<bool, SchemeError> {
         match *self {
-            Expr::Bool(ref val) => return *val,
-            _ => panic!("expect Bool"),
+            Expr::Bool(ref val) => Ok(*val),
+            _ => Err(SchemeError::wrong_type("boolean", self)),
         }
     }
 
     #[cfg(test)]
-    pub fn as_char(&self) -> char {
+    pub fn as_char(&self) -> Result<char, SchemeError> {
+        match *self {
+            Expr::Char(ref val) => Ok(*val),
+            _ => Err(SchemeError::wrong_type("char", self)),
+        }
+    }
+
+    /// True for strings of either kind.
+    pub fn is_str(&self) -> bool {
+        matches!(*self, Expr::Str(_) | Expr::MutStr(_))
+    }
+
+    /// The text of a string, or the name of a symbol.
+    pub fn as_str(&self) -> Result<String, SchemeError> {
         match *self {
-            Expr::Char(ref val) => return *val,
-            _ => panic!("expect Char"),
+            Expr::Str(ref val) => Ok(val.clone()),
+            Expr::MutStr(ref val) => Ok(val.borrow().iter().collect()),
+            Expr::Sym(sym) => Ok(sym.name().to_string()),
+            _ => Err(SchemeError::wrong_type("string", self)),
         }
     }
 
-    pub fn as_proc(&self) -> ProcFunc {
+    pub fn as_sym(&self) -> Result<Symbol, SchemeError> {
         match *self {
-            Expr::Proc(ref val) => val.clone(),
-            _ => panic!("expect Proc"),
+            Expr::Sym(sym) => Ok(sym),
+            _ => Err(SchemeError::wrong_type("symbol", self)),
         }
     }
 
-    pub fn as_str(&self) -> String {
+    pub fn car(&self) -> Result<Expr, SchemeError> {
         match *self {
-            Expr::Str(ref val) => return val.clone(),
-            Expr::Sym(ref val) => return val.clone(),
-            _ => panic!("expect Str"),
+            Expr::Pair(ref cell, _) => Ok(cell.borrow()[0].clone()),
+            _ => Err(SchemeError::wrong_type("pair", self)),
         }
     }
 
-    pub fn car(&self) -> Expr {
+    pub fn cdr(&self) -> Result<Expr, SchemeError> {
         match *self {
-            Expr::Pair(ref vec) => {
-                return vec[0].clone();
+            Expr::Pair(ref cell, _) => Ok(cell.borrow()[1].clone()),
+            _ => Err(SchemeError::wrong_type("pair", self)),
+        }
+    }
+
+    pub fn set_car(&self, val: Expr) -> Result<(), SchemeError> {
+        match *self {
+            Expr::Pair(ref cell, _) => {
+                cell.borrow_mut()[0] = val;
+                Ok(())
             }
-            _ => panic!("expect Pair"),
+            _ => Err(SchemeError::wrong_type("pair", self)),
         }
     }
 
-    pub fn cdr(&self) -> Expr {
+    pub fn set_cdr(&self, val: Expr) -> Result<(), SchemeError> {
         match *self {
-            Expr::Pair(ref vec) => {
-                return vec[1].clone();
+            Expr::Pair(ref cell, _) => {
+                cell.borrow_mut()[1] = val;
+                Ok(())
+            }
+            _ => Err(SchemeError::wrong_type("pair", self)),
+        }
+    }
+
+    /// R7RS `eqv?`, which `eq?` shares: pairs, vectors, bytevectors,
+    /// mutable strings and procedures are the same only if they are the
+    /// same object, numbers if they have the same exactness and value.
+    /// Immutable strings have no identity of their own and compare by
+    /// contents.
+    pub fn eqv(&self, other: &Expr) -> bool {
+        match (self, other) {
+            (Expr::Pair(a, _), Expr::Pair(b, _)) => Rc::ptr_eq(a, b),
+            (Expr::MutStr(a), Expr::MutStr(b)) => Rc::ptr_eq(a, b),
+            (Expr::Vector(a), Expr::Vector(b)) => Rc::ptr_eq(a, b),
+            (Expr::Bytevector(a), Expr::Bytevector(b)) => Rc::ptr_eq(a, b),
+            (Expr::Float(a), Expr::Float(b)) => a.to_bits() == b.to_bits(),
+            (Expr::CompProc(a, env_a), Expr::CompProc(b, env_b)) => {
+                Rc::ptr_eq(a, b) && Rc::ptr_eq(env_a, env_b)
             }
-            _ => panic!("expect Pair"),
+            _ => self == other,
         }
     }
 
-    pub fn def_var(&self) -> Expr {
+    /// R7RS `equal?`: pairs and vectors are compared element by element,
+    /// strings of either kind by contents, everything else as by `eqv?`.
+    /// Terminates on circular lists.
+    pub fn equal(&self, other: &Expr) -> bool {
+        equal_in(self, other, &mut HashSet::new())
+    }
+
+    pub fn def_var(&self) -> Result<Expr, SchemeError> {
         assert!(self.is_def());
-        if self.cdr().car().is_sym() {
+        if self.c("da")?.is_sym() {
             self.c("da")
         } else {
             self.c("daa")
         }
     }
 
-    pub fn def_val(&self) -> Expr {
+    pub fn def_val(&self) -> Result<Expr, SchemeError> {
         assert!(self.is_def());
-        if self.c("da").is_sym() {
+        if self.c("da")?.is_sym() {
             self.c("dda")
         } else {
             //proc
-            self.c("dad").make_lambda(self.c("dd"))
+            Ok(self.c("dad")?.make_lambda(self.c("dd")?))
         }
     }
 
     pub fn make_lambda(&self, body: Expr) -> Expr {
-        let lambda = Expr::new_sym("lambda");
+        let lambda = Expr::Sym(symbol::LAMBDA);
         Expr::new_pair(lambda, Expr::new_pair((*self).clone(), body))
     }
 
-    pub fn params(&self) -> Expr {
-        match *self {
-            Expr::CompProc(ref val, _) => val[0].clone(),
-            _ => panic!("expect CompProc"),
+    /// `cXr` accessor: `"da"` is `cadr`, read left to right.
+    pub fn c(&self, s: &str) -> Result<Expr, SchemeError> {
+        let mut r = self.clone();
+        for c in s.chars() {
+            r = match r {
+                Expr::Pair(ref cell, _) if c == 'a' => cell.borrow()[0].clone(),
+                Expr::Pair(ref cell, _) => cell.borrow()[1].clone(),
+                _ => return Err(SchemeError::wrong_type("pair", &r)),
+            };
         }
+        Ok(r)
     }
+}
 
-    pub fn body(&self) -> Expr {
-        match *self {
-            Expr::CompProc(ref val, _) => val[1].clone(),
-            _ => panic!("expect CompProc"),
+/// Splits a possibly improper list into its elements and its final cdr.
+/// A list whose spine runs back into itself is not a list at all.
+pub fn split_list(exp: &Expr) -> Result<(Vec<Expr>, Expr), SchemeError> {
+    let mut items = vec![];
+    let mut rest = exp.clone();
+    let mut slow = exp.clone();
+    while let Expr::Pair(..) = rest {
+        items.push(rest.car()?);
+        rest = rest.cdr()?;
+        if items.len() % 2 == 0 {
+            slow = slow.cdr()?;
+            if rest.eqv(&slow) {
+                return Err(SchemeError::wrong_type("list", exp));
+            }
         }
     }
+    Ok((items, rest))
+}
 
-    pub fn c(&self, s: &str) -> Expr {
-        assert!(self.is_pair());
-        let mut r = self.clone();
-        for c in s.to_string().chars() {
-            if c == 'a' {
-                r = r.car();
-            } else {
-                r = r.cdr();
+/// Builds a list of `items` ending in `tail` instead of the empty list.
+pub fn join_list(items: Vec<Expr>, tail: Expr) -> Expr {
+    items
+        .into_iter()
+        .rev()
+        .fold(tail, |acc, item| Expr::new_pair(item, acc))
+}
+
+impl fmt::Display for Expr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Expr::Int(ref val) => write!(f, "{}", val),
+            Expr::BigInt(ref val) => write!(f, "{}", val),
+            Expr::Rational(ref val) => write!(f, "{}", val),
+            Expr::Float(val) => write!(f, "{}", num::format_float(val)),
+            Expr::Str(ref val) => write_escaped(f, val, '"'),
+            Expr::MutStr(ref val) => {
+                write_escaped(f, &val.borrow().iter().collect::<String>(), '"')
+            }
+            Expr::Sym(sym) => {
+                let val = &*sym.name();
+                let plain = !val.is_empty()
+                    && num::parse_number(val, 10).is_none()
+                    && !val
+                        .chars()
+                        .any(|c| c.is_whitespace() || "()\"';|".contains(c));
+                if plain {
+                    write!(f, "{}", val)
+                } else {
+                    write_escaped(f, val, '|')
+                }
+            }
+            Expr::Bool(val) => write!(f, "{}", if val { "#t" } else { "#f" }),
+            Expr::Char(val) => match char_name(val) {
+                Some(name) => write!(f, "#\\{}", name),
+                None if val.is_control() => write!(f, "#\\x{:x}", val as u32),
+                None => write!(f, "#\\{}", val),
+            },
+            Expr::Bytevector(ref vec) => {
+                write!(f, "#u8(")?;
+                for (i, byte) in vec.borrow().iter().enumerate() {
+                    write!(f, "{}{}", if i == 0 { "" } else { " " }, byte)?;
+                }
+                write!(f, ")")
             }
+            Expr::Pair(..) | Expr::Vector(_) => {
+                let mut labels = Labels::default();
+                labels.find_cycles(self);
+                labels.write(f, self)
+            }
+            Expr::Proc(_) | Expr::CompProc(_, _) | Expr::Control(_) => write!(f, "#<procedure>"),
+            Expr::Cont(_) => write!(f, "#<continuation>"),
+            Expr::Nil => write!(f, "()"),
         }
-        r
     }
+}
 
-    fn collect(&self) -> Vec<Expr> {
-        let mut res: Vec<Expr> = vec![];
-        let mut _exp = self.clone();
-        loop {
-            let f = _exp.car();
-            if f.is_self() {
-                res.push(f);
-            } else if !f.is_empty() {
-                for e in f.collect() {
-                    res.push(e);
-                }
-                //res.push_all(f.collect().as_slice());
+/// The pairs and vectors of a value that it reaches again from inside
+/// themselves. Each is written with a datum label, `#0=` where it is first
+/// met and `#0#` after, so a cyclic value prints finitely.
+#[derive(Default)]
+struct Labels {
+    cycles: HashMap<usize, Option<usize>>,
+    next: usize,
+}
+
+fn cell_addr(exp: &Expr) -> Option<usize> {
+    match *exp {
+        Expr::Pair(ref cell, _) => Some(cell.as_ptr() as usize),
+        Expr::Vector(ref vec) => Some(vec.as_ptr() as usize),
+        _ => None,
+    }
+}
+
+impl Labels {
+    /// A depth-first walk that keeps its own stack, as a long list is as
+    /// deep as it is long.
+    fn find_cycles(&mut self, exp: &Expr) {
+        let mut path = HashSet::new();
+        let mut done = HashSet::new();
+        let mut stack = vec![(exp.clone(), false)];
+        while let Some((exp, leaving)) = stack.pop() {
+            let addr = match cell_addr(&exp) {
+                Some(addr) => addr,
+                None => continue,
+            };
+            if leaving {
+                path.remove(&addr);
+                done.insert(addr);
+                continue;
             }
-            _exp = _exp.cdr();
-            if !_exp.is_pair() {
-                break;
+            if path.contains(&addr) {
+                self.cycles.insert(addr, None);
+                continue;
             }
-            if _exp.is_empty() {
-                break;
+            if done.contains(&addr) {
+                continue;
+            }
+            path.insert(addr);
+            stack.push((exp.clone(), true));
+            match exp {
+                Expr::Pair(ref cell, _) => {
+                    stack.extend(cell.borrow().iter().map(|x| (x.clone(), false)))
+                }
+                Expr::Vector(ref vec) => {
+                    stack.extend(vec.borrow().iter().map(|x| (x.clone(), false)))
+                }
+                _ => {}
             }
         }
-        res
     }
 
-    pub fn print(&self) {
-        match *self {
-            Expr::Int(ref ast) => println!("{:?}", ast),
-            Expr::Str(ref ast) => println!("{:?}", ast),
-            Expr::Bool(ref ast) => println!("{:?}", ast),
-            Expr::Sym(ref ast) => println!("{:?}", ast),
-            Expr::Char(ref ast) => println!("{:?}", ast),
-            Expr::Pair(_) => {
-                print!("(");
-                let exps = self.collect();
-                for i in 0..exps.len() {
-                    exps[i].print();
-                    if i != exps.len() - 1 {
-                        print!(" ");
-                    }
+    /// Writes the label of `exp` if it has one, and whether that was a
+    /// reference that stands for the whole value.
+    fn write_label(&mut self, f: &mut fmt::Formatter, exp: &Expr) -> Result<bool, fmt::Error> {
+        let next = self.next;
+        let label = match cell_addr(exp).and_then(|addr| self.cycles.get_mut(&addr)) {
+            Some(label) => label,
+            None => return Ok(false),
+        };
+        match *label {
+            Some(n) => write!(f, "#{}#", n).map(|_| true),
+            None => {
+                *label = Some(next);
+                self.next += 1;
+                write!(f, "#{}=", next).map(|_| false)
+            }
+        }
+    }
+
+    fn write(&mut self, f: &mut fmt::Formatter, exp: &Expr) -> fmt::Result {
+        if self.write_label(f, exp)? {
+            return Ok(());
+        }
+        match *exp {
+            Expr::Pair(ref cell, _) => {
+                write!(f, "(")?;
+                let first = cell.borrow()[0].clone();
+                self.write(f, &first)?;
+                let mut rest = cell.borrow()[1].clone();
+                loop {
+                    let next = match rest {
+                        Expr::Pair(ref cell, _)
+                            if !self.cycles.contains_key(&(cell.as_ptr() as usize)) =>
+                        {
+                            cell.borrow().clone()
+                        }
+                        _ => break,
+                    };
+                    write!(f, " ")?;
+                    self.write(f, &next[0])?;
+                    rest = next[1].clone();
                 }
-                print!(")");
+                if !rest.is_empty() {
+                    write!(f, " . ")?;
+                    self.write(f, &rest)?;
+                }
+                write!(f, ")")
+            }
+            Expr::Vector(ref vec) => {
+                write!(f, "#(")?;
+                for (i, exp) in vec.borrow().clone().iter().enumerate() {
+                    write!(f, "{}", if i == 0 { "" } else { " " })?;
+                    self.write(f, exp)?;
+                }
+                write!(f, ")")
             }
-            Expr::Proc(ref ast) => println!("{:?}", ast),
-            Expr::CompProc(ref ast, _) => println!("{:?}", ast),
-            Expr::Nil => print!("Nil"),
+            _ => write!(f, "{}", exp),
+        }
+    }
+}
+
+/// `equal?` that remembers which pairs of cells it is already comparing,
+/// so a cycle met a second time is taken to match.
+fn equal_in(a: &Expr, b: &Expr, seen: &mut HashSet<(usize, usize)>) -> bool {
+    match (a, b) {
+        (Expr::Pair(x, _), Expr::Pair(y, _)) => {
+            if !seen.insert((x.as_ptr() as usize, y.as_ptr() as usize)) {
+                return true;
+            }
+            let (x, y) = (x.borrow().clone(), y.borrow().clone());
+            equal_in(&x[0], &y[0], seen) && equal_in(&x[1], &y[1], seen)
+        }
+        (Expr::Vector(x), Expr::Vector(y)) => {
+            let (x, y) = (x.borrow().clone(), y.borrow().clone());
+            x.len() == y.len() && x.iter().zip(&y).all(|(x, y)| equal_in(x, y, seen))
         }
+        (Expr::Bytevector(x), Expr::Bytevector(y)) => x == y,
+        _ if a.is_str() && b.is_str() => a.as_str() == b.as_str(),
+        _ => a.eqv(b),
     }
 }
 
+/// The R7RS name of a character that is written as `#\name`.
+fn char_name(ch: char) -> Option<&'static str> {
+    match ch {
+        '\u{7}' => Some("alarm"),
+        '\u{8}' => Some("backspace"),
+        '\u{7f}' => Some("delete"),
+        '\u{1b}' => Some("escape"),
+        '\n' => Some("newline"),
+        '\0' => Some("null"),
+        '\r' => Some("return"),
+        ' ' => Some("space"),
+        '\t' => Some("tab"),
+        _ => None,
+    }
+}
+
+/// Writes a string or `|symbol|` between `quote`s, escaped so the reader
+/// gives back the same text.
+fn write_escaped(f: &mut fmt::Formatter, val: &str, quote: char) -> fmt::Result {
+    write!(f, "{}", quote)?;
+    for ch in val.chars() {
+        match ch {
+            '\\' => write!(f, "\\\\")?,
+            '\n' => write!(f, "\\n")?,
+            '\t' => write!(f, "\\t")?,
+            '\r' => write!(f, "\\r")?,
+            '\u{7}' => write!(f, "\\a")?,
+            '\u{8}' => write!(f, "\\b")?,
+            _ if ch == quote => write!(f, "\\{}", ch)?,
+            _ if ch.is_control() => write!(f, "\\x{:x};", ch as u32)?,
+            _ => write!(f, "{}", ch)?,
+        }
+    }
+    write!(f, "{}", quote)
+}
+
 macro_rules! is_ast_type {
     ($func_name:ident, $type_name:ident) => {
         impl Expr {
@@ -283,32 +686,36 @@ macro_rules! is_ast_type {
 }
 
 is_ast_type!(is_char, Char);
-is_ast_type!(is_int, Int);
 is_ast_type!(is_sym, Sym);
-is_ast_type!(is_str, Str);
 is_ast_type!(is_proc, Proc);
 is_ast_type!(is_bool, Bool);
+is_ast_type!(is_vector, Vector);
+is_ast_type!(is_bytevector, Bytevector);
 
 macro_rules! is_type {
-    ($func_name:ident, $type_str:expr) => {
+    ($func_name:ident, $sym:ident) => {
         impl Expr {
             pub fn $func_name(&self) -> bool {
-                return self.is_tagged(Expr::Sym($type_str.to_string()));
+                self.is_tagged(symbol::$sym)
             }
         }
     };
 }
 
-is_type!(is_quote, "quote");
-is_type!(is_def, "define");
-is_type!(is_and, "and");
-is_type!(is_or, "or");
-is_type!(is_if, "if");
-is_type!(is_assign, "set!");
-is_type!(is_lambda, "lambda");
-is_type!(is_cond, "cond");
-is_type!(is_let, "let");
-is_type!(is_begin, "begin");
+is_type!(is_quote, QUOTE);
+is_type!(is_def, DEFINE);
+is_type!(is_and, AND);
+is_type!(is_or, OR);
+is_type!(is_if, IF);
+is_type!(is_assign, SET);
+is_type!(is_lambda, LAMBDA);
+is_type!(is_cond, COND);
+is_type!(is_let, LET);
+is_type!(is_begin, BEGIN);
+is_type!(is_top, TOP);
+is_type!(is_quasiquote, QUASIQUOTE);
+is_type!(is_unquote, UNQUOTE);
+is_type!(is_unquote_splicing, UNQUOTE_SPLICING);
 
 #[cfg(test)]
 mod tests {
@@ -316,25 +723,25 @@ mod tests {
     #[test]
     fn test_ast() {
         let int_node = Expr::Int(3);
-        assert!(int_node.as_int() == 3);
+        assert!(int_node.as_int().unwrap() == 3);
 
         let char_node = Expr::Char('a');
-        assert!(char_node.as_char() == 'a');
+        assert!(char_node.as_char().unwrap() == 'a');
 
         let bool_node = Expr::Bool(false);
-        assert!(bool_node.as_bool() == false);
+        assert!(!bool_node.as_bool().unwrap());
 
         let str_node = Expr::new_str("hello");
-        assert!(str_node.as_str() == "hello");
+        assert!(str_node.as_str().unwrap() == "hello");
         assert!(str_node.is_self());
 
         let int_node = Expr::Int(3);
         let str_node = Expr::new_str("hello");
         let pair_node = Expr::new_pair(int_node, str_node);
-        let car_node = pair_node.car();
-        let cdr_node = pair_node.cdr();
-        assert!(car_node.as_int() == 3);
-        assert!(cdr_node.as_str() == "hello");
+        let car_node = pair_node.car().unwrap();
+        let cdr_node = pair_node.cdr().unwrap();
+        assert!(car_node.as_int().unwrap() == 3);
+        assert!(cdr_node.as_str().unwrap() == "hello");
         assert!(!pair_node.is_self());
 
         let sym_node = Expr::new_sym("sym");
@@ -362,22 +769,93 @@ mod tests {
         assert!(!test_case!("begin").is_assign());
     }
 
+    #[test]
+    fn test_ast_errors() {
+        let int_node = Expr::Int(3);
+        assert_eq!(
+            int_node.as_bool(),
+            Err(SchemeError::WrongType("boolean", Expr::Int(3)))
+        );
+        assert_eq!(
+            Expr::Nil.car(),
+            Err(SchemeError::WrongType("pair", Expr::Nil))
+        );
+        assert!(Expr::Nil.cdr().is_err());
+        assert!(Expr::new_sym("x").as_int().is_err());
+        assert!(!Expr::Nil.is_tagged(symbol::QUOTE));
+    }
+
+    #[test]
+    fn test_display() {
+        let list = Expr::new_pair(
+            Expr::Int(1),
+            Expr::new_pair(Expr::new_str("two"), Expr::Nil),
+        );
+        assert_eq!(list.to_string(), r#"(1 "two")"#);
+        let dotted = Expr::new_pair(Expr::new_sym("a"), Expr::Bool(false));
+        assert_eq!(dotted.to_string(), "(a . #f)");
+        assert_eq!(Expr::Char('x').to_string(), "#\\x");
+        assert_eq!(Expr::Nil.to_string(), "()");
+    }
+
+    #[test]
+    fn test_display_cycles() {
+        let list = join_list(vec![Expr::Int(1), Expr::Int(2)], Expr::Nil);
+        list.cdr().unwrap().set_cdr(list.clone()).unwrap();
+        assert_eq!(list.to_string(), "#0=(1 2 . #0#)");
+        list.set_car(list.clone()).unwrap();
+        assert_eq!(list.to_string(), "#0=(#0# 2 . #0#)");
+        // a value met twice without a cycle is written out each time
+        let shared = Expr::new_pair(Expr::Int(1), Expr::Nil);
+        let twice = join_list(vec![shared.clone(), shared], Expr::Nil);
+        assert_eq!(twice.to_string(), "((1) (1))");
+        let vec = Expr::new_vector(vec![Expr::Int(1), Expr::Nil]);
+        if let Expr::Vector(ref items) = vec {
+            items.borrow_mut()[1] = vec.clone();
+        }
+        assert_eq!(vec.to_string(), "#0=#(1 #0#)");
+    }
+
+    #[test]
+    fn test_split_list() {
+        let list = join_list(vec![Expr::Int(1), Expr::Int(2)], Expr::Int(3));
+        assert_eq!(
+            split_list(&list),
+            Ok((vec![Expr::Int(1), Expr::Int(2)], Expr::Int(3)))
+        );
+        assert_eq!(split_list(&Expr::Nil), Ok((vec![], Expr::Nil)));
+        for len in 1..5 {
+            let items = (0..len).map(Expr::Int).collect();
+            let list = join_list(items, Expr::Nil);
+            let mut last = list.clone();
+            while !last.cdr().unwrap().is_empty() {
+                last = last.cdr().unwrap();
+            }
+            last.set_cdr(list.clone()).unwrap();
+            assert!(matches!(
+                split_list(&list),
+                Err(SchemeError::WrongType("list", _))
+            ));
+        }
+    }
+
     #[test]
     fn test_symbol_eq() {
         let aa = Expr::new_sym("else");
         let bb = Expr::new_sym("else");
         assert!(aa == bb);
+        assert!(aa.eqv(&bb));
+        assert_eq!(aa.as_sym(), Ok(symbol::ELSE));
+        assert!(aa != Expr::new_str("else"));
     }
 
     #[test]
     fn test_proc() {
-        fn _proc(obj: Expr) -> Expr {
-            obj.print();
-            return Expr::new_sym("ok");
-        }
-
-        let proc_node = Expr::new_proc(_proc);
+        let proc_node = Expr::new_proc("ok", Arity::Exactly(1), |_, _| Ok(Expr::new_sym("ok")));
         assert!(proc_node.is_proc());
         assert!(!proc_node.is_cproc());
+        assert!(proc_node == proc_node.clone());
+        let other = Expr::new_proc("ok", Arity::Exactly(1), |_, _| Ok(Expr::new_sym("ok")));
+        assert!(proc_node != other);
     }
 }
@@ -0,0 +1,771 @@
+use ast::{join_list, split_list, Arity, Expr, Span};
+use env::Globals;
+use error::SchemeError;
+use std::fmt;
+use std::rc::Rc;
+use symbol::{self, Symbol};
+
+/// One instruction of the virtual machine. The operands index the tables
+/// of the `Code` the instruction belongs to, or variable slots, or are jump
+/// targets.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Op {
+    /// Pushes `consts[i]`.
+    Const(usize),
+    /// Pushes the value in slot `index` of the frame `depth` levels above
+    /// the current one.
+    Local(usize, usize),
+    /// Pops a value and stores it in slot `index` of the frame `depth`
+    /// levels above the current one.
+    SetLocal(usize, usize),
+    /// Pushes the value of the global variable in slot `i`.
+    Global(usize),
+    /// Pops a value and stores it in the global slot `i`.
+    SetGlobal(usize),
+    Pop,
+    /// Pushes another copy of the value on top.
+    Dup,
+    /// Exchanges the two values on top.
+    Swap,
+    /// Pushes whether the value on top is `eqv?` to an element of the list
+    /// `consts[i]`, leaving the value there.
+    Memv(usize),
+    Jump(usize),
+    /// Pops a value and jumps if it is false.
+    JumpIfFalse(usize),
+    /// Jumps if the value on top is false, leaving it there; pops it
+    /// otherwise. What `and` does between its operands.
+    AndJump(usize),
+    /// Jumps if the value on top is true, leaving it there; pops it
+    /// otherwise.
+    OrJump(usize),
+    /// Pushes a procedure made from `lambdas[i]` and the current frame.
+    Closure(usize),
+    /// Calls the procedure under the top `n` values with them as the
+    /// arguments. `consts[i]` is the operator expression, which arity
+    /// errors name.
+    Call(usize, usize),
+    /// A call in tail position, which replaces the caller instead of
+    /// returning to it.
+    TailCall(usize, usize),
+    /// Pops the result and returns it to the caller.
+    Return,
+    /// Pops the values of the `n` unquoted expressions of the template
+    /// `consts[i]` and pushes the value of the template.
+    Quasi(usize, usize),
+}
+
+/// The compiled form of a top-level form or of the body of a lambda.
+/// `spans[pc]` locates the form `ops[pc]` comes from, for error messages.
+#[derive(Default)]
+pub struct Code {
+    pub ops: Vec<Op>,
+    pub spans: Vec<Option<Rc<Span>>>,
+    pub consts: Vec<Expr>,
+    pub lambdas: Vec<Rc<Lambda>>,
+}
+
+/// A compiled lambda expression, which the `Closure` instruction pairs
+/// with an environment to make a procedure. A call makes a frame with a
+/// slot for each of `names`: the `nparams` required parameters, the rest
+/// parameter if there is one, then the variables defined in the body.
+///
+/// A `case-lambda` has no code of its own but a clause for each of its
+/// alternatives; a call runs the first clause that takes that many
+/// arguments.
+#[derive(Default)]
+pub struct Lambda {
+    pub nparams: usize,
+    pub rest: bool,
+    pub names: Rc<Vec<Symbol>>,
+    pub code: Rc<Code>,
+    pub clauses: Vec<Rc<Lambda>>,
+}
+
+impl Lambda {
+    pub fn arity(&self) -> Arity {
+        if !self.clauses.is_empty() {
+            Arity::OneOf(self.clauses.iter().map(|clause| clause.arity()).collect())
+        } else if self.rest {
+            Arity::AtLeast(self.nparams)
+        } else {
+            Arity::Exactly(self.nparams)
+        }
+    }
+
+    /// The lambda that runs when this one is called with `argc` arguments,
+    /// if any does.
+    pub fn select(self: &Rc<Lambda>, argc: usize) -> Option<&Rc<Lambda>> {
+        if self.clauses.is_empty() {
+            Some(self).filter(|lambda| lambda.arity().accepts(argc))
+        } else {
+            self.clauses
+                .iter()
+                .find(|clause| clause.arity().accepts(argc))
+        }
+    }
+}
+
+impl PartialEq for Lambda {
+    fn eq(&self, other: &Lambda) -> bool {
+        ::std::ptr::eq(self, other)
+    }
+}
+
+impl fmt::Debug for Lambda {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if !self.clauses.is_empty() {
+            return write!(f, "case-lambda");
+        }
+        write!(f, "lambda (")?;
+        for (i, name) in self.names[..self.nparams].iter().enumerate() {
+            write!(f, "{}{}", if i == 0 { "" } else { " " }, name)?;
+        }
+        if self.rest {
+            let sep = if self.nparams == 0 { "" } else { " " };
+            write!(f, "{}. {}", sep, self.names[self.nparams])?;
+        }
+        write!(f, ")")
+    }
+}
+
+/// Compiles a fully expanded form, as the expander returns it, to code
+/// that leaves its value with `Return`. Variables not bound by an enclosing
+/// lambda are given slots in `globals`.
+pub fn compile(exp: &Expr, globals: &mut Globals) -> Result<Rc<Code>, SchemeError> {
+    let mut compiler = Compiler::new(vec![], globals);
+    compiler.compile(exp, true)?;
+    Ok(Rc::new(compiler.code))
+}
+
+/// `exp` with the names the expander made for it replaced by the names
+/// they stand for. Code keeps only these, as the expander reuses its names
+/// once the form is compiled.
+fn original(exp: &Expr) -> Expr {
+    match *exp {
+        Expr::Sym(name) => Expr::Sym(name.origin()),
+        Expr::Pair(ref cell, _) => {
+            let [car, cdr] = cell.borrow().clone();
+            Expr::new_pair(original(&car), original(&cdr))
+        }
+        _ => exp.clone(),
+    }
+}
+
+struct Compiler<'a> {
+    code: Code,
+    /// The innermost form with a known location around what is being
+    /// compiled.
+    span: Option<Rc<Span>>,
+    /// The variables of each frame the code can see, innermost last.
+    scopes: Vec<Vec<Symbol>>,
+    globals: &'a mut Globals,
+}
+
+impl<'a> Compiler<'a> {
+    fn new(scopes: Vec<Vec<Symbol>>, globals: &'a mut Globals) -> Compiler<'a> {
+        Compiler {
+            code: Code::default(),
+            span: None,
+            scopes,
+            globals,
+        }
+    }
+
+    fn emit(&mut self, op: Op) -> usize {
+        self.code.ops.push(op);
+        self.code.spans.push(self.span.clone());
+        self.code.ops.len() - 1
+    }
+
+    /// Points the jump at `at` to the next instruction to be emitted.
+    fn patch(&mut self, at: usize) {
+        let next = self.code.ops.len();
+        match self.code.ops[at] {
+            Op::Jump(ref mut target)
+            | Op::JumpIfFalse(ref mut target)
+            | Op::AndJump(ref mut target)
+            | Op::OrJump(ref mut target) => *target = next,
+            op => panic!("not a jump: {:?}", op),
+        }
+    }
+
+    fn constant(&mut self, val: Expr) -> usize {
+        self.code.consts.push(val);
+        self.code.consts.len() - 1
+    }
+
+    /// The instructions that load and store `var`: a slot of the innermost
+    /// frame that binds it, or else a global slot.
+    fn resolve(&mut self, var: &Expr) -> Result<(Op, Op), SchemeError> {
+        let var = var.as_sym()?;
+        for (depth, scope) in self.scopes.iter().rev().enumerate() {
+            if let Some(index) = scope.iter().position(|name| *name == var) {
+                return Ok((Op::Local(depth, index), Op::SetLocal(depth, index)));
+            }
+        }
+        let slot = self.globals.slot(var);
+        Ok((Op::Global(slot), Op::SetGlobal(slot)))
+    }
+
+    fn resolve_global(&mut self, var: &Expr) -> Result<(Op, Op), SchemeError> {
+        let slot = self.globals.slot(var.as_sym()?);
+        Ok((Op::Global(slot), Op::SetGlobal(slot)))
+    }
+
+    /// The instruction that defines `var`: in the current frame, which gets
+    /// a new slot unless its body already gave it one, or at top level as a
+    /// global.
+    fn define(&mut self, var: &Expr) -> Result<Op, SchemeError> {
+        let var = var.as_sym()?;
+        let scope = match self.scopes.last_mut() {
+            Some(scope) => scope,
+            None => return Ok(Op::SetGlobal(self.globals.slot(var))),
+        };
+        let index = match scope.iter().position(|name| *name == var) {
+            Some(index) => index,
+            None => {
+                scope.push(var);
+                scope.len() - 1
+            }
+        };
+        Ok(Op::SetLocal(0, index))
+    }
+
+    fn push_const(&mut self, val: Expr, tail: bool) {
+        let i = self.constant(val);
+        self.emit(Op::Const(i));
+        self.finish(tail);
+    }
+
+    /// Returns the value just computed if it is the value of the body.
+    fn finish(&mut self, tail: bool) {
+        if tail {
+            self.emit(Op::Return);
+        }
+    }
+
+    /// Compiles `exp` so that it pushes its value, or returns it if `tail`.
+    fn compile(&mut self, exp: &Expr, tail: bool) -> Result<(), SchemeError> {
+        let outer = self.span.clone();
+        if let Some(span) = exp.span() {
+            self.span = Some(span);
+        }
+        let res = self.compile_form(exp, tail);
+        self.span = outer;
+        res
+    }
+
+    fn compile_form(&mut self, exp: &Expr, tail: bool) -> Result<(), SchemeError> {
+        if exp.is_self() || exp.is_empty() {
+            self.push_const(exp.clone(), tail);
+            return Ok(());
+        }
+        if exp.is_sym() {
+            let (load, _) = self.resolve(exp)?;
+            self.emit(load);
+            self.finish(tail);
+            return Ok(());
+        }
+        match exp.head() {
+            Some(symbol::TOP) => {
+                let (load, _) = self.resolve_global(&exp.c("da")?)?;
+                self.emit(load);
+                self.finish(tail);
+            }
+            Some(symbol::QUOTE) => self.push_const(exp.c("da")?, tail),
+            Some(symbol::QUASIQUOTE) => {
+                let tmpl = exp.c("da")?;
+                let mut holes = vec![];
+                quasi_holes(&tmpl, 0, &mut holes)?;
+                for hole in &holes {
+                    self.compile(hole, false)?;
+                }
+                let i = self.constant(tmpl);
+                self.emit(Op::Quasi(i, holes.len()));
+                self.finish(tail);
+            }
+            Some(symbol::LAMBDA) => {
+                let i = self.lambda(&exp.c("da")?, &exp.c("dd")?)?;
+                self.emit(Op::Closure(i));
+                self.finish(tail);
+            }
+            Some(symbol::CASE_LAMBDA) => {
+                let mut clauses = vec![];
+                for clause in split_list(&exp.cdr()?)?.0 {
+                    let lambda = self.make_lambda(&clause.car()?, &clause.cdr()?)?;
+                    clauses.push(Rc::new(lambda));
+                }
+                self.code.lambdas.push(Rc::new(Lambda {
+                    clauses,
+                    ..Lambda::default()
+                }));
+                self.emit(Op::Closure(self.code.lambdas.len() - 1));
+                self.finish(tail);
+            }
+            Some(symbol::LET) => self.compile_let(exp, tail)?,
+            Some(symbol::BEGIN) => self.compile_body(&exp.cdr()?, tail)?,
+            Some(symbol::IF) => self.compile_if(exp, tail)?,
+            Some(symbol::AND) | Some(symbol::OR) => self.compile_operands(exp, tail)?,
+            Some(symbol::COND) => self.compile_cond(&exp.cdr()?, tail)?,
+            Some(symbol::CASE) => self.compile_case(exp, tail)?,
+            Some(symbol::SET) => {
+                self.compile(&exp.c("dda")?, false)?;
+                let target = exp.c("da")?;
+                let (_, store) = if target.is_top() {
+                    self.resolve_global(&target.c("da")?)?
+                } else {
+                    self.resolve(&target)?
+                };
+                self.emit(store);
+                self.push_const(Expr::new_sym("OK"), tail);
+            }
+            Some(symbol::DEFINE) => {
+                self.compile(&exp.def_val()?, false)?;
+                let store = self.define(&exp.def_var()?)?;
+                self.emit(store);
+                self.push_const(Expr::new_sym("OK"), tail);
+            }
+            _ => {
+                // the operator is evaluated first, then the operands in order
+                let argc = self.compile_operands_of(exp)? - 1;
+                let name = self.constant(original(&exp.car()?));
+                self.emit_call(argc, name, tail);
+            }
+        }
+        Ok(())
+    }
+
+    fn emit_call(&mut self, argc: usize, name: usize, tail: bool) {
+        if tail {
+            self.emit(Op::TailCall(argc, name));
+        } else {
+            self.emit(Op::Call(argc, name));
+        }
+    }
+
+    /// Compiles each element of the list `exps` and returns how many there
+    /// are. An element is located by the list cell it starts.
+    fn compile_operands_of(&mut self, exps: &Expr) -> Result<usize, SchemeError> {
+        let mut count = 0;
+        let mut rest = exps.clone();
+        while let Expr::Pair(..) = rest {
+            let outer = self.span.clone();
+            if let Some(span) = rest.span() {
+                self.span = Some(span);
+            }
+            let res = self.compile(&rest.car()?, false);
+            self.span = outer;
+            res?;
+            count += 1;
+            rest = rest.cdr()?;
+        }
+        Ok(count)
+    }
+
+    /// Compiles `params` and `body` of a lambda expression into a table
+    /// entry of the code being compiled.
+    fn lambda(&mut self, params: &Expr, body: &Expr) -> Result<usize, SchemeError> {
+        let lambda = self.make_lambda(params, body)?;
+        self.code.lambdas.push(Rc::new(lambda));
+        Ok(self.code.lambdas.len() - 1)
+    }
+
+    /// Compiles a lambda expression whose parameter list is `params`: a
+    /// proper list of the required parameters, or an improper one or a
+    /// single symbol naming a rest parameter after them.
+    fn make_lambda(&mut self, params: &Expr, body: &Expr) -> Result<Lambda, SchemeError> {
+        let (vars, tail) = split_list(params)?;
+        let mut names = vec![];
+        for var in vars {
+            names.push(var.as_sym()?);
+        }
+        let nparams = names.len();
+        let rest = !tail.is_empty();
+        if rest {
+            names.push(
+                tail.as_sym()
+                    .map_err(|_| SchemeError::BadSyntax(params.clone()))?,
+            );
+        }
+        // the body's definitions are in scope all through it
+        for exp in split_list(body)?.0 {
+            if exp.is_def() {
+                names.push(exp.def_var()?.as_sym()?);
+            }
+        }
+        let mut scopes = self.scopes.clone();
+        scopes.push(names);
+        let mut compiler = Compiler::new(scopes, self.globals);
+        compiler.compile_body(body, true)?;
+        let names = compiler.scopes.pop().unwrap_or_default();
+        Ok(Lambda {
+            nparams,
+            rest,
+            names: Rc::new(names.into_iter().map(Symbol::origin).collect()),
+            code: Rc::new(compiler.code),
+            clauses: vec![],
+        })
+    }
+
+    /// Compiles the expressions of a body in order, keeping only the value
+    /// of the last one.
+    fn compile_body(&mut self, body: &Expr, tail: bool) -> Result<(), SchemeError> {
+        let (exps, _) = split_list(body)?;
+        if exps.is_empty() {
+            self.push_const(Expr::new_sym("OK"), tail);
+        }
+        for (i, exp) in exps.iter().enumerate() {
+            if i + 1 < exps.len() {
+                self.compile(exp, false)?;
+                self.emit(Op::Pop);
+            } else {
+                self.compile(exp, tail)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// `(let ((v e) ...) body)` is `((lambda (v ...) body) e ...)`.
+    fn compile_let(&mut self, exp: &Expr, tail: bool) -> Result<(), SchemeError> {
+        let (bindings, _) = split_list(&exp.c("da")?)?;
+        let mut vars = vec![];
+        for binding in &bindings {
+            vars.push(binding.car()?);
+        }
+        let i = self.lambda(&join_list(vars, Expr::Nil), &exp.c("dd")?)?;
+        self.emit(Op::Closure(i));
+        for binding in &bindings {
+            self.compile(&binding.c("da")?, false)?;
+        }
+        let name = self.constant(Expr::Sym(symbol::LET));
+        self.emit_call(bindings.len(), name, tail);
+        Ok(())
+    }
+
+    fn compile_if(&mut self, exp: &Expr, tail: bool) -> Result<(), SchemeError> {
+        self.compile(&exp.c("da")?, false)?;
+        let to_alt = self.emit(Op::JumpIfFalse(0));
+        self.compile(&exp.c("dda")?, tail)?;
+        let to_end = if tail {
+            None
+        } else {
+            Some(self.emit(Op::Jump(0)))
+        };
+        self.patch(to_alt);
+        let alt = exp.c("ddd")?;
+        if alt.is_empty() {
+            self.push_const(Expr::Bool(false), tail);
+        } else {
+            self.compile(&alt.car()?, tail)?;
+        }
+        if let Some(at) = to_end {
+            self.patch(at);
+        }
+        Ok(())
+    }
+
+    /// `and` and `or` stop at the first operand that decides the result
+    /// and return its value; the last operand is in tail position.
+    fn compile_operands(&mut self, exp: &Expr, tail: bool) -> Result<(), SchemeError> {
+        let (elems, _) = split_list(&exp.cdr()?)?;
+        if elems.is_empty() {
+            self.push_const(Expr::Bool(exp.is_and()), tail);
+            return Ok(());
+        }
+        let mut exits = vec![];
+        for (i, elem) in elems.iter().enumerate() {
+            if i + 1 == elems.len() {
+                self.compile(elem, tail)?;
+            } else {
+                self.compile(elem, false)?;
+                let op = if exp.is_and() {
+                    Op::AndJump(0)
+                } else {
+                    Op::OrJump(0)
+                };
+                exits.push(self.emit(op));
+            }
+        }
+        // the operand that decided the result is still on the stack
+        let decided = !exits.is_empty();
+        for at in exits {
+            self.patch(at);
+        }
+        self.finish(tail && decided);
+        Ok(())
+    }
+
+    /// Compiles the clauses of a `cond`. The value of a clause with only a
+    /// test is the value of the test, and a `(test => receiver)` clause
+    /// calls the receiver with it.
+    fn compile_cond(&mut self, clauses: &Expr, tail: bool) -> Result<(), SchemeError> {
+        let (clauses, _) = split_list(clauses)?;
+        let mut exits = vec![];
+        let mut kept = false;
+        for clause in &clauses {
+            let test = clause.car()?;
+            let body = clause.cdr()?;
+            if test == Expr::Sym(symbol::ELSE) {
+                self.compile_body(&body, tail)?;
+                return self.finish_cond(exits, kept, tail);
+            }
+            self.compile(&test, false)?;
+            if body.is_empty() {
+                // the value of the test is the value of the clause
+                exits.push(self.emit(Op::OrJump(0)));
+                kept = true;
+                continue;
+            }
+            let arrow = is_arrow(&body);
+            if arrow {
+                self.emit(Op::Dup);
+            }
+            let to_next = self.emit(Op::JumpIfFalse(0));
+            if arrow {
+                self.compile_receiver(&body.c("da")?, tail)?;
+            } else {
+                self.compile_body(&body, tail)?;
+            }
+            if !tail {
+                exits.push(self.emit(Op::Jump(0)));
+            }
+            self.patch(to_next);
+            if arrow {
+                self.emit(Op::Pop);
+            }
+        }
+        self.push_const(Expr::new_sym("OK"), tail);
+        self.finish_cond(exits, kept, tail)
+    }
+
+    /// Compiles `(case key clause ...)`. The key stays on the stack while
+    /// the clauses are tried and is dropped, or passed to the receiver of a
+    /// `=>` clause, once one matches.
+    fn compile_case(&mut self, exp: &Expr, tail: bool) -> Result<(), SchemeError> {
+        self.compile(&exp.c("da")?, false)?;
+        let (clauses, _) = split_list(&exp.c("dd")?)?;
+        let mut exits = vec![];
+        for clause in &clauses {
+            let datums = clause.car()?;
+            let body = clause.cdr()?;
+            let to_next = if datums == Expr::Sym(symbol::ELSE) {
+                None
+            } else {
+                let i = self.constant(datums);
+                self.emit(Op::Memv(i));
+                Some(self.emit(Op::JumpIfFalse(0)))
+            };
+            if is_arrow(&body) {
+                self.compile_receiver(&body.c("da")?, tail)?;
+            } else {
+                self.emit(Op::Pop);
+                self.compile_body(&body, tail)?;
+            }
+            match to_next {
+                Some(at) => {
+                    if !tail {
+                        exits.push(self.emit(Op::Jump(0)));
+                    }
+                    self.patch(at);
+                }
+                None => return self.finish_cond(exits, false, tail),
+            }
+        }
+        self.emit(Op::Pop);
+        self.push_const(Expr::new_sym("OK"), tail);
+        self.finish_cond(exits, false, tail)
+    }
+
+    /// Calls `receiver` with the value on top of the stack.
+    fn compile_receiver(&mut self, receiver: &Expr, tail: bool) -> Result<(), SchemeError> {
+        self.compile(receiver, false)?;
+        self.emit(Op::Swap);
+        let name = self.constant(receiver.clone());
+        self.emit_call(1, name, tail);
+        Ok(())
+    }
+
+    fn finish_cond(
+        &mut self,
+        exits: Vec<usize>,
+        kept: bool,
+        tail: bool,
+    ) -> Result<(), SchemeError> {
+        for at in exits {
+            self.patch(at);
+        }
+        self.finish(tail && kept);
+        Ok(())
+    }
+}
+
+/// Whether the rest of a `cond` or `case` clause is `(=> receiver)`.
+fn is_arrow(body: &Expr) -> bool {
+    body.car().ok() == Some(Expr::Sym(symbol::ARROW))
+}
+
+/// The expressions in the unquotes of a quasiquote template that are
+/// evaluated, in the order they appear. `fill_quasi` puts their values in.
+fn quasi_holes(tmpl: &Expr, depth: usize, out: &mut Vec<Expr>) -> Result<(), SchemeError> {
+    if (tmpl.is_unquote() || tmpl.is_unquote_splicing()) && depth == 0 {
+        out.push(tmpl.c("da")?);
+        return Ok(());
+    }
+    if tmpl.is_quasiquote() || tmpl.is_unquote() || tmpl.is_unquote_splicing() {
+        let depth = if tmpl.is_quasiquote() {
+            depth + 1
+        } else {
+            depth - 1
+        };
+        return quasi_holes(&tmpl.cdr()?, depth, out);
+    }
+    match *tmpl {
+        Expr::Pair(ref cell, _) => {
+            let [car, cdr] = cell.borrow().clone();
+            quasi_holes(&car, depth, out)?;
+            quasi_holes(&cdr, depth, out)
+        }
+        Expr::Vector(ref vec) => {
+            quasi_holes(&join_list(vec.borrow().clone(), Expr::Nil), depth, out)
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Builds the value of a quasiquote template, taking the values of its
+/// unquoted expressions from `vals`. `depth` counts the quasiquotes nested
+/// inside the outermost one; only unquotes at depth zero were evaluated,
+/// the others are rebuilt as data.
+pub fn fill_quasi(
+    tmpl: &Expr,
+    depth: usize,
+    vals: &mut ::std::vec::IntoIter<Expr>,
+) -> Result<Expr, SchemeError> {
+    if tmpl.is_unquote() && depth == 0 {
+        return Ok(vals.next().unwrap_or(Expr::Nil));
+    }
+    if tmpl.is_quasiquote() || tmpl.is_unquote() || tmpl.is_unquote_splicing() {
+        let depth = if tmpl.is_quasiquote() {
+            depth + 1
+        } else {
+            depth - 1
+        };
+        let rest = fill_quasi(&tmpl.cdr()?, depth, vals)?;
+        return Ok(Expr::new_pair(tmpl.car()?, rest));
+    }
+    match *tmpl {
+        Expr::Pair(ref cell, _) => {
+            let [car, cdr] = cell.borrow().clone();
+            if car.is_unquote_splicing() && depth == 0 {
+                let spliced = vals.next().unwrap_or(Expr::Nil);
+                let (items, tail) = split_list(&spliced)?;
+                if !tail.is_empty() {
+                    return Err(SchemeError::wrong_type("list", &spliced));
+                }
+                return Ok(join_list(items, fill_quasi(&cdr, depth, vals)?));
+            }
+            let head = fill_quasi(&car, depth, vals)?;
+            Ok(Expr::new_pair(head, fill_quasi(&cdr, depth, vals)?))
+        }
+        Expr::Vector(ref vec) => {
+            let items = join_list(vec.borrow().clone(), Expr::Nil);
+            let (items, _) = split_list(&fill_quasi(&items, depth, vals)?)?;
+            Ok(Expr::new_vector(items))
+        }
+        _ => Ok(tmpl.clone()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use parser::Parser;
+    use std;
+
+    fn compile_str(code: &str, globals: &mut Globals) -> Rc<Code> {
+        let mut parser = Parser::new_from(std::io::stdin(), false);
+        parser.load(code.to_string());
+        compile(&parser.read_exp().unwrap().unwrap(), globals).unwrap()
+    }
+
+    #[test]
+    fn test_compile() {
+        let mut globals = Globals::new();
+        let code = compile_str("(if (f x) 1 'a)", &mut globals);
+        let f = globals.slot(Symbol::intern("f"));
+        let x = globals.slot(Symbol::intern("x"));
+        assert_eq!(
+            code.ops,
+            vec![
+                Op::Global(f),
+                Op::Global(x),
+                Op::Call(1, 0),
+                Op::JumpIfFalse(6),
+                Op::Const(1),
+                Op::Return,
+                Op::Const(2),
+                Op::Return,
+            ]
+        );
+
+        // the call at the end of a body is a tail call
+        let code = compile_str("(lambda (n) (f n) (f n))", &mut globals);
+        assert_eq!(code.ops, vec![Op::Closure(0), Op::Return]);
+        let body = &code.lambdas[0].code;
+        assert_eq!(body.ops[1], Op::Local(0, 0));
+        assert_eq!(body.ops[3], Op::Pop);
+        assert_eq!(body.ops[6], Op::TailCall(1, 1));
+
+        let code = compile_str("(and x f)", &mut globals);
+        assert_eq!(
+            code.ops,
+            vec![
+                Op::Global(x),
+                Op::AndJump(4),
+                Op::Global(f),
+                Op::Return,
+                Op::Return,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_resolve() {
+        let mut globals = Globals::new();
+        let code = compile_str(
+            "(lambda (a b)
+               (define (inner) (set! b a) x)
+               (define x 1)
+               (inner))",
+            &mut globals,
+        );
+        let outer = &code.lambdas[0];
+        assert_eq!(outer.nparams, 2);
+        let names: Vec<String> = outer.names.iter().map(|name| name.to_string()).collect();
+        assert_eq!(names, vec!["a", "b", "inner", "x"]);
+        let inner = &outer.code.lambdas[0];
+        assert_eq!(inner.names.len(), 0);
+        // set! b: a is loaded, then stored one frame up
+        assert_eq!(inner.code.ops[0], Op::Local(1, 0));
+        assert_eq!(inner.code.ops[1], Op::SetLocal(1, 1));
+        // x is defined after inner, but in scope all through the body
+        assert_eq!(inner.code.ops[4], Op::Local(1, 3));
+
+        // a rest parameter takes the slot after the required ones
+        let code = compile_str("(lambda (a . r) (define d 1) r)", &mut globals);
+        let lambda = &code.lambdas[0];
+        assert_eq!((lambda.nparams, lambda.rest), (1, true));
+        assert_eq!(lambda.arity(), Arity::AtLeast(1));
+        assert_eq!(format!("{:?}", lambda), "lambda (a . r)");
+        assert_eq!(lambda.code.ops[1], Op::SetLocal(0, 2));
+        assert_eq!(lambda.code.ops[4], Op::Local(0, 1));
+
+        let code = compile_str("(define y (let ((y 2)) y))", &mut globals);
+        assert_eq!(code.lambdas[0].code.ops[0], Op::Local(0, 0));
+        assert_eq!(
+            code.ops[3],
+            Op::SetGlobal(globals.slot(Symbol::intern("y")))
+        );
+    }
+}
@@ -1,13 +1,25 @@
-use std::collections::HashMap;
+use ast::*;
+use error::SchemeError;
+use gc;
+use json;
+use num;
 use std::cell::RefCell;
-use std::rc::Rc;
+use std::cmp::Ordering;
+use std::collections::HashMap;
 use std::fmt;
-use ast::*;
+use std::rc::Rc;
+use string::{self, *};
+use symbol::Symbol;
+use vm::Vm;
 
+/// A frame of local variables: the parameters of a procedure call followed
+/// by the variables defined in its body, in the slots the compiler assigned
+/// them. A slot is empty until its variable is defined.
 #[derive(Clone, PartialEq)]
 pub struct Env {
-    pub table: HashMap<String, Expr>,
-    pub parent: Option<Rc<RefCell<Env>>>
+    pub names: Rc<Vec<Symbol>>,
+    pub slots: Vec<Option<Expr>>,
+    pub parent: Option<Rc<RefCell<Env>>>,
 }
 
 impl fmt::Debug for Env {
@@ -16,86 +28,170 @@ impl fmt::Debug for Env {
     }
 }
 
-#[allow(unreachable_code)]
-#[allow(dead_code)]
 impl Env {
-    pub fn new() -> Env {
-        let mut res = Env {
-            table: HashMap::new(),
-            parent: None
+    /// The frame top-level code runs in. It binds nothing: top-level
+    /// variables live in `Globals`.
+    pub fn top() -> Rc<RefCell<Env>> {
+        Rc::new(RefCell::new(Env {
+            names: Rc::new(vec![]),
+            slots: vec![],
+            parent: None,
+        }))
+    }
+
+    /// Creates a new frame whose parent is `env` itself (not a copy), so
+    /// assignments made through either one are seen by both. It has a slot
+    /// for each of `names`, the first ones filled with `vals`.
+    pub fn extend(
+        env: &Rc<RefCell<Env>>,
+        names: Rc<Vec<Symbol>>,
+        vals: Vec<Expr>,
+    ) -> Rc<RefCell<Env>> {
+        let mut slots: Vec<Option<Expr>> = vals.into_iter().map(Some).collect();
+        slots.resize(names.len(), None);
+        let res = Rc::new(RefCell::new(Env {
+            names,
+            slots,
+            parent: Some(env.clone()),
+        }));
+        gc::track_env(&res);
+        res
+    }
+
+    /// The value in slot `index` of the frame `depth` levels above this one.
+    /// The compiler only emits references to frames that enclose the code,
+    /// and the collector never empties a frame that live code can reach.
+    pub fn get(&self, depth: usize, index: usize) -> Option<Expr> {
+        if depth == 0 {
+            return self.slots[index].clone();
+        }
+        self.parent().borrow().get(depth - 1, index)
+    }
+
+    pub fn set(&mut self, depth: usize, index: usize, val: Expr) {
+        if depth == 0 {
+            self.slots[index] = Some(val);
+            return;
+        }
+        self.parent().borrow_mut().set(depth - 1, index, val)
+    }
+
+    /// The name of the variable in slot `index` of the frame `depth` levels
+    /// above this one.
+    pub fn name(&self, depth: usize, index: usize) -> Symbol {
+        if depth == 0 {
+            return self.names[index];
+        }
+        self.parent().borrow().name(depth - 1, index)
+    }
+
+    /// The enclosing frame, which only the top frame lacks.
+    fn parent(&self) -> Rc<RefCell<Env>> {
+        self.parent.clone().expect("no frame at depth")
+    }
+}
+
+/// The global variables. The compiler resolves each global name to a slot
+/// once, so running code reaches a global by index.
+pub struct Globals {
+    slots: HashMap<Symbol, usize>,
+    names: Vec<Symbol>,
+    values: Vec<Option<Expr>>,
+}
+
+impl Default for Globals {
+    fn default() -> Globals {
+        Globals::new()
+    }
+}
+
+impl Globals {
+    /// A table holding the builtin procedures.
+    pub fn new() -> Globals {
+        let mut res = Globals {
+            slots: HashMap::new(),
+            names: vec![],
+            values: vec![],
         };
         res.setup();
         res
     }
 
-    pub fn def_var(&mut self, var: String, val: Expr) {
-        self.add_binding(var, val);
+    /// The slot of the global `var`, which is made, unbound, the first time
+    /// it is asked for.
+    pub fn slot(&mut self, var: Symbol) -> usize {
+        if let Some(&slot) = self.slots.get(&var) {
+            return slot;
+        }
+        self.names.push(var);
+        self.values.push(None);
+        self.slots.insert(var, self.names.len() - 1);
+        self.names.len() - 1
     }
 
-    pub fn str_def(&mut self, var: &str, val: Expr) {
-        self.add_binding(var.to_string(), val);
+    pub fn get(&self, slot: usize) -> Option<&Expr> {
+        self.values[slot].as_ref()
     }
 
-    pub fn add_binding(&mut self, var: String, val: Expr) {
-        self.table.insert(var, val);
+    pub fn set(&mut self, slot: usize, val: Expr) {
+        self.values[slot] = Some(val);
     }
 
-    pub fn lookup(&self, var: String) -> Option<Expr> {
-        match self.table.get(&var) {
-            Some(val) => return Some(val.clone()),
-            _ => {}
-        }
-        match self.parent.clone() {
-            Some(p) =>
-                return p.borrow().lookup(var),
-            _ => {
-                return None;
-            }
-        };
+    pub fn name(&self, slot: usize) -> Symbol {
+        self.names[slot]
+    }
+
+    pub fn def_var(&mut self, var: Symbol, val: Expr) {
+        let slot = self.slot(var);
+        self.set(slot, val);
+    }
+
+    pub fn str_def(&mut self, var: &str, val: Expr) {
+        self.def_var(Symbol::intern(var), val);
+    }
+
+    pub fn lookup(&self, var: Symbol) -> Option<Expr> {
+        self.slots
+            .get(&var)
+            .and_then(|&slot| self.values[slot].clone())
     }
 
     pub fn str_lookup(&self, var: &str) -> Option<Expr> {
-        self.lookup(var.to_string())
+        self.lookup(Symbol::intern(var))
     }
 
-    pub fn parent(&self) ->  Option<Rc<RefCell<Env>>> {
-        match self.parent.clone() {
-            Some(p) => return Some(p.clone()),
-            _ => { return None; }
-        }
+    /// The globals that are bound, with their values, in the order they
+    /// were first referred to.
+    pub fn bindings(&self) -> Vec<(Symbol, Expr)> {
+        self.names
+            .iter()
+            .zip(&self.values)
+            .filter_map(|(&name, val)| val.clone().map(|val| (name, val)))
+            .collect()
     }
 
-    pub fn extend(&mut self, vars: Expr, vals: Expr) -> Rc<RefCell<Env>> {
-        let mut _vars = vars;
-        let mut _vals = vals;
-        let mut res = Env {
-            table: HashMap::new(),
-            parent: Some(Rc::new(RefCell::new(self.clone())))
-        };
-        loop {
-            if _vars.is_last() { break; }
-            res.add_binding(_vars.car().as_str(), _vals.car());
-            _vars = _vars.cdr();
-            _vals = _vals.cdr();
-        }
-        res.add_binding(_vars.car().as_str(), _vals.car());
-        return Rc::new(RefCell::new(res));
+    /// Defines the global `name` as a builtin that runs `func` on
+    /// arguments `arity` accepts.
+    pub fn def_fn<F>(&mut self, name: &str, arity: Arity, func: F)
+    where
+        F: Fn(&mut Vm, &[Expr]) -> Result<Expr, SchemeError> + 'static,
+    {
+        self.str_def(name, Expr::new_proc(name, arity, func));
     }
 
     fn setup(&mut self) {
         macro_rules! def_proc {
-            ($func_name:ident, $raw_func_name:ident) => (
-                fn $func_name(args: Expr) -> Expr {
-                    Expr::Bool(args.car().$raw_func_name())
+            ($func_name:ident, $raw_func_name:ident) => {
+                fn $func_name(args: &[Expr]) -> Result<Expr, SchemeError> {
+                    Ok(Expr::Bool(args[0].$raw_func_name()))
                 }
-                )
+            };
         }
 
         macro_rules! add_proc {
-            ($type_str:expr, $func_name:ident) => (
-                self.def_var($type_str.to_string(),
-                             Expr::new_proc($func_name))
-                    )
+            ($type_str:expr, $arity:expr, $func_name:ident) => {
+                self.def_fn($type_str, $arity, |_, args| $func_name(args))
+            };
         }
 
         def_proc!(is_null, is_empty);
@@ -104,149 +200,368 @@ impl Env {
         def_proc!(is_str, is_str);
         def_proc!(is_pair, is_pair);
         def_proc!(is_char, is_char);
-        def_proc!(is_int, is_int);
-
-        add_proc!("null?", is_null);
-        add_proc!("boolean?", is_boolean);
-        add_proc!("symbol?", is_sym);
-        add_proc!("string?", is_str);
-        add_proc!("char?", is_char);
-        add_proc!("integer?", is_int);
-        add_proc!("+", add);
-        add_proc!("-", sub);
-        add_proc!("*", mul);
-        add_proc!("/", div);
-        add_proc!("eq?", eq);
-        add_proc!("=", eq);
-        add_proc!("<", less);
-        add_proc!(">", large);
-        add_proc!("pair?", is_pair);
-        add_proc!("car", car);
-        add_proc!("cdr", cdr);
-        add_proc!("cons", cons);
+        def_proc!(is_int, is_integer);
+        def_proc!(is_number, is_number);
+        def_proc!(is_rational, is_rational);
+        def_proc!(is_vector, is_vector);
+        def_proc!(is_bytevector, is_bytevector);
+        def_proc!(is_procedure, is_procedure);
+
+        let one = Arity::Exactly(1);
+        let two = Arity::Exactly(2);
+        let any = Arity::AtLeast(0);
+        let some = Arity::AtLeast(1);
+        add_proc!("null?", one.clone(), is_null);
+        add_proc!("boolean?", one.clone(), is_boolean);
+        add_proc!("symbol?", one.clone(), is_sym);
+        add_proc!("string?", one.clone(), is_str);
+        add_proc!("char?", one.clone(), is_char);
+        add_proc!("vector?", one.clone(), is_vector);
+        add_proc!("bytevector?", one.clone(), is_bytevector);
+        add_proc!("procedure?", one.clone(), is_procedure);
+        add_proc!("integer?", one.clone(), is_int);
+        add_proc!("number?", one.clone(), is_number);
+        add_proc!("real?", one.clone(), is_number);
+        add_proc!("rational?", one.clone(), is_rational);
+        add_proc!("exact?", one.clone(), is_exact);
+        add_proc!("inexact?", one.clone(), is_inexact);
+        add_proc!("+", any.clone(), add);
+        add_proc!("-", some.clone(), sub);
+        add_proc!("*", any.clone(), mul);
+        add_proc!("/", some.clone(), div);
+        add_proc!("quotient", two.clone(), quotient);
+        add_proc!("remainder", two.clone(), remainder);
+        add_proc!("modulo", two.clone(), modulo);
+        add_proc!("expt", two.clone(), expt);
+        add_proc!("sqrt", one.clone(), sqrt);
+        add_proc!("exact->inexact", one.clone(), exact_to_inexact);
+        add_proc!("inexact->exact", one.clone(), inexact_to_exact);
+        add_proc!("inexact", one.clone(), exact_to_inexact);
+        add_proc!("exact", one.clone(), inexact_to_exact);
+        add_proc!(
+            "number->string",
+            Arity::OneOf(vec![Arity::Exactly(1), Arity::Exactly(2)]),
+            number_to_string
+        );
+        add_proc!("eq?", two.clone(), eq);
+        add_proc!("eqv?", two.clone(), eqv);
+        add_proc!("equal?", two.clone(), equal);
+        add_proc!("=", some.clone(), num_eq);
+        add_proc!("<", some.clone(), less);
+        add_proc!(">", some.clone(), large);
+        add_proc!("<=", some.clone(), less_eq);
+        add_proc!(">=", some.clone(), large_eq);
+        add_proc!("pair?", one.clone(), is_pair);
+        add_proc!("car", one.clone(), car);
+        add_proc!("cdr", one.clone(), cdr);
+        add_proc!("cons", two.clone(), cons);
+        add_proc!("length", one.clone(), length);
+        add_proc!("set-car!", two.clone(), set_car);
+        add_proc!("set-cdr!", two.clone(), set_cdr);
+        add_proc!("symbol->string", one.clone(), symbol_to_string);
+        add_proc!("string->symbol", one.clone(), string_to_symbol);
+        add_proc!(
+            "gensym",
+            Arity::OneOf(vec![Arity::Exactly(0), Arity::Exactly(1)]),
+            gensym
+        );
+        self.str_def(
+            "call-with-current-continuation",
+            Expr::Control(Control::CallCc),
+        );
+        self.str_def("call/cc", Expr::Control(Control::CallCc));
+        self.str_def("dynamic-wind", Expr::Control(Control::DynamicWind));
+        self.str_def("apply", Expr::Control(Control::Apply));
+        self.def_fn("json->scm", Arity::Exactly(1), |_, args| {
+            json::json_to_scm(args)
+        });
+        self.def_fn("scm->json", Arity::Exactly(1), |_, args| {
+            json::scm_to_json(args)
+        });
+        let upto = |min: usize, max: usize| Arity::OneOf((min..=max).map(Arity::Exactly).collect());
+        add_proc!("make-string", upto(1, 2), make_string);
+        add_proc!("string", any.clone(), string);
+        add_proc!("string-length", one.clone(), string_length);
+        add_proc!("string-ref", two.clone(), string_ref);
+        add_proc!("string-set!", Arity::Exactly(3), string_set);
+        add_proc!("string-fill!", upto(2, 4), string_fill);
+        add_proc!("substring", Arity::Exactly(3), substring);
+        add_proc!("string-copy", upto(1, 3), string_copy);
+        add_proc!("string-append", any, string_append);
+        add_proc!("string->list", upto(1, 3), string_to_list);
+        add_proc!("list->string", one.clone(), list_to_string);
+        add_proc!("string->number", upto(1, 2), string_to_number);
+        add_proc!("string-upcase", one.clone(), string_upcase);
+        add_proc!("string-downcase", one.clone(), string_downcase);
+        add_proc!("string=?", some.clone(), string_eq);
+        add_proc!("string<?", some.clone(), string_less);
+        add_proc!("string>?", some.clone(), string_large);
+        add_proc!("string<=?", some.clone(), string_less_eq);
+        add_proc!("string>=?", some.clone(), string_large_eq);
+        add_proc!("string-ci=?", some.clone(), string_ci_eq);
+        add_proc!("string-ci<?", some.clone(), string_ci_less);
+        add_proc!("string-ci>?", some.clone(), string_ci_large);
+        add_proc!("string-ci<=?", some.clone(), string_ci_less_eq);
+        add_proc!("string-ci>=?", some, string_ci_large_eq);
+        self.def_fn("string-index", upto(2, 4), string_index);
+        add_proc!("string-contains", two, string_contains);
+        add_proc!("string-split", upto(1, 2), string_split);
+        add_proc!("string-join", upto(1, 2), string_join);
+        add_proc!("gc", Arity::Exactly(0), collect);
+        add_proc!("heap-stats", Arity::Exactly(0), heap_stats);
     }
 }
 
+// The builtins below get as many arguments as the arity they are
+// registered with allows, so they index `args` without checking.
 
-fn add(args: Expr) -> Expr {
-    let mut res = 0isize;
-    let mut exps = args;
-    loop {
-        if exps.is_empty() { break; }
-        res += exps.car().as_int();
-        exps = exps.cdr();
+fn add(args: &[Expr]) -> Result<Expr, SchemeError> {
+    let mut res = Expr::Int(0);
+    for arg in args {
+        res = num::add(&res, arg)?;
     }
-    Expr::Int(res)
+    Ok(res)
 }
 
-fn sub(args: Expr) -> Expr {
-    let mut res = args.car().as_int();
-    let mut exps = args.cdr();
-    loop {
-        if exps.is_empty() { break; }
-        res -= exps.car().as_int();
-        exps = exps.cdr();
+fn sub(args: &[Expr]) -> Result<Expr, SchemeError> {
+    if args.len() == 1 {
+        return num::neg(&args[0]);
     }
-    Expr::Int(res)
+    let mut res = args[0].clone();
+    for arg in &args[1..] {
+        res = num::sub(&res, arg)?;
+    }
+    Ok(res)
 }
 
-fn mul(args: Expr) -> Expr {
-    let mut res = 1isize;
-    let mut exps = args;
-    loop {
-        if exps.is_empty() { break; }
-        res *= exps.car().as_int();
-        exps = exps.cdr();
+fn mul(args: &[Expr]) -> Result<Expr, SchemeError> {
+    let mut res = Expr::Int(1);
+    for arg in args {
+        res = num::mul(&res, arg)?;
     }
-    Expr::Int(res)
-}
-
-fn div(args: Expr) -> Expr {
-    let mut exps = args;
-    let mut res = exps.car().as_int();
-    exps = exps.cdr();
-    loop {
-        if exps.is_empty() { break; }
-        let nxt = exps.car().as_int();
-        if nxt == 0 {
-            return Expr::new_sym("fail");
+    Ok(res)
+}
+
+fn div(args: &[Expr]) -> Result<Expr, SchemeError> {
+    let (mut res, divisors) = if args.len() == 1 {
+        (Expr::Int(1), args)
+    } else {
+        (args[0].clone(), &args[1..])
+    };
+    for arg in divisors {
+        if num::is_exact_zero(arg) {
+            return Err(SchemeError::DivisionByZero(call_form("/", args)));
         }
-        res /= nxt;
-        exps = exps.cdr();
+        res = num::div(&res, arg)?;
     }
-    Expr::Int(res)
+    Ok(res)
 }
 
-fn cons(args: Expr) -> Expr {
-    let obj1 = args.car();
-    let obj2 = args.c("da");
-    Expr::new_pair(obj1, Expr::new_pair(obj2, Expr::Nil))
+/// The call `(name args ...)`, for error messages.
+pub fn call_form(name: &str, args: &[Expr]) -> Expr {
+    Expr::new_pair(Expr::new_sym(name), join_list(args.to_vec(), Expr::Nil))
 }
 
-fn eq(args: Expr) -> Expr {
-    let obj1 = args.car();
-    let obj2 = args.c("da");
-    Expr::Bool(obj1 == obj2)
+fn int_div(name: &str, op: num::IntDiv, args: &[Expr]) -> Result<Expr, SchemeError> {
+    if num::is_zero(&args[1]) {
+        return Err(SchemeError::DivisionByZero(call_form(name, args)));
+    }
+    num::int_div(op, &args[0], &args[1])
 }
 
-fn less(args: Expr) -> Expr {
-    let val = args.car().as_int();
-    let mut exps = args.cdr();
-    loop {
-        if exps.is_empty() { break; }
-        if val >= exps.car().as_int() {
-            return Expr::Bool(false);
-        }
-        exps = exps.cdr();
+fn quotient(args: &[Expr]) -> Result<Expr, SchemeError> {
+    int_div("quotient", num::IntDiv::Quotient, args)
+}
+
+fn remainder(args: &[Expr]) -> Result<Expr, SchemeError> {
+    int_div("remainder", num::IntDiv::Remainder, args)
+}
+
+fn modulo(args: &[Expr]) -> Result<Expr, SchemeError> {
+    int_div("modulo", num::IntDiv::Modulo, args)
+}
+
+fn expt(args: &[Expr]) -> Result<Expr, SchemeError> {
+    num::expt(&args[0], &args[1])?
+        .ok_or_else(|| SchemeError::Unrepresentable(call_form("expt", args)))
+}
+
+fn sqrt(args: &[Expr]) -> Result<Expr, SchemeError> {
+    num::sqrt(&args[0])?.ok_or_else(|| SchemeError::Unrepresentable(call_form("sqrt", args)))
+}
+
+fn is_exact(args: &[Expr]) -> Result<Expr, SchemeError> {
+    Ok(Expr::Bool(num::is_exact(&args[0])?))
+}
+
+fn is_inexact(args: &[Expr]) -> Result<Expr, SchemeError> {
+    Ok(Expr::Bool(!num::is_exact(&args[0])?))
+}
+
+fn exact_to_inexact(args: &[Expr]) -> Result<Expr, SchemeError> {
+    num::to_inexact(&args[0])
+}
+
+fn inexact_to_exact(args: &[Expr]) -> Result<Expr, SchemeError> {
+    num::to_exact(&args[0])
+}
+
+fn number_to_string(args: &[Expr]) -> Result<Expr, SchemeError> {
+    let radix = match args.get(1) {
+        None => 10,
+        Some(&Expr::Int(r)) if r == 2 || r == 8 || r == 10 || r == 16 => r as u32,
+        Some(other) => return Err(SchemeError::wrong_type("radix 2, 8, 10 or 16", other)),
+    };
+    Ok(Expr::Str(num::to_string_radix(&args[0], radix)?))
+}
+
+/// Checks that `test` holds between each pair of adjacent arguments, as in
+/// `(< 1 2 3)`.
+fn compare_chain(args: &[Expr], test: fn(Option<Ordering>) -> bool) -> Result<Expr, SchemeError> {
+    if !args[0].is_number() {
+        return Err(SchemeError::wrong_type("number", &args[0]));
+    }
+    let mut res = true;
+    for pair in args.windows(2) {
+        res = res && test(num::compare(&pair[0], &pair[1])?);
     }
-    Expr::Bool(true)
+    Ok(Expr::Bool(res))
 }
 
-fn car(args: Expr) -> Expr {
-    assert!(args.car().is_pair());
-    args.c("aa")
+fn num_eq(args: &[Expr]) -> Result<Expr, SchemeError> {
+    compare_chain(args, |ord| ord == Some(Ordering::Equal))
 }
 
-fn cdr(args: Expr) -> Expr {
-    assert!(args.car().is_pair());
-    args.c("ad")
+fn less(args: &[Expr]) -> Result<Expr, SchemeError> {
+    compare_chain(args, |ord| ord == Some(Ordering::Less))
 }
 
+fn large(args: &[Expr]) -> Result<Expr, SchemeError> {
+    compare_chain(args, |ord| ord == Some(Ordering::Greater))
+}
 
-fn large(args: Expr) -> Expr {
-    let val = args.car().as_int();
-    let mut exps = args.cdr();
-    loop {
-        if exps.is_empty() { break; }
-        if val <= exps.car().as_int() {
-            return Expr::Bool(false);
-        }
-        exps = exps.cdr();
+fn less_eq(args: &[Expr]) -> Result<Expr, SchemeError> {
+    compare_chain(args, |ord| {
+        matches!(ord, Some(Ordering::Less) | Some(Ordering::Equal))
+    })
+}
+
+fn large_eq(args: &[Expr]) -> Result<Expr, SchemeError> {
+    compare_chain(args, |ord| {
+        matches!(ord, Some(Ordering::Greater) | Some(Ordering::Equal))
+    })
+}
+
+fn cons(args: &[Expr]) -> Result<Expr, SchemeError> {
+    Ok(Expr::new_pair(args[0].clone(), args[1].clone()))
+}
+
+fn eq(args: &[Expr]) -> Result<Expr, SchemeError> {
+    Ok(Expr::Bool(args[0].eqv(&args[1])))
+}
+
+fn eqv(args: &[Expr]) -> Result<Expr, SchemeError> {
+    Ok(Expr::Bool(args[0].eqv(&args[1])))
+}
+
+fn equal(args: &[Expr]) -> Result<Expr, SchemeError> {
+    Ok(Expr::Bool(args[0].equal(&args[1])))
+}
+
+fn set_car(args: &[Expr]) -> Result<Expr, SchemeError> {
+    args[0].set_car(args[1].clone())?;
+    Ok(Expr::new_sym("OK"))
+}
+
+fn set_cdr(args: &[Expr]) -> Result<Expr, SchemeError> {
+    args[0].set_cdr(args[1].clone())?;
+    Ok(Expr::new_sym("OK"))
+}
+
+fn symbol_to_string(args: &[Expr]) -> Result<Expr, SchemeError> {
+    Ok(Expr::Str(args[0].as_sym()?.name().to_string()))
+}
+
+fn string_to_symbol(args: &[Expr]) -> Result<Expr, SchemeError> {
+    Ok(Expr::Sym(Symbol::intern(&string::text(&args[0])?)))
+}
+
+/// `(gensym)` and `(gensym prefix)` make a symbol that is not `eq?` to any
+/// other, even one read or made by `string->symbol` with the same name.
+fn gensym(args: &[Expr]) -> Result<Expr, SchemeError> {
+    let prefix = match args.first() {
+        None => "g".to_string(),
+        Some(prefix) if prefix.is_str() || prefix.is_sym() => prefix.as_str()?,
+        Some(other) => return Err(SchemeError::wrong_type("string or symbol", other)),
+    };
+    Ok(Expr::Sym(Symbol::gensym(&prefix)))
+}
+
+fn car(args: &[Expr]) -> Result<Expr, SchemeError> {
+    args[0].car()
+}
+
+fn cdr(args: &[Expr]) -> Result<Expr, SchemeError> {
+    args[0].cdr()
+}
+
+/// `(length list)` is an error for an improper or cyclic list, rather
+/// than a walk off its end or a loop.
+fn length(args: &[Expr]) -> Result<Expr, SchemeError> {
+    let (items, tail) = split_list(&args[0])?;
+    if !tail.is_empty() {
+        return Err(SchemeError::wrong_type("list", &args[0]));
     }
-    Expr::Bool(true)
+    Ok(Expr::Int(items.len() as isize))
 }
 
+/// `(gc)` collects now and returns how many objects were freed.
+fn collect(_: &[Expr]) -> Result<Expr, SchemeError> {
+    Ok(Expr::Int(gc::collect() as isize))
+}
+
+/// `(heap-stats)` is an association list of the live objects by kind and
+/// of the totals so far: collections, objects freed and objects allocated.
+fn heap_stats(_: &[Expr]) -> Result<Expr, SchemeError> {
+    let stats = gc::stats();
+    let fields = [
+        ("environments", stats.environments),
+        ("pairs", stats.pairs),
+        ("vectors", stats.vectors),
+        ("continuations", stats.continuations),
+        ("winders", stats.winders),
+        ("collections", stats.collections),
+        ("freed", stats.freed),
+        ("allocations", stats.allocations),
+    ];
+    Ok(fields.iter().rev().fold(Expr::Nil, |acc, &(name, val)| {
+        Expr::new_pair(
+            Expr::new_pair(Expr::new_sym(name), Expr::Int(val as isize)),
+            acc,
+        )
+    }))
+}
 
 #[test]
 fn test_env() {
-    let mut env = Env::new();
+    let mut env = Globals::new();
     env.str_def("hello", Expr::new_str("world"));
 
     let val = env.str_lookup("hello");
-    assert!(val.unwrap().as_str() == "world");
+    assert!(val.unwrap().as_str().unwrap() == "world");
 
     env.str_def("1", Expr::Int(1));
     let val = env.str_lookup("1");
-    assert!(val.unwrap().as_int() == 1);
+    assert!(val.unwrap().as_int().unwrap() == 1);
 
     env.str_def("1", Expr::Int(2));
     let val = env.str_lookup("1");
-    assert!(val.unwrap().as_int() == 2);
+    assert!(val.unwrap().as_int().unwrap() == 2);
 
     env.str_def("sym", Expr::Int(2));
 
     let val = env.str_lookup("sym");
-    assert!(val.unwrap().as_int() == 2);
+    assert!(val.unwrap().as_int().unwrap() == 2);
 
     let val = env.str_lookup("null?");
     assert!(val.unwrap().is_proc());
@@ -260,81 +575,123 @@ fn test_env() {
 
     let val = env.str_lookup("<");
     assert!(val.unwrap().is_proc());
-
 }
 
 #[test]
-fn test_env_extend() {
-    let mut env = Env::new();
-    env.str_def("hello", Expr::new_str("world"));
-
-    let vars = Expr::new_pair(Expr::new_str("var"), Expr::Nil);
-    let vals = Expr::new_pair(Expr::new_str("val"), Expr::Nil);
-
-    let env = env.extend(vars, vals).clone();
-    let val = env.borrow_mut().str_lookup("var");
-    assert!(val.unwrap().as_str() == "val");
-
-    let val = env.borrow_mut().str_lookup("hello");
-    assert!(val.unwrap().as_str() == "world");
-
-    let vars = Expr::new_pair(Expr::new_str("var_x"), Expr::Nil);
-    let vals = Expr::new_pair(Expr::new_str("val_x"), Expr::Nil);
-
-    let env = env.borrow_mut().extend(vars, vals);
-    let val = env.borrow_mut().str_lookup("var_x");
-    assert!(val.unwrap().as_str() == "val_x");
+fn test_builtin_errors() {
+    match div(&[Expr::Int(1), Expr::Int(0)]) {
+        Err(SchemeError::DivisionByZero(exp)) => assert_eq!(exp.to_string(), "(/ 1 0)"),
+        res => panic!("unexpected {:?}", res),
+    }
+    assert_eq!(
+        car(&[Expr::Int(1)]),
+        Err(SchemeError::WrongType("pair", Expr::Int(1)))
+    );
+    assert_eq!(
+        add(&[Expr::Bool(true)]),
+        Err(SchemeError::WrongType("number", Expr::Bool(true)))
+    );
+
+    // the machine checks the arity a builtin was registered with
+    let mut vm = Vm::new();
+    let cons = vm.globals.str_lookup("cons").unwrap();
+    assert_eq!(
+        vm.call(cons, vec![Expr::Int(1)]),
+        Err(SchemeError::ArityMismatch(
+            Expr::new_sym("cons"),
+            Arity::Exactly(2),
+            1
+        ))
+    );
+    let sub = vm.globals.str_lookup("-").unwrap();
+    assert!(vm.call(sub, vec![]).is_err());
+}
 
-    env.borrow_mut().str_def("1", Expr::new_str("1"));
+#[test]
+fn test_native_closures() {
+    let mut vm = Vm::new();
+    let count = Rc::new(RefCell::new(0));
+    let counter = count.clone();
+    vm.globals.def_fn("tick!", Arity::Exactly(0), move |_, _| {
+        *counter.borrow_mut() += 1;
+        Ok(Expr::Int(*counter.borrow()))
+    });
+    let tick = vm.globals.str_lookup("tick!").unwrap();
+    assert_eq!(vm.call(tick.clone(), vec![]), Ok(Expr::Int(1)));
+    assert_eq!(vm.call(tick, vec![]), Ok(Expr::Int(2)));
+    assert_eq!(*count.borrow(), 2);
+
+    // a builtin can call back into Scheme through the machine
+    vm.globals.def_fn("twice", Arity::Exactly(2), |vm, args| {
+        let once = vm.call(args[0].clone(), vec![args[1].clone()])?;
+        vm.call(args[0].clone(), vec![once])
+    });
+    let twice = vm.globals.str_lookup("twice").unwrap();
+    let car = vm.globals.str_lookup("car").unwrap();
+    let nested = Expr::new_pair(Expr::new_pair(Expr::Int(7), Expr::Nil), Expr::Nil);
+    assert_eq!(vm.call(twice, vec![car, nested]), Ok(Expr::Int(7)));
+}
 
-    let val = env.borrow_mut().str_lookup("1");
-    assert!(val.unwrap().as_str() == "1");
+#[test]
+fn test_env_extend() {
+    let top = Env::top();
+    let names = Rc::new(vec![Symbol::intern("var")]);
+    let env = Env::extend(&top, names, vec![Expr::new_str("val")]);
+    let val = env.borrow().get(0, 0);
+    assert!(val.unwrap().as_str().unwrap() == "val");
+
+    let names = Rc::new(vec![Symbol::intern("var_x"), Symbol::intern("defined")]);
+    let env = Env::extend(&env, names, vec![Expr::new_str("val_x")]);
+    let val = env.borrow().get(0, 0);
+    assert!(val.unwrap().as_str().unwrap() == "val_x");
+    let val = env.borrow().get(1, 0);
+    assert!(val.unwrap().as_str().unwrap() == "val");
+
+    // a slot without an argument is empty until it is defined
+    assert_eq!(env.borrow().get(0, 1), None);
+    assert_eq!(env.borrow().name(0, 1), Symbol::intern("defined"));
+    env.borrow_mut().set(0, 1, Expr::new_str("1"));
+    let val = env.borrow().get(0, 1);
+    assert!(val.unwrap().as_str().unwrap() == "1");
 }
 
 #[test]
 fn test_env_parent() {
-    let mut env = Env::new();
-    env.str_def("hello", Expr::new_str("world"));
+    let top = Env::top();
+    let env = Env::extend(&top, Rc::new(vec![]), vec![]);
+    let parent = env.borrow().parent();
+    assert!(Rc::ptr_eq(&parent, &top));
+}
 
-    let vars = Expr::new_pair(Expr::new_str("var"), Expr::Nil);
-    let vals = Expr::new_pair(Expr::new_str("val"), Expr::Nil);
-
-
-    let env = env.extend(vars, vals).clone();
-    let parent = env.borrow_mut().parent().unwrap();
-    let env = parent.clone();
-    let val = env.borrow_mut().str_lookup("hello");
-    assert!(val.unwrap().as_str() == "world");
-}
-
-// #[bench]
-// fn env_bench(b: &mut Bencher) {
-//     fn test_env() {
-//         let mut env = Env::new();
-//         for i in 1isize..1000 {
-//             let key = i.to_string();
-//             env.def_var(key.clone(), Expr::new_str("world"));
-//         }
-//         for i in 1isize..1000 {
-//             let val = env.lookup(i.to_string());
-//             assert!(val.unwrap().as_str() == "world");
-//         }
-//     }
-
-//     b.iter(|| test_env());
-// }
-
-
-// #[bench]
-// fn env_bench_iter(b: &mut Bencher) {
-//     fn test_env() {
-//         let mut env = Env::new();
-//         for _ in 1..1000 {
-//             env.str_def("hello", Expr::new_str("world"));
-//             let val = env.str_lookup("hello");
-//             assert!(val.unwrap().as_str() == "world");
-//         }
-//     }
-
-//     b.iter(|| test_env());
-// }
+#[test]
+fn test_env_shared_frames() {
+    let top = Env::top();
+    let names = Rc::new(vec![Symbol::intern("x")]);
+    let outer = Env::extend(&top, names, vec![Expr::Int(1)]);
+    let inner = Env::extend(&outer, Rc::new(vec![]), vec![]);
+
+    // assignment through the inner frame lands in the frame that binds x
+    inner.borrow_mut().set(1, 0, Expr::Int(2));
+    assert!(inner.borrow().slots.is_empty());
+    assert!(outer.borrow().get(0, 0).unwrap().as_int().unwrap() == 2);
+}
+
+#[test]
+fn test_globals() {
+    let mut globals = Globals::new();
+    let late = Symbol::intern("late");
+    let slot = globals.slot(late);
+    assert_eq!(globals.get(slot), None);
+    assert_eq!(globals.name(slot), late);
+    // defining a global fills the slot code compiled earlier refers to
+    globals.str_def("late", Expr::Int(4));
+    assert_eq!(globals.get(slot), Some(&Expr::Int(4)));
+    assert_eq!(globals.slot(late), slot);
+    assert!(globals.str_lookup("car").unwrap().is_proc());
+    globals.slot(Symbol::intern("unbound"));
+    let bound = globals.bindings();
+    assert!(bound.contains(&(late, Expr::Int(4))));
+    assert!(!bound
+        .iter()
+        .any(|&(name, _)| name == Symbol::intern("unbound")));
+}
@@ -0,0 +1,168 @@
+use ast::{Arity, Expr, Span};
+use std::error;
+use std::fmt;
+use std::rc::Rc;
+
+/// Everything that can go wrong while evaluating a Scheme expression.
+///
+/// Each variant carries the offending expression so the caller can show
+/// the user what failed and carry on.
+#[derive(Clone, PartialEq, Debug)]
+pub enum SchemeError {
+    /// A symbol with no binding in any enclosing environment.
+    UnboundVariable(Expr),
+    /// A value of the wrong type: the name of the expected type and the
+    /// value that was found instead.
+    WrongType(&'static str, Expr),
+    /// A procedure applied to the wrong number of arguments: the procedure,
+    /// the counts it accepts and the count it actually received.
+    ArityMismatch(Expr, Arity, usize),
+    /// An index outside the object it indexes: the index and the object.
+    OutOfRange(isize, Expr),
+    /// An integer division whose divisor was zero, with the whole call.
+    DivisionByZero(Expr),
+    /// A numeric call whose result no number here can represent, such as
+    /// a complex square root or a power too large for memory, with the
+    /// whole call.
+    Unrepresentable(Expr),
+    /// A call that asked for more memory than there is, with the whole
+    /// call.
+    OutOfMemory(Expr),
+    /// A special form or macro use that does not have the required shape,
+    /// including a macro use that matches none of its rules.
+    BadSyntax(Expr),
+    /// Text the reader cannot parse, with a description of the problem.
+    ReadError(String),
+    /// Input that ends in the middle of a datum.
+    UnexpectedEof,
+    /// A file that cannot be read, with what went wrong.
+    IoError(String),
+    /// Evaluation stopped because the user asked it to, with Ctrl-C.
+    Interrupted,
+    /// Another error together with the source span of the innermost form
+    /// it was raised in.
+    Located(Rc<Span>, Box<SchemeError>),
+}
+
+impl SchemeError {
+    pub fn wrong_type(expected: &'static str, got: &Expr) -> SchemeError {
+        SchemeError::WrongType(expected, got.clone())
+    }
+
+    /// Attaches `span` unless the error already has a location, so the
+    /// innermost known position wins.
+    pub fn at(self, span: Option<Rc<Span>>) -> SchemeError {
+        match (self, span) {
+            (err @ SchemeError::Located(..), _) | (err, None) => err,
+            (err, Some(span)) => SchemeError::Located(span, Box::new(err)),
+        }
+    }
+
+    /// The error without the location attached to it, if any.
+    pub fn unlocated(&self) -> &SchemeError {
+        match *self {
+            SchemeError::Located(_, ref err) => err,
+            ref err => err,
+        }
+    }
+}
+
+impl fmt::Display for SchemeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            SchemeError::UnboundVariable(ref exp) => write!(f, "unbound variable {}", exp),
+            SchemeError::WrongType(expected, ref got) => {
+                write!(f, "wrong type: expected {}, got {}", expected, got)
+            }
+            SchemeError::ArityMismatch(ref exp, ref expected, got) => write!(
+                f,
+                "{}: wrong number of arguments (expected {}, got {})",
+                exp, expected, got
+            ),
+            SchemeError::OutOfRange(index, ref exp) => {
+                write!(f, "index {} out of range for {}", index, exp)
+            }
+            SchemeError::DivisionByZero(ref exp) => write!(f, "division by zero in {}", exp),
+            SchemeError::OutOfMemory(ref exp) => write!(f, "out of memory in {}", exp),
+            SchemeError::Unrepresentable(ref exp) => {
+                write!(f, "no representable number for {}", exp)
+            }
+            SchemeError::BadSyntax(ref exp) => write!(f, "bad syntax: {}", exp),
+            SchemeError::ReadError(ref msg) => write!(f, "read error: {}", msg),
+            SchemeError::UnexpectedEof => write!(f, "unexpected end of input"),
+            SchemeError::IoError(ref msg) => write!(f, "cannot read {}", msg),
+            SchemeError::Interrupted => write!(f, "interrupted"),
+            SchemeError::Located(ref span, ref err) => write!(f, "{} at {}", err, span),
+        }
+    }
+}
+
+impl error::Error for SchemeError {}
+
+#[test]
+fn test_error_display() {
+    let err = SchemeError::UnboundVariable(Expr::new_sym("foo"));
+    assert_eq!(err.to_string(), "unbound variable foo");
+
+    let err = SchemeError::wrong_type("integer", &Expr::Bool(true));
+    assert_eq!(err.to_string(), "wrong type: expected integer, got #t");
+
+    let err = SchemeError::ArityMismatch(Expr::new_sym("car"), Arity::Exactly(1), 2);
+    assert_eq!(
+        err.to_string(),
+        "car: wrong number of arguments (expected 1, got 2)"
+    );
+    let err = SchemeError::ArityMismatch(Expr::new_sym("f"), Arity::AtLeast(2), 1);
+    assert_eq!(
+        err.to_string(),
+        "f: wrong number of arguments (expected at least 2, got 1)"
+    );
+    let arity = Arity::OneOf(vec![Arity::Exactly(0), Arity::AtLeast(2)]);
+    let err = SchemeError::ArityMismatch(Expr::new_sym("g"), arity, 1);
+    assert_eq!(
+        err.to_string(),
+        "g: wrong number of arguments (expected 0 or at least 2, got 1)"
+    );
+
+    let err = SchemeError::OutOfRange(3, Expr::new_str("ab"));
+    assert_eq!(err.to_string(), "index 3 out of range for \"ab\"");
+
+    let err = SchemeError::BadSyntax(Expr::new_pair(Expr::new_sym("if"), Expr::Nil));
+    assert_eq!(err.to_string(), "bad syntax: (if)");
+
+    let span = Rc::new(Span {
+        file: Rc::from("lib.scm"),
+        line: 12,
+        col: 5,
+        start: 140,
+        end: 143,
+    });
+    let err = SchemeError::UnboundVariable(Expr::new_sym("foo")).at(Some(span.clone()));
+    assert_eq!(err.to_string(), "unbound variable foo at lib.scm:12:5");
+    // an error keeps the first, innermost location it was given
+    let outer = Rc::new(Span {
+        line: 1,
+        ..(*span).clone()
+    });
+    assert_eq!(err.clone().at(Some(outer)), err);
+    assert_eq!(
+        err.unlocated(),
+        &SchemeError::UnboundVariable(Expr::new_sym("foo"))
+    );
+    let call = Expr::new_pair(
+        Expr::new_sym("sqrt"),
+        Expr::new_pair(Expr::Int(-4), Expr::Nil),
+    );
+    assert_eq!(
+        SchemeError::Unrepresentable(call).to_string(),
+        "no representable number for (sqrt -4)"
+    );
+    let call = Expr::new_pair(
+        Expr::new_sym("make-string"),
+        Expr::new_pair(Expr::Int(1 << 40), Expr::Nil),
+    );
+    assert_eq!(
+        SchemeError::OutOfMemory(call).to_string(),
+        "out of memory in (make-string 1099511627776)"
+    );
+}
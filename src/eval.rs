@@ -1,278 +1,91 @@
-#[cfg(test)]
-use std;
-use std::cell::RefCell;
-use std::io::Read;
-use std::rc::Rc;
+//! Scaffolding for the evaluation tests, and the tests themselves.
+
+use std::io;
 
 use ast::*;
-use env::*;
+use error::SchemeError;
+use gc;
+use interp::Interpreter;
 use parser::*;
 
-pub struct Evaler<R> {
-    parser: Parser<R>,
-    env: Rc<RefCell<Env>>,
-    iteractive: bool,
+/// Reads forms from strings of code and evaluates them with an
+/// `Interpreter`, returning the value of the last, or `()` if there are
+/// none.
+pub struct Evaler {
+    parser: Parser<io::Empty>,
+    interp: Interpreter,
 }
 
-#[allow(dead_code)]
-impl<R: Read> Evaler<R> {
-    pub fn new(inner: R, iteractive: bool) -> Evaler<R> {
-        let env = Env::new();
+impl Evaler {
+    pub fn new() -> Evaler {
         Evaler {
-            parser: Parser::new_from(inner, iteractive),
-            env: Rc::new(RefCell::new(env)),
-            iteractive: iteractive,
+            parser: Parser::new_from(io::empty(), false),
+            interp: Interpreter::new(),
         }
     }
 
-    pub fn eval(&mut self) -> Option<Expr> {
-        let mut res = None;
-        loop {
-            if self.iteractive {
-                print!("> ");
-            }
-            let exp = self.parser.read_exp();
-            match exp {
-                Some(_exp) => {
-                    let r = self.eval_exp(_exp);
-                    if self.iteractive {
-                        r.print();
-                        println!("");
-                    }
-                    res = Some(r);
-                }
-                None => break,
-            }
-        }
-        res
-    }
-
-    pub fn eval_from(&mut self, code: String) -> Option<Expr> {
+    pub fn eval_from(&mut self, code: String) -> Result<Expr, SchemeError> {
         self.parser.load(code);
-        let mut res = None;
-        loop {
-            let exp = self.parser.read_exp();
-            match exp {
-                Some(_exp) => {
-                    let r = self.eval_exp(_exp);
-                    res = Some(r);
-                }
-                None => break,
-            }
+        let mut res = Expr::Nil;
+        while let Some(exp) = self.parser.read_exp()? {
+            res = self
+                .eval_toplevel(exp)
+                .map_err(|err| err.at(self.parser.last_span()))?;
         }
-        res
+        Ok(res)
     }
 
-    fn eval_exp(&mut self, exp: Expr) -> Expr {
-        if exp.is_self() {
-            return exp;
-        }
-        if exp.is_sym() {
-            return self.env.borrow().lookup(exp.as_str()).unwrap();
-        }
-        if exp.is_quote() {
-            return exp.cdr().car();
-        }
-        if exp.is_assign() {
-            return self.eval_assign(exp);
-        }
-        if exp.is_def() {
-            return self.eval_def(exp);
-        }
-        if exp.is_begin() {
-            return self.eval_begin(exp);
+    /// Evaluates `code` read from the source `file`, so that errors report
+    /// where in that file they happened.
+    pub fn eval_source(&mut self, file: &str, code: String) -> Result<Expr, SchemeError> {
+        self.parser.load_file(file, code);
+        let mut res = Expr::Nil;
+        while let Some(exp) = self.parser.read_exp()? {
+            res = self
+                .eval_toplevel(exp)
+                .map_err(|err| err.at(self.parser.last_span()))?;
         }
-        if exp.is_if() {
-            return self.eval_if(exp);
-        }
-        if exp.is_lambda() {
-            return self.eval_lambda(exp);
-        }
-        if exp.is_and() {
-            return self.eval_and(exp);
-        }
-        if exp.is_or() {
-            return self.eval_or(exp);
-        }
-        if exp.is_cond() {
-            return self.eval_cond(exp);
-        }
-        if exp.is_let() {
-            return self.eval_let(exp);
-        }
-        if exp.is_pair() {
-            return self.eval_app(exp);
-        }
-        Expr::new_sym("OK")
-    }
-
-    fn eval_assign(&mut self, exp: Expr) -> Expr {
-        let var = exp.c("da");
-        let val = self.eval_exp(exp.c("dda"));
-        let env = self.env.clone();
-        env.borrow_mut().def_var(var.as_str(), val);
-        Expr::new_sym("OK")
+        Ok(res)
     }
 
-    fn eval_def(&mut self, exp: Expr) -> Expr {
-        let var = exp.def_var();
-        let val = exp.def_val();
-        let val = self.eval_exp(val);
-        let env = self.env.clone();
-        env.borrow_mut().def_var(var.as_str(), val);
-        Expr::new_sym("OK")
-    }
-
-    fn eval_if(&mut self, exp: Expr) -> Expr {
-        let pred = exp.c("da");
-        let blk_t = exp.c("dda");
-        let blk_f = exp.c("ddd");
-        let res = self.eval_exp(pred);
-        if res.is_true() {
-            self.eval_exp(blk_t)
-        } else {
-            if res.is_empty() {
-                Expr::Bool(false)
-            } else {
-                self.eval_exp(blk_f.car())
-            }
-        }
-    }
-
-    fn eval_and(&mut self, exp: Expr) -> Expr {
-        let mut elems = exp.cdr();
-        if elems.is_empty() {
-            return Expr::Bool(true);
-        }
-        loop {
-            if elems.is_last() {
-                break;
-            }
-            let res = self.eval_exp(elems.car());
-            if res.is_false() {
-                return Expr::Bool(false);
-            }
-            elems = elems.cdr();
-        }
-        self.eval_exp(elems.car())
-    }
-
-    fn eval_or(&mut self, exp: Expr) -> Expr {
-        let mut elems = exp.cdr();
-        if elems.is_empty() {
-            return Expr::Bool(true);
-        }
-        loop {
-            if elems.is_last() {
-                break;
-            }
-            let res = self.eval_exp(elems.car());
-            if res.is_true() {
-                return Expr::Bool(true);
-            }
-            elems = elems.cdr();
-        }
-        self.eval_exp(elems.car())
-    }
-
-    fn eval_cond(&mut self, exp: Expr) -> Expr {
-        let mut elems = exp.cdr().car();
-        loop {
-            if elems.is_empty() {
-                break;
-            }
-            let cur = elems.car();
-            let val = self.eval_exp(cur.clone());
-            if val.is_true() || val == Expr::new_sym("else") {
-                return self.eval_exp(cur.clone().cdr().car());
-            }
-            elems = elems.cdr();
-        }
-        Expr::Bool(true)
-    }
-
-    fn eval_let(&mut self, exp: Expr) -> Expr {
-        fn bind_params(exp: Expr) -> Expr {
-            if exp.is_empty() {
-                Expr::Nil
-            } else {
-                Expr::new_pair(exp.c("aa"), bind_params(exp.cdr()))
-            }
-        }
-
-        fn bind_args(exp: Expr) -> Expr {
-            if exp.is_empty() {
-                Expr::Nil
-            } else {
-                Expr::new_pair(exp.c("ada"), bind_args(exp.cdr()))
-            }
-        }
-
-        let bindings = exp.c("da");
-        let obj = Expr::new_pair(
-            bind_params(bindings.clone()).make_lambda(exp.c("dd")),
-            bind_args(bindings),
-        );
-        self.eval_exp(obj)
-    }
-
-    fn eval_begin(&mut self, exp: Expr) -> Expr {
-        let mut _exp = exp.cdr();
-        loop {
-            if _exp.is_last() {
-                break;
-            }
-            self.eval_exp(_exp.car());
-            _exp = _exp.cdr();
-        }
-        self.eval_exp(_exp.car())
-    }
-
-    fn eval_values(&mut self, exprs: Expr) -> Expr {
-        if exprs.is_empty() {
-            Expr::Nil
-        } else {
-            let first = self.eval_exp(exprs.car());
-            Expr::new_pair(first, self.eval_values(exprs.cdr()))
-        }
-    }
-
-    fn eval_app(&mut self, expr: Expr) -> Expr {
-        let _proc = self.eval_exp(expr.car());
-        let _args = self.eval_values(expr.cdr());
-        if _proc.is_proc() {
-            let func = _proc.as_proc().func();
-            return func(_args);
-        } else {
-            assert!(_proc.is_cproc());
-            let _vars = _proc.params();
-            let env = self.env.clone();
-            self.env = env.borrow_mut().extend(_vars, _args);
-            let begin = Expr::new_sym("begin");
-            let res = self.eval_exp(Expr::new_pair(begin, _proc.body()));
-            let env = self.env.clone();
-            self.env = env.borrow_mut().parent().unwrap();
-            res
-        }
-    }
-
-    fn eval_lambda(&mut self, expr: Expr) -> Expr {
-        // vars + body + env
-        Expr::new_cproc(expr.c("da"), expr.c("dd"), self.env.clone())
+    fn eval_toplevel(&mut self, exp: Expr) -> Result<Expr, SchemeError> {
+        self.interp.eval_expr(&exp)
     }
 }
 
 #[allow(unused_macros)]
 macro_rules! test_case {
     ($test_str:expr, $expect_type:ident, $expect_val:expr) => {{
-        let mut evaler = Evaler::new(std::io::stdin(), false);
+        let mut evaler = Evaler::new();
         let res = evaler.eval_from($test_str.to_string()).unwrap();
-        if res.$expect_type() != $expect_val {
+        if res.$expect_type().unwrap() != $expect_val {
             assert!(false);
         }
     }};
 }
 
+/// Evaluates `test_str` in a new evaluator and checks how the last value
+/// prints.
+#[allow(unused_macros)]
+macro_rules! test_val {
+    ($test_str:expr, $expect:expr) => {{
+        let mut evaler = Evaler::new();
+        let res = evaler.eval_from($test_str.to_string()).unwrap();
+        assert_eq!(res.to_string(), $expect, "{}", $test_str);
+    }};
+}
+
+/// Evaluates `test_str` in a new evaluator and checks that it fails with
+/// `expect_err`.
+#[allow(unused_macros)]
+macro_rules! test_err {
+    ($test_str:expr, $expect_err:expr) => {{
+        let mut evaler = Evaler::new();
+        let res = evaler.eval_from($test_str.to_string());
+        assert_eq!(res, Err($expect_err), "{}", $test_str);
+    }};
+}
+
 #[test]
 fn test_evaler() {
     test_case!("11", as_int, 11);
@@ -311,13 +124,13 @@ fn test_evaler() {
     test_case!("(let ((a 1)) (> (+ a 1) 0))", as_bool, true);
     test_case!("(let ((a 1) (b 2)) (> a b))", as_bool, false);
     test_case!("(define (add a b) (+ a b))", as_str, "OK");
-    test_case!("(cond ((eq? 1 1) 1) ((> 1 2) 2) ))", as_int, 1);
+    test_case!("(cond ((eq? 1 1) 1) ((> 1 2) 2))", as_int, 1);
     test_case!("(begin (set! x 5) (set! x 4) (+ x 1))", as_int, 5);
     test_case!("(car '(1 2))", as_int, 1);
     test_case!("(car (cdr '(1 2)))", as_int, 2);
     test_case!("(car (cons 1 2))", as_int, 1);
-    test_case!("(car (cons (pair? '(1)) pair? '()))", as_bool, true);
-    test_case!("(car (cdr (cons 1 2)))", as_int, 2);
+    test_case!("(car (cons (pair? '(1)) '()))", as_bool, true);
+    test_case!("(cdr (cons 1 2))", as_int, 2);
     test_case!("(car (car (cons (cons 2 3) (cons 1 2))))", as_int, 2);
     test_case!("((lambda (x) x) 1)", as_int, 1);
     test_case!("((lambda (x y) (+ x y )) 1 2)", as_int, 3);
@@ -337,14 +150,909 @@ fn test_evaler() {
     );
 }
 
-// #[bench]
-// fn eval_bench(b: &mut Bencher) {
-//     b.iter(|| test_evaler());
-// }
-
-// #[bench]
-// fn lambda_bench(b: &mut Bencher) {
-//     b.iter(||
-//            test_case!("((lambda (x y ) (if ( = y 0) 1 (* y (x x (- y 1)))))
-//                       (lambda (x y ) (if ( = y 0) 1 (* y (x x (- y 1))))) 5)", as_int, 5isize*4*3*2));
-// }
+/// Defines `(grown)`, how many more environments are live than when
+/// `(mark)` was last called. A loop of tail calls keeps this flat, as each
+/// call's frame replaces its caller's.
+const ENV_COUNT: &str = "(define (environments) (cdr (assq 'environments (heap-stats))))
+                         (define start 0)
+                         (define (mark) (set! start (environments)))
+                         (define (grown) (- (environments) start))";
+
+#[test]
+fn test_tail_calls_deep() {
+    test_val!(
+        format!(
+            "{}
+             (define (count n)
+               (define (iter i acc)
+                 (if (= i (quotient n 2)) (mark))
+                 (if (= i 0) (list acc (grown)) (iter (- i 1) (+ acc 1))))
+               (iter n 0))
+             (count 1000000)",
+            ENV_COUNT
+        ),
+        "(1000000 0)"
+    );
+}
+
+#[test]
+fn test_tail_calls() {
+    // each loop ends with as many live environments as it started with, which
+    // it would not if a call in tail position kept its caller's frame
+    test_val!(
+        format!(
+            "{}
+             (define (length items)
+               (define (iter a count)
+                 (if (null? a) (list count (grown)) (iter (cdr a) (+ 1 count))))
+               (mark)
+               (iter items 0))
+             (length '(1 2 3 4))",
+            ENV_COUNT
+        ),
+        "(4 1)"
+    );
+
+    macro_rules! test_loop {
+        ($body:expr) => {{
+            let code = format!(
+                "{} (define (run n) (define (f n) {}) (mark) (f n)) (run 20000)",
+                ENV_COUNT, $body
+            );
+            test_val!(code, "#t");
+        }};
+    }
+    test_loop!("(if (= n 0) (< (grown) 4) (f (- n 1)))");
+    test_loop!("(begin 1 (if (= n 0) (< (grown) 4) (f (- n 1))))");
+    test_loop!("(let ((m (- n 1))) (if (< m 0) (< (grown) 4) (f m)))");
+    test_loop!("(if (and #t (> n 0)) (and #t (f (- n 1))) (< (grown) 4))");
+    test_loop!("(if (or #f (= n 0)) (< (grown) 4) (or #f (f (- n 1))))");
+    test_loop!("((lambda (m) (if (= m 0) (< (grown) 4) (f (- m 1)))) n)");
+}
+
+#[test]
+fn test_numeric_tower() {
+    test_val!(
+        "(define (fact n) (if (= n 1) 1 (* n (fact (- n 1))))) (fact 25)",
+        "15511210043330985984000000"
+    );
+    test_val!(
+        "(- (* 99999999999 99999999999 99999999999) 1)",
+        "999999999970000000000299999999998"
+    );
+    test_val!("(+ 1/3 1/6)", "1/2");
+    test_val!("(/ 1 3)", "1/3");
+    test_val!("(/ 6 3)", "2");
+    test_val!("(/ 2)", "1/2");
+    test_val!("(- 5)", "-5");
+    test_val!("(- 0)", "0");
+    test_val!("(- 0.0)", "-0.0");
+    test_val!("(- -0.0)", "0.0");
+    test_val!("(- 0.0 0.0)", "0.0");
+    test_val!("(+ 1 2.5)", "3.5");
+    test_val!("(* 1/2 4.0)", "2.0");
+    test_val!("(+ 0.1 0.2)", "0.30000000000000004");
+    test_val!("(/ 1.0 0.0)", "+inf.0");
+    test_val!("(exact->inexact 1/4)", "0.25");
+    test_val!("(inexact->exact 0.5)", "1/2");
+    test_val!("(quotient 17 5)", "3");
+    test_val!("(quotient -17 5)", "-3");
+    test_val!("(remainder -17 5)", "-2");
+    test_val!("(modulo -17 5)", "3");
+    test_val!("(modulo 17 -5)", "-3");
+    test_val!("(modulo 17.0 5)", "2.0");
+    test_val!("(quotient 7 2.0)", "3.0");
+    test_val!("(expt 2 100)", "1267650600228229401496703205376");
+    test_val!("(expt 2 -2)", "1/4");
+    test_val!("(expt 2/3 2)", "4/9");
+    test_val!("(expt 2.0 3)", "8.0");
+    test_val!("(expt 4 1/2)", "2.0");
+    test_val!("(sqrt 16)", "4");
+    test_val!("(sqrt 1/4)", "1/2");
+    test_val!("(sqrt 2)", "1.4142135623730951");
+    test_val!("(sqrt 16.0)", "4.0");
+    test_val!("(expt 1 (expt 10 10))", "1");
+    test_val!("(expt -1 (+ (expt 10 10) 1))", "-1");
+    test_val!("(expt 0 (expt 10 10))", "0");
+    test_val!("(expt 10.0 400)", "+inf.0");
+    test_val!("(number->string 255)", r#""255""#);
+    test_val!("(number->string 255 16)", r#""ff""#);
+    test_val!("(number->string 1/3 2)", r#""1/11""#);
+    test_val!("(number->string 1.5)", r#""1.5""#);
+    test_val!("(< 1 2 3)", "#t");
+    test_val!("(< 1 3 2)", "#f");
+    test_val!("(>= 3 3 1)", "#t");
+    test_val!("(= 1 1.0 2/2)", "#t");
+    test_val!("(< 1/3 0.34)", "#t");
+    test_val!("(integer? 2.0)", "#t");
+    test_val!("(integer? 1/2)", "#f");
+    test_val!("(rational? 1/2)", "#t");
+    test_val!("(exact? 1/2)", "#t");
+    test_val!("(inexact? 1.5)", "#t");
+    test_val!("(number? 'a)", "#f");
+}
+
+#[test]
+fn test_closures() {
+    test_case!(
+        "(define (make-counter)
+           (let ((n 0))
+             (lambda () (set! n (+ n 1)) n)))
+         (define c1 (make-counter))
+         (define c2 (make-counter))
+         (c1) (c1) (c2)
+         (+ (* 10 (c1)) (c2))",
+        as_int,
+        32
+    );
+    test_case!(
+        "(define (make-account balance)
+           (define (withdraw amount)
+             (if (> amount balance)
+                 0
+                 (begin (set! balance (- balance amount)) balance)))
+           (define (deposit amount)
+             (set! balance (+ balance amount))
+             balance)
+           (lambda (m amount)
+             (if (eq? m 'withdraw) (withdraw amount) (deposit amount))))
+         (define acc (make-account 100))
+         (acc 'withdraw 30)
+         (acc 'deposit 50)
+         (acc 'withdraw 10)",
+        as_int,
+        110
+    );
+    // free variables resolve where the procedure was defined, not called
+    test_case!(
+        "(define x 1)
+         (define (get-x) x)
+         (define (call-with-x x) (get-x))
+         (call-with-x 2)",
+        as_int,
+        1
+    );
+    test_case!(
+        "(define (adder n) (lambda (x) (+ x n)))
+         (define add5 (adder 5))
+         (define n 100)
+         (add5 1)",
+        as_int,
+        6
+    );
+    // set! on a global from inside a procedure updates the global frame
+    test_case!(
+        "(define total 0)
+         (define (bump! k) (set! total (+ total k)))
+         (bump! 3) (bump! 4)
+         total",
+        as_int,
+        7
+    );
+    // define inside a body stays local to that call
+    test_case!(
+        "(define y 1)
+         (define (shadow) (define y 2) y)
+         (+ (shadow) y)",
+        as_int,
+        3
+    );
+}
+
+#[test]
+fn test_lambda_params() {
+    test_val!("((lambda args args))", "()");
+    test_val!("((lambda args args) 1 2 3)", "(1 2 3)");
+    test_val!(
+        "((lambda (a b . rest) (cons a (cons b rest))) 1 2)",
+        "(1 2)"
+    );
+    test_val!("((lambda (a b . rest) rest) 1 2 3 4)", "(3 4)");
+    test_val!(
+        "(define (list . items) items)
+         (define (sum . ns) (apply + ns))
+         (list (sum) (sum 1 2 3))",
+        "(0 6)"
+    );
+    // the rest list is fresh for each call, even through apply
+    test_val!(
+        "(define l '(1 2))
+         (define (f . xs) (set-car! xs 9) xs)
+         (apply f l)
+         l",
+        "(1 2)"
+    );
+    test_val!(
+        "(define area
+           (case-lambda
+             ((r) (* 3 r r))
+             ((w h) (* w h))
+             ((w h . more) (cons (* w h) more))))
+         (define (list . items) items)
+         (list (area 2) (area 2 3) (area 1 2 3 4))",
+        "(12 6 (2 3 4))"
+    );
+    // the first clause that accepts the arguments wins
+    test_val!(
+        "(define f (case-lambda (args 'any) ((x) 'one)))
+         (f 1)",
+        "any"
+    );
+    test_val!(
+        "(define (make-counter)
+           (define n 0)
+           (case-lambda (() n) ((k) (set! n (+ n k)) n)))
+         (define c (make-counter))
+         (c 5) (c 2)
+         (c)",
+        "7"
+    );
+
+    test_err!(
+        "(define (f a b . rest) a) (f 1)",
+        SchemeError::ArityMismatch(Expr::new_sym("f"), Arity::AtLeast(2), 1)
+    );
+    test_err!(
+        "(define (f a) a) (f)",
+        SchemeError::ArityMismatch(Expr::new_sym("f"), Arity::Exactly(1), 0)
+    );
+    test_err!(
+        "(define g (case-lambda ((a) a) ((a b c . d) a))) (g 1 2)",
+        SchemeError::ArityMismatch(
+            Expr::new_sym("g"),
+            Arity::OneOf(vec![Arity::Exactly(1), Arity::AtLeast(3)]),
+            2
+        )
+    );
+    test_err!("(lambda (a . 1) a)", SchemeError::BadSyntax(Expr::Int(1)));
+    test_err!(
+        "(case-lambda)",
+        SchemeError::BadSyntax(Expr::new_pair(Expr::new_sym("case-lambda"), Expr::Nil))
+    );
+}
+
+#[test]
+fn test_let_forms() {
+    test_case!("(let* () 1)", as_int, 1);
+    test_case!(
+        "(let* ((x 1) (y (+ x 1)) (x (* y 10))) (+ x y))",
+        as_int,
+        22
+    );
+    // plain let evaluates every init outside the new bindings
+    test_case!("(define x 5) (let ((x 1) (y x)) y)", as_int, 5);
+    test_case!(
+        "(letrec ((even? (lambda (n) (if (= n 0) #t (odd? (- n 1)))))
+                  (odd? (lambda (n) (if (= n 0) #f (even? (- n 1))))))
+           (even? 100))",
+        as_bool,
+        true
+    );
+    test_case!(
+        "(letrec* ((a 1) (b (+ a 1)) (f (lambda () (* a b)))) (f))",
+        as_int,
+        2
+    );
+    // the body of a letrec may define the same names again
+    test_case!("(letrec ((x 1)) (define x 2) x)", as_int, 2);
+    test_case!(
+        "(let loop ((i 0) (acc 0))
+           (if (> i 10) acc (loop (+ i 1) (+ acc i))))",
+        as_int,
+        55
+    );
+    // the loop name is not in scope in the inits
+    test_case!(
+        "(define (loop n) 100)
+         (let loop ((i (loop 0))) (if (> i 100) i (loop (+ i 1))))",
+        as_int,
+        101
+    );
+    // a named let is a proper loop
+    test_case!(
+        "(let loop ((i 100000)) (if (= i 0) 'done (loop (- i 1))))",
+        as_str,
+        "done"
+    );
+    // internal definitions are mutually recursive and local to the call
+    test_case!(
+        "(define (parity n)
+           (define (ev? n) (if (= n 0) 'even (od? (- n 1))))
+           (define (od? n) (if (= n 0) 'odd (ev? (- n 1))))
+           (ev? n))
+         (parity 7)",
+        as_str,
+        "odd"
+    );
+    test_case!(
+        "(define (length items)
+           (define (iter a count)
+             (if (null? a) count (iter (cdr a) (+ 1 count))))
+           (iter items 0))
+         (define (iter . args) 'global)
+         (+ (length '(1 2 3)) (if (eq? (iter) 'global) 10 0))",
+        as_int,
+        13
+    );
+
+    let mut evaler = Evaler::new();
+    assert_eq!(
+        evaler.eval_from("(letrec ((a b) (b 1)) a)".to_string()),
+        Err(SchemeError::UnboundVariable(Expr::new_sym("b")))
+    );
+    assert!(evaler.eval_from("(let* ((x)) x)".to_string()).is_err());
+    assert!(evaler.eval_from("(letrec ((1 2)) 3)".to_string()).is_err());
+}
+
+#[test]
+fn test_cond_case() {
+    test_case!("(cond ((cons 1 'b) => cdr) (else 'c))", as_str, "b");
+    test_case!("(cond (#f 1) ((+ 1 2)) (else 4))", as_int, 3);
+    test_case!("(define x 0) (cond (#t (set! x 5) (+ x 1)))", as_int, 6);
+    test_case!("(cond (#f 1) (else 2 3))", as_int, 3);
+    test_case!("(cond (#f 1))", as_str, "OK");
+    // a local binding of `else` is just a variable
+    test_case!("(let ((else #f)) (cond (else 1) (#t 2)))", as_int, 2);
+    test_case!(
+        "(define (f n) (cond ((= n 0) 0) ((- n 1) => f))) (f 100000)",
+        as_int,
+        0
+    );
+
+    test_val!(
+        "(define (kind x)
+           (case x ((1 2 3) 'small) ((a b) 'letter) (else 'other)))
+         (cons (kind 2) (cons (kind 'b) (kind 9)))",
+        "(small letter . other)"
+    );
+    test_case!(
+        "(case (* 2 3) ((2 3 5 7) 'prime) ((1 4 6 8 9) 'composite))",
+        as_str,
+        "composite"
+    );
+    test_case!("(case 2.5 ((2.5) 'x) (else 'y))", as_str, "x");
+    test_case!("(case 'z ((a) 1))", as_str, "OK");
+    test_case!(
+        "(case 5 ((5) => (lambda (x) (* x x))) (else 0))",
+        as_int,
+        25
+    );
+    test_case!("(case 7 ((5) 0) (else => (lambda (x) (+ x 1))))", as_int, 8);
+
+    test_case!("(define x 1) (when (> x 0) (set! x 2) (+ x 1))", as_int, 3);
+    test_case!("(when #f 1)", as_str, "OK");
+    test_case!("(unless #f 1 2)", as_int, 2);
+    test_case!("(unless #t 1)", as_str, "OK");
+
+    test_case!(
+        "(do ((i 0 (+ i 1)) (acc 0 (+ acc i))) ((= i 5) acc))",
+        as_int,
+        10
+    );
+    test_val!(
+        "(define x '())
+         (do ((i 0 (+ i 1))) ((= i 3)) (set! x (cons i x)))
+         x",
+        "(2 1 0)"
+    );
+    // a variable without a step keeps its value
+    test_case!("(do ((i 0 (+ i 1)) (k 7)) ((= i 3) k))", as_int, 7);
+    test_case!("(do ((i 100000 (- i 1))) ((= i 0) 'done))", as_str, "done");
+
+    let mut evaler = Evaler::new();
+    assert!(evaler.eval_from("(cond ())".to_string()).is_err());
+    assert!(evaler
+        .eval_from("(cond (else 1) (#t 2))".to_string())
+        .is_err());
+    assert!(evaler.eval_from("(cond (1 => f g))".to_string()).is_err());
+    assert!(evaler.eval_from("(case)".to_string()).is_err());
+    assert!(evaler.eval_from("(when)".to_string()).is_err());
+    assert!(evaler.eval_from("(do ((1 2)) (#t))".to_string()).is_err());
+    assert!(evaler.eval_from("(do () ())".to_string()).is_err());
+}
+
+#[test]
+fn test_macros() {
+    test_case!(
+        "(define-syntax swap!
+           (syntax-rules ()
+             ((_ a b) (let ((tmp a)) (set! a b) (set! b tmp)))))
+         (define tmp 1)
+         (define other 2)
+         (swap! tmp other)
+         (- (* 10 tmp) other)",
+        as_int,
+        19
+    );
+    // the temporary introduced by my-or does not capture the user's t
+    test_case!(
+        "(define-syntax my-or
+           (syntax-rules ()
+             ((_) #f)
+             ((_ e) e)
+             ((_ e r ...) (let ((t e)) (if t t (my-or r ...))))))
+         (define t 5)
+         (my-or #f t)",
+        as_int,
+        5
+    );
+    // a renamed binder is never the identifier its name reads as
+    test_case!(
+        "(define v#12 'global)
+         ((lambda (v) ((lambda (v) v#12) 2)) 1)",
+        as_str,
+        "global"
+    );
+    test_case!(
+        "(define x#1 5) ((lambda (x) ((lambda (x) x#1) 2)) 1)",
+        as_int,
+        5
+    );
+    test_case!(
+        "(define-syntax while
+           (syntax-rules ()
+             ((_ cond body ...)
+              ((lambda ()
+                 (define (loop) (if cond (begin body ... (loop)) #f))
+                 (loop))))))
+         (define i 0)
+         (define sum 0)
+         (while (< i 5) (set! sum (+ sum i)) (set! i (+ i 1)))
+         sum",
+        as_int,
+        10
+    );
+    test_case!(
+        "(define-syntax my-if
+           (syntax-rules (then else)
+             ((_ c then t else e) (if c t e))))
+         (my-if #f then 1 else 2)",
+        as_int,
+        2
+    );
+    // rebinding a keyword at the use site does not affect the template
+    test_case!(
+        "(define-syntax my-unless
+           (syntax-rules () ((_ c body ...) (if c #f (begin body ...)))))
+         (let ((if car)) (my-unless #f 1 2))",
+        as_int,
+        2
+    );
+    // nor does shadowing a global the template refers to
+    test_case!(
+        "(define-syntax first (syntax-rules () ((_ x) (car x))))
+         (define (f car) (first car))
+         (f '(1 2))",
+        as_int,
+        1
+    );
+    test_case!(
+        "(define (g x)
+           (let-syntax ((get-x (syntax-rules () ((_) x))))
+             (let ((x 2)) (+ x (get-x)))))
+         (g 1)",
+        as_int,
+        3
+    );
+    test_case!(
+        "(letrec-syntax
+             ((my-and (syntax-rules ()
+                        ((_) #t)
+                        ((_ e) e)
+                        ((_ e r ...) (if e (my-and r ...) #f)))))
+           (my-and 1 2 3))",
+        as_int,
+        3
+    );
+    test_case!(
+        "(define (h)
+           (define-syntax twice (syntax-rules () ((_ e) (begin e e))))
+           (define n 0)
+           (twice (set! n (+ n 1)))
+           n)
+         (h)",
+        as_int,
+        2
+    );
+    test_case!(
+        "(define-syntax def-const (syntax-rules () ((_ name v) (define name v))))
+         (def-const k 42)
+         k",
+        as_int,
+        42
+    );
+}
+
+#[test]
+fn test_pairs() {
+    test_case!("(define p (cons 1 2)) (set-car! p 3) (car p)", as_int, 3);
+    test_case!("(define p (cons 1 2)) (set-cdr! p 4) (cdr p)", as_int, 4);
+    // two references to one pair see each other's updates
+    test_case!(
+        "(define a (cons 1 (cons 2 '())))
+         (define b (cdr a))
+         (set-car! b 5)
+         (car (cdr a))",
+        as_int,
+        5
+    );
+    test_case!(
+        "(define (make-counter)
+           (let ((cell (cons 0 '())))
+             (lambda () (set-car! cell (+ (car cell) 1)) (car cell))))
+         (define c (make-counter))
+         (c)
+         (c)",
+        as_int,
+        2
+    );
+    test_case!("(define p (cons 1 2)) (eq? p p)", as_bool, true);
+    test_case!("(eq? (cons 1 2) (cons 1 2))", as_bool, false);
+    test_case!("(eqv? '(1) '(1))", as_bool, false);
+    test_case!("(equal? (cons 1 2) (cons 1 2))", as_bool, true);
+    test_case!("(equal? '(1 (2 #(3))) '(1 (2 #(3))))", as_bool, true);
+    test_case!("(equal? '(1 2) '(1 3))", as_bool, false);
+    test_case!("(eqv? 2 2)", as_bool, true);
+    test_case!("(eqv? 2 2.0)", as_bool, false);
+    test_case!("(eqv? 'a 'a)", as_bool, true);
+    test_case!("(eqv? '() '())", as_bool, true);
+    test_case!("(define v #(1)) (eq? v v)", as_bool, true);
+    test_case!("(eqv? #(1) #(1))", as_bool, false);
+    test_case!("(define (f) f) (eq? (f) f)", as_bool, true);
+    // each call closes over a new frame, so makes a new procedure
+    test_case!(
+        "(define (make) (lambda () 1))
+         (eq? (make) (make))",
+        as_bool,
+        false
+    );
+    // equal? terminates on circular lists
+    test_case!(
+        "(define a (cons 1 (cons 2 '())))
+         (set-cdr! (cdr a) a)
+         (define b (cons 1 (cons 2 '())))
+         (set-cdr! (cdr b) b)
+         (equal? a b)",
+        as_bool,
+        true
+    );
+
+    let mut evaler = Evaler::new();
+    assert_eq!(
+        evaler.eval_from("(set-car! '() 1)".to_string()),
+        Err(SchemeError::WrongType("pair", Expr::Nil))
+    );
+    assert_eq!(
+        evaler.eval_from("(cons 1 2 3)".to_string()),
+        Err(SchemeError::ArityMismatch(
+            Expr::new_sym("cons"),
+            Arity::Exactly(2),
+            3
+        ))
+    );
+}
+
+#[test]
+fn test_symbols() {
+    test_case!("(eq? 'abc (string->symbol \"abc\"))", as_bool, true);
+    test_case!("(symbol->string 'abc)", as_str, "abc");
+    test_case!("(symbol->string (string->symbol \"a b\"))", as_str, "a b");
+    test_case!("(symbol? (string->symbol \"x\"))", as_bool, true);
+    test_case!("(eq? (gensym) (gensym))", as_bool, false);
+    test_case!("(let ((g (gensym))) (eq? g g))", as_bool, true);
+    // a gensym is never the symbol that its name reads as
+    test_case!(
+        "(let ((g (gensym 'tmp)))
+           (eq? g (string->symbol (symbol->string g))))",
+        as_bool,
+        false
+    );
+
+    let mut evaler = Evaler::new();
+    let name = evaler.eval_from("(symbol->string (gensym \"tmp\"))".to_string());
+    assert!(name.unwrap().as_str().unwrap().starts_with("tmp"));
+    assert_eq!(
+        evaler.eval_from("(symbol->string \"abc\")".to_string()),
+        Err(SchemeError::WrongType("symbol", Expr::new_str("abc")))
+    );
+    assert_eq!(
+        evaler.eval_from("(string->symbol 'abc)".to_string()),
+        Err(SchemeError::WrongType("string", Expr::new_sym("abc")))
+    );
+}
+
+#[test]
+fn test_gc() {
+    // every call leaves behind a frame that refers to itself through the
+    // closure bound in it
+    test_case!(
+        "(define (make-rec) (define (self) self) self)
+         (define (churn n) (if (= n 0) 'done (begin (make-rec) (churn (- n 1)))))
+         (define (live-envs) (cdr (car (heap-stats))))
+         (churn 100)
+         (gc)
+         (define before (live-envs))
+         (churn 1000)
+         (define freed (gc))
+         (and (= (live-envs) before) (>= freed 1000))",
+        as_bool,
+        true
+    );
+    // a cycle that is still referenced survives
+    test_case!(
+        "(define p (cons 1 '()))
+         (set-cdr! p p)
+         (gc)
+         (car (cdr (cdr p)))",
+        as_int,
+        1
+    );
+    // a continuation stored in a frame it refers to
+    let mut evaler = Evaler::new();
+    let code = "(define (f) (let ((k #f)) (call/cc (lambda (c) (set! k c))) 1)) (f) (f)";
+    evaler.eval_from(code.to_string()).unwrap();
+    gc::collect();
+    assert_eq!(gc::stats().continuations, 0);
+}
+
+#[test]
+fn test_continuations() {
+    test_val!("(+ 1 (call/cc (lambda (k) (+ 10 (k 1)))))", "2");
+    test_val!("(call-with-current-continuation (lambda (k) 5))", "5");
+    test_val!("(procedure? call/cc)", "#t");
+    test_val!("(call/cc procedure?)", "#t");
+    // an early exit from a loop
+    test_val!(
+        "(define (find-first pred lst)
+           (call/cc
+             (lambda (return)
+               (define (walk l)
+                 (if (null? l) #f (begin (if (pred (car l)) (return (car l)) #f) (walk (cdr l)))))
+               (walk lst))))
+         (find-first (lambda (x) (> x 2)) '(1 2 3 4))",
+        "3"
+    );
+    // re-entering a continuation resumes the pending addition each time
+    test_val!(
+        "(define k #f)
+         (define count 0)
+         (define (f)
+           (let ((v (+ 100 (call/cc (lambda (c) (set! k c) 1)))))
+             (set! count (+ count 1))
+             (if (< count 3) (k count) (cons v count))))
+         (f)",
+        "(102 . 3)"
+    );
+    // a continuation captured by an earlier top-level form finishes that
+    // form, then evaluation goes on after the form that invoked it
+    test_val!(
+        "(define k #f)
+         (define r '())
+         (set! r (cons (call/cc (lambda (c) (set! k c) 1)) r))
+         (if (< (car r) 3) (k (+ (car r) 1)) #f)
+         r",
+        "(2 1)"
+    );
+    test_val!("(apply + 1 2 '(3 4))", "10");
+    test_val!("(apply call/cc (cons (lambda (k) (k 7)) '()))", "7");
+    // deep recursion does not use up the Rust stack
+    test_val!(
+        "(define (sum n) (if (= n 0) 0 (+ n (sum (- n 1)))))
+         (sum 30000)",
+        "450015000"
+    );
+}
+
+#[test]
+fn test_dynamic_wind() {
+    let note = "(define trace '()) (define (note x) (set! trace (cons x trace)))";
+
+    test_val!(
+        format!(
+            "{} {}",
+            note,
+            "(define v (dynamic-wind
+                         (lambda () (note 'before))
+                         (lambda () (note 'during) 'result)
+                         (lambda () (note 'after))))
+             (cons v trace)"
+        ),
+        "(result after during before)"
+    );
+    // escaping runs the after thunk
+    test_val!(
+        format!(
+            "{} {}",
+            note,
+            "(define v (call/cc
+                         (lambda (k)
+                           (dynamic-wind
+                             (lambda () (note 'in))
+                             (lambda () (k 'escaped) (note 'unreached))
+                             (lambda () (note 'out))))))
+             (cons v trace)"
+        ),
+        "(escaped out in)"
+    );
+    // re-entering runs the before thunk again
+    test_val!(
+        format!(
+            "{} {}",
+            note,
+            "(define k #f)
+             (define count 0)
+             (dynamic-wind
+               (lambda () (note 'in))
+               (lambda () (call/cc (lambda (c) (set! k c))) (note count))
+               (lambda () (note 'out)))
+             (if (= count 0) (begin (set! count 1) (k #f)) #f)
+             trace"
+        ),
+        "(out 1 in out 0 in)"
+    );
+    // jumping between two extents leaves the inner ones innermost first
+    test_val!(
+        format!(
+            "{} {}",
+            note,
+            "(define (wind name thunk)
+               (dynamic-wind (lambda () (note (cons 'in name)))
+                             thunk
+                             (lambda () (note (cons 'out name)))))
+             (call/cc
+               (lambda (k)
+                 (wind 'a (lambda () (wind 'b (lambda () (k 0)))))))
+             trace"
+        ),
+        "((out . a) (out . b) (in . b) (in . a))"
+    );
+    // an error abandons the extent and leaves the evaluator usable
+    let mut evaler = Evaler::new();
+    let res = evaler
+        .eval_from("(dynamic-wind (lambda () 1) (lambda () (car 1)) (lambda () 2))".to_string());
+    assert_eq!(res, Err(SchemeError::WrongType("pair", Expr::Int(1))));
+    let res =
+        evaler.eval_from("(dynamic-wind (lambda () 1) (lambda () 2) (lambda () 3))".to_string());
+    assert_eq!(res, Ok(Expr::Int(2)));
+}
+
+#[test]
+fn test_quasiquote() {
+    test_val!("`(a b)", "(a b)");
+    test_val!("`(1 ,(+ 1 1) ,@'(3 4))", "(1 2 3 4)");
+    test_val!("`(,@'() . x)", "x");
+    test_val!("(define x 5) `(a . ,x)", "(a . 5)");
+    test_val!("(define l '(2 3)) `(1 ,@l 4 ,@l)", "(1 2 3 4 2 3)");
+    test_val!("(define l '(2 3)) `#(1 ,@l ,(car l))", "#(1 2 3 2)");
+    test_val!("`(1 #(a ,(+ 1 1)))", "(1 #(a 2))");
+    // only the innermost unquote of a nested quasiquote is evaluated
+    test_val!(
+        "`(1 `(2 ,(3 ,(+ 1 3))))",
+        "(1 (quasiquote (2 (unquote (3 4)))))"
+    );
+    test_val!(
+        "`(1 `(,@x ,@,@'(y z)))",
+        "(1 (quasiquote ((unquote-splicing x) (unquote-splicing y z))))"
+    );
+    // unquoted expressions are expanded with the surrounding scope
+    test_val!("(let ((if 1)) `(if ,if))", "(if 1)");
+    test_val!(
+        "(define-syntax make-pair
+           (syntax-rules () ((_ a b) `(first ,a second ,b))))
+         (make-pair 1 (+ 1 1))",
+        "(first 1 second 2)"
+    );
+
+    let mut evaler = Evaler::new();
+    assert_eq!(
+        evaler.eval_from("`(1 ,@2)".to_string()),
+        Err(SchemeError::WrongType("list", Expr::Int(2)))
+    );
+    assert!(evaler.eval_from("`,@'(1)".to_string()).is_err());
+    assert!(evaler.eval_from(",1".to_string()).is_err());
+    assert!(evaler.eval_from("(quasiquote 1 2)".to_string()).is_err());
+}
+
+#[test]
+fn test_evaler_errors() {
+    test_err!(
+        "undefined-var",
+        SchemeError::UnboundVariable(Expr::new_sym("undefined-var"))
+    );
+    test_err!(
+        "(+ 1 (car 2))",
+        SchemeError::WrongType("pair", Expr::Int(2))
+    );
+    test_err!(
+        "(+ 1 #t)",
+        SchemeError::WrongType("number", Expr::Bool(true))
+    );
+    test_err!("(1 2)", SchemeError::WrongType("procedure", Expr::Int(1)));
+    test_err!(
+        "(define (f x) x) (f 1 2)",
+        SchemeError::ArityMismatch(Expr::new_sym("f"), Arity::Exactly(1), 2)
+    );
+    test_err!(
+        "(car '(1) '(2))",
+        SchemeError::ArityMismatch(Expr::new_sym("car"), Arity::Exactly(1), 2)
+    );
+    // errors name a renamed local as it is written
+    test_err!(
+        "((lambda (f) ((lambda (f) (f 1 2)) f)) (lambda (x) x))",
+        SchemeError::ArityMismatch(Expr::new_sym("f"), Arity::Exactly(1), 2)
+    );
+    // a literal bound at the use site no longer matches
+    let mut evaler = Evaler::new();
+    let res = evaler.eval_from(
+        "(define-syntax my-if (syntax-rules (else) ((_ c t else e) (if c t e))))
+         (let ((else 1)) (my-if #f 1 else 2))"
+            .to_string(),
+    );
+    match res {
+        Err(SchemeError::BadSyntax(exp)) => assert_eq!(exp.to_string(), "(my-if #f 1 else 2)"),
+        res => panic!("unexpected {:?}", res),
+    }
+
+    let mut evaler = Evaler::new();
+    // integer division fails on an inexact zero as well, unlike `/`
+    for &(code, call) in [
+        ("(/ 10 (- 2 2))", "(/ 10 0)"),
+        ("(quotient 7 0.0)", "(quotient 7 0.0)"),
+        ("(remainder 7.0 -0.0)", "(remainder 7.0 -0.0)"),
+        ("(modulo 7 0)", "(modulo 7 0)"),
+    ]
+    .iter()
+    {
+        match evaler.eval_from(code.to_string()) {
+            Err(SchemeError::DivisionByZero(exp)) => assert_eq!(exp.to_string(), call),
+            res => panic!("unexpected {:?} from {}", res, code),
+        }
+    }
+    // results beyond the numbers this implementation has are errors, not
+    // +inf.0 or +nan.0
+    for &(code, call) in [
+        ("(expt 2 (expt 10 10))", "(expt 2 10000000000)"),
+        ("(expt 1/2 (- (expt 10 10)))", "(expt 1/2 -10000000000)"),
+        ("(expt -8 1/3)", "(expt -8 1/3)"),
+        ("(sqrt -4)", "(sqrt -4)"),
+        ("(sqrt -2.0)", "(sqrt -2.0)"),
+    ]
+    .iter()
+    {
+        match evaler.eval_from(code.to_string()) {
+            Err(SchemeError::Unrepresentable(exp)) => assert_eq!(exp.to_string(), call),
+            res => panic!("unexpected {:?} from {}", res, code),
+        }
+    }
+    // the evaluator is still usable after a failure
+    evaler.eval_from("(define x 41)".to_string()).unwrap();
+    assert!(evaler.eval_from("(+ x y)".to_string()).is_err());
+    let res = evaler.eval_from("(+ x 1)".to_string()).unwrap();
+    assert_eq!(res.as_int().unwrap(), 42);
+}
+
+#[test]
+fn test_error_locations() {
+    let mut evaler = Evaler::new();
+    let code = "(define x 1)
+(define (f y)
+  (+ y (car y)))
+(define-syntax m (syntax-rules () ((_ a) a)))
+";
+    evaler.eval_source("lib.scm", code.to_string()).unwrap();
+
+    macro_rules! test_loc {
+        ($test_str:expr, $expect:expr) => {{
+            let res = evaler.eval_source("lib.scm", $test_str.to_string());
+            assert_eq!(res.unwrap_err().to_string(), $expect);
+        }};
+    }
+    test_loc!("(+ x foo)", "unbound variable foo at lib.scm:1:6");
+    test_loc!("\n(f 5)", "wrong type: expected pair, got 5 at lib.scm:3:8");
+    test_loc!("x\n  (m)", "bad syntax: (m) at lib.scm:2:3");
+    test_loc!("x\n  foo", "unbound variable foo at lib.scm:2:3");
+    test_loc!(
+        "(f 1 2)",
+        "f: wrong number of arguments (expected 1, got 2) at lib.scm:1:1"
+    );
+}
@@ -0,0 +1,1227 @@
+use ast::{join_list, split_list, Expr};
+use error::SchemeError;
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::rc::Rc;
+use symbol::{self, Symbol};
+use syntax_rules::Macro;
+
+/// Keywords the evaluator handles itself, and the auxiliary keywords `else`
+/// and `=>` that only mean something inside them. `#%top` is never produced
+/// by the reader: the expander emits `(#%top name)` to refer to a global
+/// that a local binding at the use site would otherwise shadow.
+const SPECIAL_FORMS: [Symbol; 28] = [
+    symbol::QUOTE,
+    symbol::QUASIQUOTE,
+    symbol::UNQUOTE,
+    symbol::UNQUOTE_SPLICING,
+    symbol::DEFINE,
+    symbol::SET,
+    symbol::LAMBDA,
+    symbol::CASE_LAMBDA,
+    symbol::IF,
+    symbol::BEGIN,
+    symbol::LET,
+    symbol::LET_STAR,
+    symbol::LETREC,
+    symbol::LETREC_STAR,
+    symbol::COND,
+    symbol::CASE,
+    symbol::WHEN,
+    symbol::UNLESS,
+    symbol::DO,
+    symbol::ELSE,
+    symbol::ARROW,
+    symbol::AND,
+    symbol::OR,
+    symbol::DEFINE_SYNTAX,
+    symbol::LET_SYNTAX,
+    symbol::LETREC_SYNTAX,
+    symbol::SYNTAX_RULES,
+    symbol::TOP,
+];
+
+/// What an identifier is bound to in a `Scope`.
+#[derive(Clone)]
+enum Binding {
+    /// A local variable, with the name it has in the expanded code.
+    Var(Symbol),
+    Macro(Rc<Macro>),
+}
+
+/// One lexical contour seen by the expander: a procedure or `let` body, or
+/// the keywords of a `let-syntax`. The root scope holds global macros.
+pub struct Scope {
+    table: RefCell<HashMap<Symbol, Binding>>,
+    parent: Option<Rc<Scope>>,
+}
+
+impl Scope {
+    fn new(parent: Option<Rc<Scope>>) -> Rc<Scope> {
+        Rc::new(Scope {
+            table: RefCell::new(HashMap::new()),
+            parent,
+        })
+    }
+
+    fn lookup(&self, name: Symbol) -> Option<Binding> {
+        match self.table.borrow().get(&name) {
+            Some(binding) => Some(binding.clone()),
+            None => match self.parent {
+                Some(ref parent) => parent.lookup(name),
+                None => None,
+            },
+        }
+    }
+
+    fn insert(&self, name: Symbol, binding: Binding) {
+        self.table.borrow_mut().insert(name, binding);
+    }
+}
+
+/// An identifier inserted by a macro template: the identifier it was
+/// copied from, and the scope of the macro that inserted it.
+struct Alias {
+    name: Symbol,
+    scope: Rc<Scope>,
+}
+
+/// The meaning of an identifier at one point in the program.
+enum Denotation {
+    Var(Symbol),
+    Macro(Rc<Macro>),
+    Special(Symbol),
+    Global(Symbol),
+}
+
+impl PartialEq for Denotation {
+    fn eq(&self, o: &Denotation) -> bool {
+        match (self, o) {
+            (Denotation::Var(a), Denotation::Var(b)) => a == b,
+            (Denotation::Macro(a), Denotation::Macro(b)) => Rc::ptr_eq(a, b),
+            (Denotation::Special(a), Denotation::Special(b)) => a == b,
+            (Denotation::Global(a), Denotation::Global(b)) => a == b,
+            _ => false,
+        }
+    }
+}
+
+enum BodyForm {
+    Define(Symbol, Expr),
+    Expr(Expr),
+}
+
+/// Expands macro uses before evaluation.
+///
+/// Hygiene works by renaming. Each identifier a template inserts becomes a
+/// fresh alias such as `tmp#3`, which is resolved in the scope where the
+/// macro was defined. A binder that would shadow a keyword or an enclosing
+/// local is renamed too. The output therefore only contains the core forms
+/// the evaluator knows, and every local name in it is unique along its
+/// scope chain.
+pub struct Expander {
+    root: Rc<Scope>,
+    aliases: HashMap<Symbol, Alias>,
+    /// The names made by `fresh_name` that have not been released.
+    fresh: Vec<Symbol>,
+    /// How many of `fresh` the last `end_form` kept.
+    kept: usize,
+}
+
+fn bad_syntax(exp: &Expr) -> SchemeError {
+    SchemeError::BadSyntax(exp.clone()).at(exp.span())
+}
+
+fn list(items: Vec<Expr>) -> Expr {
+    join_list(items, Expr::Nil)
+}
+
+fn ok_form() -> Expr {
+    list(vec![Expr::Sym(symbol::QUOTE), Expr::new_sym("OK")])
+}
+
+/// The elements of a proper list; anything else is bad syntax in `form`.
+fn list_items(exp: &Expr, form: &Expr) -> Result<Vec<Expr>, SchemeError> {
+    let (items, tail) = split_list(exp)?;
+    if !tail.is_empty() {
+        return Err(bad_syntax(form));
+    }
+    Ok(items)
+}
+
+impl Default for Expander {
+    fn default() -> Expander {
+        Expander::new()
+    }
+}
+
+impl Expander {
+    pub fn new() -> Expander {
+        Expander {
+            root: Scope::new(None),
+            aliases: HashMap::new(),
+            fresh: vec![],
+            kept: 0,
+        }
+    }
+
+    /// Expands one top-level form. Macro definitions are remembered for the
+    /// forms that follow.
+    pub fn expand(&mut self, exp: &Expr) -> Result<Expr, SchemeError> {
+        let root = self.root.clone();
+        match self.head(exp, &root) {
+            Some(Denotation::Macro(mac)) => {
+                let out = self.expand_macro(&mac, exp, &root)?;
+                self.expand(&out)
+            }
+            Some(Denotation::Special(symbol::BEGIN)) => {
+                let mut forms = vec![Expr::Sym(symbol::BEGIN)];
+                for form in list_items(&exp.cdr()?, exp)? {
+                    forms.push(self.expand(&form)?);
+                }
+                Ok(if forms.len() == 1 {
+                    ok_form()
+                } else {
+                    list(forms)
+                })
+            }
+            Some(Denotation::Special(symbol::DEFINE_SYNTAX)) => {
+                self.define_syntax(exp, &root)?;
+                Ok(ok_form())
+            }
+            _ => self.expand_expr(exp, &root),
+        }
+    }
+
+    /// Forgets the aliases and releases the fresh names made so far that
+    /// no global macro can bring back, so that the tables stay the same
+    /// size from one top-level form to the next. The caller must be done
+    /// with the forms expanded so far, which may use those names.
+    pub fn end_form(&mut self) {
+        if self.fresh.len() == self.kept {
+            return;
+        }
+        let live = self.live_names();
+        self.aliases.retain(|name, _| live.contains(name));
+        self.fresh.retain(|&name| {
+            if !live.contains(&name) {
+                name.release();
+            }
+            live.contains(&name)
+        });
+        self.kept = self.fresh.len();
+    }
+
+    /// The names the global macros refer to: those in their rules and in
+    /// the scopes they were defined in, and what those aliases stand for.
+    fn live_names(&self) -> HashSet<Symbol> {
+        let mut live = HashSet::new();
+        let mut seen = HashSet::new();
+        let mut scopes = vec![self.root.clone()];
+        while let Some(scope) = scopes.pop() {
+            if !seen.insert(Rc::as_ptr(&scope)) {
+                continue;
+            }
+            let mut names = vec![];
+            for (&name, binding) in scope.table.borrow().iter() {
+                names.push(name);
+                match *binding {
+                    Binding::Var(var) => names.push(var),
+                    Binding::Macro(ref mac) => {
+                        mac.each_symbol(&mut |name| names.push(name));
+                        scopes.push(mac.scope().clone());
+                    }
+                }
+            }
+            scopes.extend(scope.parent.clone());
+            while let Some(name) = names.pop() {
+                if live.insert(name) {
+                    if let Some(alias) = self.aliases.get(&name) {
+                        names.push(alias.name);
+                        scopes.push(alias.scope.clone());
+                    }
+                }
+            }
+        }
+        live
+    }
+
+    /// The name an alias was ultimately copied from.
+    pub fn base_name(&self, name: Symbol) -> Symbol {
+        let mut name = name;
+        while let Some(alias) = self.aliases.get(&name) {
+            name = alias.name;
+        }
+        name
+    }
+
+    /// Creates a fresh alias for `name` as inserted by a macro defined in
+    /// `scope`.
+    pub fn make_alias(&mut self, name: Symbol, scope: Rc<Scope>) -> Symbol {
+        let alias = self.fresh_name(name);
+        self.aliases.insert(alias, Alias { name, scope });
+        alias
+    }
+
+    /// Whether identifier `a` in `a_scope` means the same as `b` in
+    /// `b_scope`; this is how `syntax-rules` literals are matched.
+    pub fn free_identifier_eq(
+        &self,
+        a: &Expr,
+        a_scope: &Rc<Scope>,
+        b: &Expr,
+        b_scope: &Rc<Scope>,
+    ) -> bool {
+        match (a, b) {
+            (&Expr::Sym(a), &Expr::Sym(b)) => self.resolve(a, a_scope) == self.resolve(b, b_scope),
+            _ => false,
+        }
+    }
+
+    /// A new name for a renamed binder. It is not interned, so no
+    /// identifier in the program can capture it, whatever its spelling.
+    fn fresh_name(&mut self, name: Symbol) -> Symbol {
+        let name = Symbol::fresh(self.base_name(name));
+        self.fresh.push(name);
+        name
+    }
+
+    fn resolve(&self, name: Symbol, scope: &Rc<Scope>) -> Denotation {
+        match scope.lookup(name) {
+            Some(Binding::Var(var)) => return Denotation::Var(var),
+            Some(Binding::Macro(mac)) => return Denotation::Macro(mac),
+            None => {}
+        }
+        if let Some(alias) = self.aliases.get(&name) {
+            return self.resolve(alias.name, &alias.scope);
+        }
+        if SPECIAL_FORMS.contains(&name) {
+            Denotation::Special(name)
+        } else {
+            Denotation::Global(name)
+        }
+    }
+
+    /// What the operator of `exp` means, if `exp` is a form headed by an
+    /// identifier.
+    fn head(&self, exp: &Expr, scope: &Rc<Scope>) -> Option<Denotation> {
+        exp.head().map(|name| self.resolve(name, scope))
+    }
+
+    fn is_root(&self, scope: &Rc<Scope>) -> bool {
+        Rc::ptr_eq(scope, &self.root)
+    }
+
+    /// True if a local binding in `scope` is emitted under the plain name
+    /// `name`, hiding the global of that name at run time.
+    fn shadows_global(&self, name: Symbol, scope: &Rc<Scope>) -> bool {
+        let mut scope = Some(scope);
+        while let Some(s) = scope {
+            if let Some(&Binding::Var(var)) = s.table.borrow().get(&name) {
+                if var == name {
+                    return true;
+                }
+            }
+            scope = s.parent.as_ref();
+        }
+        false
+    }
+
+    fn global_ref(&self, name: Symbol, scope: &Rc<Scope>) -> Expr {
+        if self.shadows_global(name, scope) {
+            list(vec![Expr::Sym(symbol::TOP), Expr::Sym(name)])
+        } else {
+            Expr::Sym(name)
+        }
+    }
+
+    fn expand_ref(&self, id: &Expr, scope: &Rc<Scope>) -> Result<Expr, SchemeError> {
+        match self.resolve(id.as_sym()?, scope) {
+            Denotation::Var(var) => Ok(Expr::Sym(var)),
+            Denotation::Macro(_) => Err(bad_syntax(id)),
+            Denotation::Special(name) | Denotation::Global(name) => {
+                Ok(self.global_ref(name, scope))
+            }
+        }
+    }
+
+    /// Binds the identifier `id` as a variable in `scope` and returns the
+    /// name it gets in the expanded code.
+    fn bind_var(&mut self, id: &Expr, scope: &Rc<Scope>) -> Result<Symbol, SchemeError> {
+        let name = match *id {
+            Expr::Sym(name) => name,
+            _ => return Err(bad_syntax(id)),
+        };
+        if let Some(&Binding::Var(var)) = scope.table.borrow().get(&name) {
+            return Ok(var);
+        }
+        let var = if self.aliases.contains_key(&name) {
+            name
+        } else {
+            match self.resolve(name, scope) {
+                Denotation::Global(_) => name,
+                _ => self.fresh_name(name),
+            }
+        };
+        scope.insert(name, Binding::Var(var));
+        Ok(var)
+    }
+
+    /// Replaces aliases in quoted data by the names they stand for.
+    fn strip(&self, exp: &Expr) -> Expr {
+        match *exp {
+            Expr::Sym(name) => Expr::Sym(self.base_name(name)),
+            Expr::Pair(ref cell, _) => {
+                let [car, cdr] = cell.borrow().clone();
+                Expr::new_pair(self.strip(&car), self.strip(&cdr))
+            }
+            Expr::Vector(ref vec) => {
+                let items = vec.borrow().iter().map(|exp| self.strip(exp)).collect();
+                Expr::new_vector(items)
+            }
+            _ => exp.clone(),
+        }
+    }
+
+    fn make_macro(&mut self, spec: &Expr, scope: &Rc<Scope>) -> Result<Rc<Macro>, SchemeError> {
+        match self.head(spec, scope) {
+            Some(Denotation::Special(symbol::SYNTAX_RULES)) => {
+                Ok(Rc::new(Macro::parse(spec, scope.clone())?))
+            }
+            _ => Err(bad_syntax(spec)),
+        }
+    }
+
+    fn define_syntax(&mut self, exp: &Expr, scope: &Rc<Scope>) -> Result<(), SchemeError> {
+        let items = list_items(exp, exp)?;
+        if items.len() != 3 || !items[1].is_sym() {
+            return Err(bad_syntax(exp));
+        }
+        let mac = self.make_macro(&items[2], scope)?;
+        let mut name = items[1].as_sym()?;
+        if self.is_root(scope) {
+            name = self.base_name(name);
+        }
+        scope.insert(name, Binding::Macro(mac));
+        Ok(())
+    }
+
+    /// Splits `(define name value)` and `(define (name . params) body ...)`
+    /// into the name and the value expression.
+    fn parse_define(&self, exp: &Expr) -> Result<(Expr, Expr), SchemeError> {
+        let items = list_items(exp, exp)?;
+        if items.len() < 3 {
+            return Err(bad_syntax(exp));
+        }
+        if items[1].is_sym() {
+            if items.len() != 3 {
+                return Err(bad_syntax(exp));
+            }
+            return Ok((items[1].clone(), items[2].clone()));
+        }
+        match items[1] {
+            Expr::Pair(ref cell, _) => {
+                let [name, params] = cell.borrow().clone();
+                Ok((name, params.make_lambda(exp.c("dd")?)))
+            }
+            _ => Err(bad_syntax(exp)),
+        }
+    }
+
+    /// Rewrites one use of `mac`. The result takes over the span of the
+    /// use, so errors in the expansion point at the macro call.
+    fn expand_macro(
+        &mut self,
+        mac: &Rc<Macro>,
+        exp: &Expr,
+        scope: &Rc<Scope>,
+    ) -> Result<Expr, SchemeError> {
+        match mac.expand(self, exp, scope) {
+            Ok(out) => Ok(out.with_span(exp.span())),
+            Err(err) => Err(err.at(exp.span())),
+        }
+    }
+
+    fn expand_expr(&mut self, exp: &Expr, scope: &Rc<Scope>) -> Result<Expr, SchemeError> {
+        let res = match *exp {
+            Expr::Sym(_) => self.expand_ref(exp, scope)?,
+            Expr::Pair(..) => match self.head(exp, scope) {
+                Some(Denotation::Special(kw)) => self.expand_special(kw, exp, scope)?,
+                Some(Denotation::Macro(mac)) => {
+                    let out = self.expand_macro(&mac, exp, scope)?;
+                    self.expand_expr(&out, scope)?
+                }
+                _ => self.expand_list(exp, exp, scope)?,
+            },
+            _ => exp.clone(),
+        };
+        Ok(res.with_span(exp.span()))
+    }
+
+    /// Expands every element of the proper list `exps`, part of `form`.
+    fn expand_list(
+        &mut self,
+        exps: &Expr,
+        form: &Expr,
+        scope: &Rc<Scope>,
+    ) -> Result<Expr, SchemeError> {
+        // keep the span of every cell: it locates the element it starts
+        let mut res = vec![];
+        let mut rest = exps.clone();
+        while let Expr::Pair(ref cell, ref loc) = rest.clone() {
+            let [car, cdr] = cell.borrow().clone();
+            res.push((self.expand_expr(&car, scope)?, loc.0.clone()));
+            rest = cdr;
+        }
+        if !rest.is_empty() {
+            return Err(bad_syntax(form));
+        }
+        Ok(res.into_iter().rev().fold(Expr::Nil, |acc, (exp, span)| {
+            Expr::new_pair(exp, acc).with_span(span)
+        }))
+    }
+
+    fn expand_special(
+        &mut self,
+        kw: Symbol,
+        exp: &Expr,
+        scope: &Rc<Scope>,
+    ) -> Result<Expr, SchemeError> {
+        let items = list_items(exp, exp)?;
+        let args = exp.cdr()?;
+        let keyword = Expr::Sym(kw);
+        match kw {
+            symbol::QUOTE => {
+                if items.len() != 2 {
+                    return Err(bad_syntax(exp));
+                }
+                Ok(list(vec![keyword, self.strip(&items[1])]))
+            }
+            symbol::QUASIQUOTE => {
+                if items.len() != 2 {
+                    return Err(bad_syntax(exp));
+                }
+                Ok(list(vec![keyword, self.expand_quasi(&items[1], 0, scope)?]))
+            }
+            symbol::IF => {
+                if items.len() < 3 {
+                    return Err(bad_syntax(exp));
+                }
+                Ok(Expr::new_pair(
+                    keyword,
+                    self.expand_list(&args, exp, scope)?,
+                ))
+            }
+            symbol::BEGIN => {
+                if items.len() < 2 {
+                    return Err(bad_syntax(exp));
+                }
+                Ok(Expr::new_pair(
+                    keyword,
+                    self.expand_list(&args, exp, scope)?,
+                ))
+            }
+            symbol::AND | symbol::OR => Ok(Expr::new_pair(
+                keyword,
+                self.expand_list(&args, exp, scope)?,
+            )),
+            symbol::COND => {
+                let mut clauses = vec![keyword];
+                for (i, clause) in items[1..].iter().enumerate() {
+                    let parts = list_items(clause, exp)?;
+                    if parts.is_empty() {
+                        return Err(bad_syntax(exp));
+                    }
+                    let test = if self.is_keyword(&parts[0], symbol::ELSE, scope) {
+                        if parts.len() < 2 || i + 2 < items.len() {
+                            return Err(bad_syntax(exp));
+                        }
+                        Expr::Sym(symbol::ELSE)
+                    } else {
+                        self.expand_expr(&parts[0], scope)?
+                    };
+                    let body = self.expand_clause_body(&parts[1..], exp, scope)?;
+                    clauses.push(join_list(vec![test], body));
+                }
+                Ok(list(clauses))
+            }
+            symbol::CASE => {
+                if items.len() < 2 {
+                    return Err(bad_syntax(exp));
+                }
+                let mut res = vec![keyword, self.expand_expr(&items[1], scope)?];
+                for (i, clause) in items[2..].iter().enumerate() {
+                    let parts = list_items(clause, exp)?;
+                    if parts.len() < 2 {
+                        return Err(bad_syntax(exp));
+                    }
+                    let datums = if self.is_keyword(&parts[0], symbol::ELSE, scope) {
+                        if i + 3 < items.len() {
+                            return Err(bad_syntax(exp));
+                        }
+                        Expr::Sym(symbol::ELSE)
+                    } else {
+                        list(list_items(&parts[0], exp)?)
+                    };
+                    let body = self.expand_clause_body(&parts[1..], exp, scope)?;
+                    res.push(join_list(vec![self.strip(&datums)], body));
+                }
+                Ok(list(res))
+            }
+            symbol::WHEN | symbol::UNLESS => {
+                if items.len() < 3 {
+                    return Err(bad_syntax(exp));
+                }
+                let test = self.expand_expr(&items[1], scope)?;
+                let body = Expr::new_pair(
+                    Expr::Sym(symbol::BEGIN),
+                    self.expand_list(&exp.c("dd")?, exp, scope)?,
+                );
+                let (then, alt) = if kw == symbol::WHEN {
+                    (body, ok_form())
+                } else {
+                    (ok_form(), body)
+                };
+                Ok(list(vec![Expr::Sym(symbol::IF), test, then, alt]))
+            }
+            symbol::DO => self.expand_do(exp, &items, scope),
+            symbol::SET => {
+                if items.len() != 3 || !items[1].is_sym() {
+                    return Err(bad_syntax(exp));
+                }
+                let var = self.expand_ref(&items[1], scope)?;
+                let val = self.expand_expr(&items[2], scope)?;
+                Ok(list(vec![keyword, var, val]))
+            }
+            symbol::DEFINE => {
+                let (name, val) = self.parse_define(exp)?;
+                let var = if self.is_root(scope) {
+                    let name = self.base_name(name.as_sym()?);
+                    // a global definition replaces any macro of that name
+                    self.root.table.borrow_mut().remove(&name);
+                    name
+                } else {
+                    self.bind_var(&name, scope)?
+                };
+                let val = self.expand_expr(&val, scope)?;
+                Ok(list(vec![keyword, Expr::Sym(var), val]))
+            }
+            symbol::LAMBDA => {
+                if items.len() < 3 {
+                    return Err(bad_syntax(exp));
+                }
+                let clause = self.expand_clause(&args, exp, scope)?;
+                Ok(Expr::new_pair(keyword, clause))
+            }
+            symbol::CASE_LAMBDA => {
+                if items.len() < 2 {
+                    return Err(bad_syntax(exp));
+                }
+                let mut clauses = vec![keyword];
+                for clause in &items[1..] {
+                    clauses.push(self.expand_clause(clause, exp, scope)?);
+                }
+                Ok(list(clauses))
+            }
+            symbol::LET => self.expand_let(exp, &items, scope),
+            symbol::LET_STAR => {
+                if items.len() < 3 {
+                    return Err(bad_syntax(exp));
+                }
+                let bindings = self.let_bindings(&items[1], exp)?;
+                self.expand_let_star(&bindings, &exp.c("dd")?, scope)
+            }
+            symbol::LETREC | symbol::LETREC_STAR => self.expand_letrec(exp, &items, scope),
+            symbol::LET_SYNTAX | symbol::LETREC_SYNTAX => {
+                if items.len() < 3 {
+                    return Err(bad_syntax(exp));
+                }
+                let inner = Scope::new(Some(scope.clone()));
+                let def_scope = if kw == symbol::LETREC_SYNTAX {
+                    &inner
+                } else {
+                    scope
+                };
+                for binding in list_items(&items[1], exp)? {
+                    let parts = list_items(&binding, exp)?;
+                    if parts.len() != 2 || !parts[0].is_sym() {
+                        return Err(bad_syntax(exp));
+                    }
+                    let mac = self.make_macro(&parts[1], def_scope)?;
+                    inner.insert(parts[0].as_sym()?, Binding::Macro(mac));
+                }
+                let body = self.expand_body(&exp.c("dd")?, &inner)?;
+                Ok(Expr::new_pair(
+                    Expr::Sym(symbol::LET),
+                    Expr::new_pair(Expr::Nil, body),
+                ))
+            }
+            symbol::TOP => Ok(exp.clone()),
+            // define-syntax is only allowed where definitions are, and
+            // syntax-rules only as a transformer
+            _ => Err(bad_syntax(exp)),
+        }
+    }
+
+    /// Expands the code inside the unquotes of a quasiquote template and
+    /// strips the data around it. `depth` counts the quasiquotes nested
+    /// inside the outermost one; only unquotes at depth zero are code.
+    fn expand_quasi(
+        &mut self,
+        tmpl: &Expr,
+        depth: usize,
+        scope: &Rc<Scope>,
+    ) -> Result<Expr, SchemeError> {
+        match self.quasi_keyword(tmpl, scope)? {
+            // splicing is only meaningful as an element of a list
+            Some(symbol::UNQUOTE_SPLICING) if depth == 0 => return Err(bad_syntax(tmpl)),
+            Some(symbol::UNQUOTE) if depth == 0 => {
+                let arg = self.expand_expr(&tmpl.c("da")?, scope)?;
+                return Ok(list(vec![Expr::Sym(symbol::UNQUOTE), arg]));
+            }
+            Some(kw) => {
+                // the argument is walked as a list so that it may splice
+                let depth = if kw == symbol::QUASIQUOTE {
+                    depth + 1
+                } else {
+                    depth - 1
+                };
+                let rest = self.expand_quasi(&tmpl.cdr()?, depth, scope)?;
+                return Ok(Expr::new_pair(Expr::Sym(kw), rest));
+            }
+            None => {}
+        }
+        match *tmpl {
+            Expr::Pair(ref cell, _) => {
+                let [car, cdr] = cell.borrow().clone();
+                let head = match self.quasi_keyword(&car, scope)? {
+                    Some(symbol::UNQUOTE_SPLICING) if depth == 0 => {
+                        let arg = self.expand_expr(&car.c("da")?, scope)?;
+                        list(vec![Expr::Sym(symbol::UNQUOTE_SPLICING), arg])
+                    }
+                    _ => self.expand_quasi(&car, depth, scope)?,
+                };
+                let rest = self.expand_quasi(&cdr, depth, scope)?;
+                Ok(Expr::new_pair(head, rest).with_span(tmpl.span()))
+            }
+            Expr::Vector(ref vec) => {
+                let items = join_list(vec.borrow().clone(), Expr::Nil);
+                let (items, _) = split_list(&self.expand_quasi(&items, depth, scope)?)?;
+                Ok(Expr::new_vector(items))
+            }
+            _ => Ok(self.strip(tmpl)),
+        }
+    }
+
+    /// Which of `quasiquote`, `unquote` and `unquote-splicing` heads the
+    /// template `tmpl`, if any.
+    fn quasi_keyword(&self, tmpl: &Expr, scope: &Rc<Scope>) -> Result<Option<Symbol>, SchemeError> {
+        match self.head(tmpl, scope) {
+            Some(Denotation::Special(kw))
+                if kw == symbol::QUASIQUOTE
+                    || kw == symbol::UNQUOTE
+                    || kw == symbol::UNQUOTE_SPLICING =>
+            {
+                if list_items(tmpl, tmpl)?.len() != 2 {
+                    return Err(bad_syntax(tmpl));
+                }
+                Ok(Some(kw))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// Expands `(let ((var init) ...) body ...)` and the named form
+    /// `(let name ((var init) ...) body ...)`, which becomes
+    /// `((letrec ((name (lambda (var ...) body ...))) name) init ...)`.
+    fn expand_let(
+        &mut self,
+        exp: &Expr,
+        items: &[Expr],
+        scope: &Rc<Scope>,
+    ) -> Result<Expr, SchemeError> {
+        let named = items.len() > 1 && items[1].is_sym();
+        let first = if named { 2 } else { 1 };
+        if items.len() < first + 2 {
+            return Err(bad_syntax(exp));
+        }
+        let bindings = self.let_bindings(&items[first], exp)?;
+        let mut inits = vec![];
+        for (_, init) in &bindings {
+            inits.push(self.expand_expr(init, scope)?);
+        }
+        let body = list(items[first + 1..].to_vec());
+        let inner = Scope::new(Some(scope.clone()));
+        if named {
+            let name = Expr::Sym(self.bind_var(&items[1], &inner)?);
+            let params = list(bindings.into_iter().map(|(var, _)| var).collect());
+            let lambda = self.expand_clause(&Expr::new_pair(params, body), exp, &inner)?;
+            let define = list(vec![
+                Expr::Sym(symbol::DEFINE),
+                name.clone(),
+                Expr::new_pair(Expr::Sym(symbol::LAMBDA), lambda),
+            ]);
+            let letrec = list(vec![Expr::Sym(symbol::LET), Expr::Nil, define, name]);
+            return Ok(Expr::new_pair(letrec, list(inits)));
+        }
+        let mut res = vec![];
+        for ((var, _), init) in bindings.iter().zip(inits) {
+            res.push(list(vec![Expr::Sym(self.bind_var(var, &inner)?), init]));
+        }
+        let body = self.expand_body(&body, &inner)?;
+        Ok(join_list(vec![Expr::Sym(symbol::LET), list(res)], body))
+    }
+
+    /// Expands `(let* ((var init) ...) body ...)` into nested `let`s, each
+    /// binding one variable in the scope of those before it.
+    fn expand_let_star(
+        &mut self,
+        bindings: &[(Expr, Expr)],
+        body: &Expr,
+        scope: &Rc<Scope>,
+    ) -> Result<Expr, SchemeError> {
+        let inner = Scope::new(Some(scope.clone()));
+        let (first, rest) = match bindings.split_first() {
+            Some(split) => split,
+            None => {
+                let body = self.expand_body(body, &inner)?;
+                return Ok(join_list(vec![Expr::Sym(symbol::LET), Expr::Nil], body));
+            }
+        };
+        let init = self.expand_expr(&first.1, scope)?;
+        let var = self.bind_var(&first.0, &inner)?;
+        let body = if rest.is_empty() {
+            self.expand_body(body, &inner)?
+        } else {
+            list(vec![self.expand_let_star(rest, body, &inner)?])
+        };
+        let binding = list(vec![Expr::Sym(var), init]);
+        Ok(join_list(
+            vec![Expr::Sym(symbol::LET), list(vec![binding])],
+            body,
+        ))
+    }
+
+    /// Expands `(letrec ((var init) ...) body ...)` and `letrec*` into
+    /// `(let () (define var init) ... body ...)`. The compiler gives the
+    /// variables defined in a body their slots before any of it runs, so
+    /// the inits see each other, and they are evaluated and assigned from
+    /// left to right, which `letrec*` requires and `letrec` allows.
+    fn expand_letrec(
+        &mut self,
+        exp: &Expr,
+        items: &[Expr],
+        scope: &Rc<Scope>,
+    ) -> Result<Expr, SchemeError> {
+        if items.len() < 3 {
+            return Err(bad_syntax(exp));
+        }
+        let bindings = self.let_bindings(&items[1], exp)?;
+        let inner = Scope::new(Some(scope.clone()));
+        let mut vars = vec![];
+        for (var, _) in &bindings {
+            vars.push(self.bind_var(var, &inner)?);
+        }
+        let mut res = vec![Expr::Sym(symbol::LET), Expr::Nil];
+        for (var, (_, init)) in vars.into_iter().zip(&bindings) {
+            let init = self.expand_expr(init, &inner)?;
+            res.push(list(vec![Expr::Sym(symbol::DEFINE), Expr::Sym(var), init]));
+        }
+        // the body is a scope of its own, whose definitions may shadow the
+        // variables; they are renamed apart, so they can share the frame
+        let body = list(items[2..].to_vec());
+        let body = self.expand_body(&body, &Scope::new(Some(inner)))?;
+        Ok(join_list(res, body))
+    }
+
+    /// The `(var init)` pairs of the bindings of a `let` form `exp`.
+    fn let_bindings(&self, bindings: &Expr, exp: &Expr) -> Result<Vec<(Expr, Expr)>, SchemeError> {
+        let mut res = vec![];
+        for binding in list_items(bindings, exp)? {
+            let parts = list_items(&binding, exp)?;
+            if parts.len() != 2 || !parts[0].is_sym() {
+                return Err(bad_syntax(exp));
+            }
+            res.push((parts[0].clone(), parts[1].clone()));
+        }
+        Ok(res)
+    }
+
+    /// Expands `(do ((var init step) ...) (test res ...) command ...)` into
+    /// a loop like a named `let`, whose name no code in the form can see.
+    fn expand_do(
+        &mut self,
+        exp: &Expr,
+        items: &[Expr],
+        scope: &Rc<Scope>,
+    ) -> Result<Expr, SchemeError> {
+        if items.len() < 3 {
+            return Err(bad_syntax(exp));
+        }
+        let mut specs = vec![];
+        let mut inits = vec![];
+        for spec in list_items(&items[1], exp)? {
+            let parts = list_items(&spec, exp)?;
+            if parts.len() < 2 || parts.len() > 3 || !parts[0].is_sym() {
+                return Err(bad_syntax(exp));
+            }
+            inits.push(self.expand_expr(&parts[1], scope)?);
+            specs.push(parts);
+        }
+        let inner = Scope::new(Some(scope.clone()));
+        let mut params = vec![];
+        for parts in &specs {
+            params.push(Expr::Sym(self.bind_var(&parts[0], &inner)?));
+        }
+        let exit = list_items(&items[2], exp)?;
+        if exit.is_empty() {
+            return Err(bad_syntax(exp));
+        }
+        let test = self.expand_expr(&exit[0], &inner)?;
+        let res = if exit.len() == 1 {
+            ok_form()
+        } else {
+            let exps = self.expand_list(&list(exit[1..].to_vec()), exp, &inner)?;
+            Expr::new_pair(Expr::Sym(symbol::BEGIN), exps)
+        };
+        let name = Expr::Sym(self.fresh_name(Symbol::intern("do-loop")));
+        let mut steps = vec![name.clone()];
+        for (parts, param) in specs.iter().zip(&params) {
+            steps.push(match parts.get(2) {
+                Some(step) => self.expand_expr(step, &inner)?,
+                None => param.clone(),
+            });
+        }
+        let mut commands = vec![Expr::Sym(symbol::BEGIN)];
+        for command in &items[3..] {
+            commands.push(self.expand_expr(command, &inner)?);
+        }
+        commands.push(list(steps));
+        let body = list(vec![Expr::Sym(symbol::IF), test, res, list(commands)]);
+        let lambda = list(vec![Expr::Sym(symbol::LAMBDA), list(params), body]);
+        let define = list(vec![Expr::Sym(symbol::DEFINE), name.clone(), lambda]);
+        let letrec = list(vec![Expr::Sym(symbol::LET), Expr::Nil, define, name]);
+        Ok(Expr::new_pair(letrec, list(inits)))
+    }
+
+    /// Whether `id` is an identifier that means the keyword `kw` in `scope`.
+    fn is_keyword(&self, id: &Expr, kw: Symbol, scope: &Rc<Scope>) -> bool {
+        match *id {
+            Expr::Sym(name) => self.resolve(name, scope) == Denotation::Special(kw),
+            _ => false,
+        }
+    }
+
+    /// Expands what follows the test of a `cond` or `case` clause in
+    /// `form`: a body, or `=> receiver`.
+    fn expand_clause_body(
+        &mut self,
+        body: &[Expr],
+        form: &Expr,
+        scope: &Rc<Scope>,
+    ) -> Result<Expr, SchemeError> {
+        if !body.is_empty() && self.is_keyword(&body[0], symbol::ARROW, scope) {
+            if body.len() != 2 {
+                return Err(bad_syntax(form));
+            }
+            let receiver = self.expand_expr(&body[1], scope)?;
+            return Ok(list(vec![Expr::Sym(symbol::ARROW), receiver]));
+        }
+        self.expand_list(&list(body.to_vec()), form, scope)
+    }
+
+    /// Expands `(params body ...)`, the parameters and body of a lambda or
+    /// of a `case-lambda` clause in `form`.
+    fn expand_clause(
+        &mut self,
+        clause: &Expr,
+        form: &Expr,
+        scope: &Rc<Scope>,
+    ) -> Result<Expr, SchemeError> {
+        if list_items(clause, form)?.len() < 2 {
+            return Err(bad_syntax(form));
+        }
+        let inner = Scope::new(Some(scope.clone()));
+        let params = self.expand_params(&clause.car()?, &inner)?;
+        let body = self.expand_body(&clause.cdr()?, &inner)?;
+        Ok(Expr::new_pair(params, body))
+    }
+
+    fn expand_params(&mut self, params: &Expr, scope: &Rc<Scope>) -> Result<Expr, SchemeError> {
+        match *params {
+            Expr::Nil => Ok(Expr::Nil),
+            Expr::Sym(_) => Ok(Expr::Sym(self.bind_var(params, scope)?)),
+            Expr::Pair(ref cell, _) => {
+                let [param, rest] = cell.borrow().clone();
+                let var = self.bind_var(&param, scope)?;
+                Ok(Expr::new_pair(
+                    Expr::Sym(var),
+                    self.expand_params(&rest, scope)?,
+                ))
+            }
+            _ => Err(bad_syntax(params)),
+        }
+    }
+
+    /// Expands a body in two passes: first the forms are expanded just far
+    /// enough to find the definitions among them, so that every name the
+    /// body defines is in scope before any expression is expanded.
+    fn expand_body(&mut self, body: &Expr, scope: &Rc<Scope>) -> Result<Expr, SchemeError> {
+        let mut queue: VecDeque<Expr> = list_items(body, body)?.into();
+        let mut forms = vec![];
+        while let Some(form) = queue.pop_front() {
+            match self.head(&form, scope) {
+                Some(Denotation::Macro(mac)) => {
+                    let out = self.expand_macro(&mac, &form, scope)?;
+                    queue.push_front(out);
+                }
+                Some(Denotation::Special(symbol::BEGIN)) => {
+                    for exp in list_items(&form.cdr()?, &form)?.into_iter().rev() {
+                        queue.push_front(exp);
+                    }
+                }
+                Some(Denotation::Special(symbol::DEFINE)) => {
+                    let (name, val) = self.parse_define(&form)?;
+                    let var = self.bind_var(&name, scope)?;
+                    forms.push(BodyForm::Define(var, val));
+                }
+                Some(Denotation::Special(symbol::DEFINE_SYNTAX)) => {
+                    self.define_syntax(&form, scope)?
+                }
+                _ => forms.push(BodyForm::Expr(form)),
+            }
+        }
+        if forms.is_empty() {
+            return Err(bad_syntax(body));
+        }
+        let mut res = vec![];
+        for form in forms {
+            res.push(match form {
+                BodyForm::Define(var, val) => list(vec![
+                    Expr::Sym(symbol::DEFINE),
+                    Expr::Sym(var),
+                    self.expand_expr(&val, scope)?,
+                ]),
+                BodyForm::Expr(exp) => self.expand_expr(&exp, scope)?,
+            });
+        }
+        Ok(list(res))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use parser::Parser;
+    use std;
+
+    fn expand_all(ex: &mut Expander, code: &str) -> Result<Vec<String>, SchemeError> {
+        let mut parser = Parser::new_from(std::io::stdin(), false);
+        parser.load(code.to_string());
+        let mut res = vec![];
+        while let Some(exp) = parser.read_exp()? {
+            res.push(ex.expand(&exp)?.to_string());
+        }
+        Ok(res)
+    }
+
+    /// The expansion of the last form in `code`. Renamed binders are
+    /// numbered from 1 in order of appearance, as the symbol table's
+    /// counter is shared with everything else on the thread.
+    fn expand_last(code: &str) -> String {
+        let mut ex = Expander::new();
+        renumber(&expand_all(&mut ex, code).unwrap().pop().unwrap())
+    }
+
+    fn renumber(text: &str) -> String {
+        let mut seen: Vec<String> = vec![];
+        let mut out = String::new();
+        let mut chars = text.chars().peekable();
+        while let Some(c) = chars.next() {
+            out.push(c);
+            if c != '#' {
+                continue;
+            }
+            let mut digits = String::new();
+            while let Some(&d) = chars.peek().filter(|d| d.is_ascii_digit()) {
+                digits.push(d);
+                chars.next();
+            }
+            if digits.is_empty() {
+                continue;
+            }
+            if !seen.contains(&digits) {
+                seen.push(digits.clone());
+            }
+            let n = seen.iter().position(|x| *x == digits).unwrap() + 1;
+            out.push_str(&n.to_string());
+        }
+        out
+    }
+
+    #[test]
+    fn test_expand_core() {
+        assert_eq!(expand_last("(f x 1)"), "(f x 1)");
+        assert_eq!(expand_last("(define (f x) x)"), "(define f (lambda (x) x))");
+        assert_eq!(expand_last("'(a b)"), "(quote (a b))");
+        // a binder that shadows a keyword or an enclosing local is renamed
+        assert_eq!(
+            expand_last("(lambda (if) (if 1))"),
+            "(lambda (if#1) (if#1 1))"
+        );
+        assert_eq!(
+            expand_last("(lambda (x) (lambda (x) x))"),
+            "(lambda (x) (lambda (x#1) x#1))"
+        );
+        assert_eq!(
+            expand_last("(let ((x 1)) (define y x) y)"),
+            "(let ((x 1)) (define y x) y)"
+        );
+        assert_eq!(
+            expand_last("(let loop ((i 0)) (loop i))"),
+            "((let () (define loop (lambda (i) (loop i))) loop) 0)"
+        );
+        assert_eq!(
+            expand_last("(let* ((x 1) (x x)) x)"),
+            "(let ((x 1)) (let ((x#1 x)) x#1))"
+        );
+        assert_eq!(
+            expand_last("(letrec ((f (lambda () (g))) (g f)) (f))"),
+            "(let () (define f (lambda () (g))) (define g f) (f))"
+        );
+        assert_eq!(
+            expand_last("(when (f) 1 2)"),
+            "(if (f) (begin 1 2) (quote OK))"
+        );
+        assert_eq!(
+            expand_last("(case x ((a 1) => f) (else x))"),
+            "(case x ((a 1) => f) (else x))"
+        );
+    }
+
+    #[test]
+    fn test_expand_quasiquote() {
+        // only the unquoted parts are expanded as code
+        assert_eq!(
+            expand_last("(lambda (x) `(x (lambda (x) x) ,(lambda (x) x)))"),
+            "(lambda (x) (quasiquote (x (lambda (x) x) (unquote (lambda (x#1) x#1)))))"
+        );
+        assert_eq!(
+            expand_last("`(1 `(,(a ,(let ((b 1)) b))))"),
+            "(quasiquote (1 (quasiquote ((unquote (a (unquote (let ((b 1)) b))))))))"
+        );
+        assert_eq!(
+            expand_last("`#(1 ,@(f x))"),
+            "(quasiquote #(1 (unquote-splicing (f x))))"
+        );
+    }
+
+    #[test]
+    fn test_expand_hygiene() {
+        let swap = "(define-syntax swap!
+                      (syntax-rules ()
+                        ((_ a b) (let ((tmp a)) (set! a b) (set! b tmp)))))";
+        assert_eq!(
+            expand_last(&format!("{} (swap! tmp other)", swap)),
+            "(let ((tmp#1 tmp)) (set! tmp other) (set! other tmp#1))"
+        );
+
+        // a template's free identifiers keep referring to the globals even
+        // when the use site binds the same names
+        let first = "(define-syntax first (syntax-rules () ((_ x) (car x))))";
+        assert_eq!(
+            expand_last(&format!("{} (lambda (car) (first car))", first)),
+            "(lambda (car) ((#%top car) car))"
+        );
+    }
+
+    #[test]
+    fn test_expand_ellipsis() {
+        let my_let = "(define-syntax my-let
+                        (syntax-rules ()
+                          ((_ ((n v) ...) body ...) ((lambda (n ...) body ...) v ...))))";
+        assert_eq!(
+            expand_last(&format!("{} (my-let ((a 1) (b 2)) (f a b))", my_let)),
+            "((lambda (a b) (f a b)) 1 2)"
+        );
+
+        let flat = "(define-syntax flat
+                      (syntax-rules () ((_ (a ...) ...) '(a ... ...))))";
+        assert_eq!(
+            expand_last(&format!("{} (flat (1 2) () (3))", flat)),
+            "(quote (1 2 3))"
+        );
+
+        let tail = "(define-syntax tail
+                      (syntax-rules () ((_ a ... z) 'z) ((_ . rest) 'rest)))";
+        assert_eq!(expand_last(&format!("{} (tail 1 2 3)", tail)), "(quote 3)");
+        assert_eq!(expand_last(&format!("{} (tail)", tail)), "(quote ())");
+
+        let vec = "(define-syntax vec
+                     (syntax-rules () ((_ #(a b ...)) '#(b ... a))))";
+        assert_eq!(
+            expand_last(&format!("{} (vec #(1 2 3))", vec)),
+            "(quote #(2 3 1))"
+        );
+
+        let escape = "(define-syntax dots (syntax-rules () ((_) '(... ...))))";
+        assert_eq!(expand_last(&format!("{} (dots)", escape)), "(quote ...)");
+    }
+
+    #[test]
+    fn test_expand_errors() {
+        let mut ex = Expander::new();
+        let res = expand_all(&mut ex, "(if)");
+        assert_eq!(
+            res,
+            Err(SchemeError::BadSyntax(Expr::new_pair(
+                Expr::new_sym("if"),
+                Expr::Nil
+            )))
+        );
+
+        let res = expand_all(
+            &mut ex,
+            "(define-syntax one (syntax-rules () ((_) 1))) (one 2)",
+        );
+        assert!(res.is_err());
+
+        let res = expand_all(&mut ex, "(lambda (x))");
+        assert!(res.is_err());
+
+        let res = expand_all(&mut ex, "(syntax-rules ())");
+        assert!(res.is_err());
+
+        let res = expand_all(&mut ex, "`(1 ,@'(2) . ,@'(3))");
+        assert!(res.is_err());
+    }
+
+    #[test]
+    fn test_expand_memory_flat() {
+        let mut ex = Expander::new();
+        let defs = "(define-syntax swap!
+                      (syntax-rules () ((_ a b) (let ((tmp a)) (set! a b) (set! b tmp)))))
+                    (define-syntax def-one
+                      (syntax-rules () ((_ name) (define-syntax name
+                                                   (syntax-rules () ((_) (let ((tmp 1)) (if tmp tmp 0))))))))
+                    (def-one one)";
+        expand_all(&mut ex, defs).unwrap();
+        ex.end_form();
+        let form = "(lambda (x y)
+                      (swap! x y)
+                      (lambda (x) (do ((i 0 (+ i 1))) ((= i x) (one)) (swap! x i))))";
+        let mut sizes = vec![];
+        for _ in 0..100 {
+            expand_all(&mut ex, form).unwrap();
+            ex.end_form();
+            sizes.push((ex.aliases.len(), ex.fresh.len(), Symbol::table_len()));
+        }
+        // other tests make symbols on their own threads, not this one
+        assert!(sizes.iter().all(|&size| size == sizes[0]), "{:?}", sizes);
+        // the aliases `one` was defined with are kept
+        assert_eq!(
+            renumber(&expand_all(&mut ex, "(one)").unwrap()[0]),
+            "(let ((tmp#1 1)) (if tmp#1 tmp#1 0))"
+        );
+    }
+}
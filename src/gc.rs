@@ -0,0 +1,492 @@
+use ast::Expr;
+use env::Env;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::mem;
+use std::rc::{Rc, Weak};
+use vm::{Continuation, Frame, Winder};
+
+/// Allocations between two automatic collections, at the least.
+const MIN_THRESHOLD: usize = 10_000;
+
+/// An object on the collected heap: the things that can hold references to
+/// other heap objects and so can end up in a cycle. Closures are reached
+/// through the environment they capture.
+enum Obj {
+    Env(Rc<RefCell<Env>>),
+    Pair(Rc<RefCell<[Expr; 2]>>),
+    Vector(Rc<RefCell<Vec<Expr>>>),
+    Cont(Rc<Continuation>),
+    Winder(Rc<Winder>),
+}
+
+enum Tracked {
+    Env(Weak<RefCell<Env>>),
+    Pair(Weak<RefCell<[Expr; 2]>>),
+    Vector(Weak<RefCell<Vec<Expr>>>),
+    Cont(Weak<Continuation>),
+    Winder(Weak<Winder>),
+}
+
+/// What the heap holds right now, and how many objects have been allocated
+/// and collected so far.
+#[derive(Debug, Default, PartialEq)]
+pub struct HeapStats {
+    pub environments: usize,
+    pub pairs: usize,
+    pub vectors: usize,
+    pub continuations: usize,
+    pub winders: usize,
+    pub collections: usize,
+    pub freed: usize,
+    pub allocations: usize,
+}
+
+struct Heap {
+    objects: Vec<Tracked>,
+    allocated: usize,
+    threshold: usize,
+    collections: usize,
+    freed: usize,
+    allocations: usize,
+}
+
+thread_local! {
+    static HEAP: RefCell<Heap> = const {
+        RefCell::new(Heap {
+            objects: Vec::new(),
+            allocated: 0,
+            threshold: MIN_THRESHOLD,
+            collections: 0,
+            freed: 0,
+            allocations: 0,
+        })
+    };
+}
+
+fn track(obj: Tracked) {
+    HEAP.with(|heap| {
+        let mut heap = heap.borrow_mut();
+        heap.objects.push(obj);
+        heap.allocated += 1;
+        heap.allocations += 1;
+    });
+}
+
+pub fn track_env(env: &Rc<RefCell<Env>>) {
+    track(Tracked::Env(Rc::downgrade(env)));
+}
+
+pub fn track_pair(cell: &Rc<RefCell<[Expr; 2]>>) {
+    track(Tracked::Pair(Rc::downgrade(cell)));
+}
+
+pub fn track_vector(vec: &Rc<RefCell<Vec<Expr>>>) {
+    track(Tracked::Vector(Rc::downgrade(vec)));
+}
+
+pub fn track_cont(cont: &Rc<Continuation>) {
+    track(Tracked::Cont(Rc::downgrade(cont)));
+}
+
+pub fn track_winder(winder: &Rc<Winder>) {
+    track(Tracked::Winder(Rc::downgrade(winder)));
+}
+
+pub fn addr<T>(rc: &Rc<T>) -> usize {
+    Rc::as_ptr(rc) as *const () as usize
+}
+
+/// Pushes the heap objects `exp` refers to directly.
+pub fn expr_children(exp: &Expr, out: &mut Vec<usize>) {
+    match *exp {
+        Expr::Pair(ref cell, _) => out.push(addr(cell)),
+        Expr::Vector(ref vec) => out.push(addr(vec)),
+        Expr::Cont(ref cont) => out.push(addr(cont)),
+        Expr::CompProc(_, ref env) => out.push(addr(env)),
+        _ => {}
+    }
+}
+
+impl Tracked {
+    fn upgrade(&self) -> Option<Obj> {
+        match *self {
+            Tracked::Env(ref weak) => weak.upgrade().map(Obj::Env),
+            Tracked::Pair(ref weak) => weak.upgrade().map(Obj::Pair),
+            Tracked::Vector(ref weak) => weak.upgrade().map(Obj::Vector),
+            Tracked::Cont(ref weak) => weak.upgrade().map(Obj::Cont),
+            Tracked::Winder(ref weak) => weak.upgrade().map(Obj::Winder),
+        }
+    }
+}
+
+impl Obj {
+    fn addr(&self) -> usize {
+        match *self {
+            Obj::Env(ref rc) => addr(rc),
+            Obj::Pair(ref rc) => addr(rc),
+            Obj::Vector(ref rc) => addr(rc),
+            Obj::Cont(ref rc) => addr(rc),
+            Obj::Winder(ref rc) => addr(rc),
+        }
+    }
+
+    fn strong_count(&self) -> usize {
+        match *self {
+            Obj::Env(ref rc) => Rc::strong_count(rc),
+            Obj::Pair(ref rc) => Rc::strong_count(rc),
+            Obj::Vector(ref rc) => Rc::strong_count(rc),
+            Obj::Cont(ref rc) => Rc::strong_count(rc),
+            Obj::Winder(ref rc) => Rc::strong_count(rc),
+        }
+    }
+
+    fn downgrade(&self) -> Tracked {
+        match *self {
+            Obj::Env(ref rc) => Tracked::Env(Rc::downgrade(rc)),
+            Obj::Pair(ref rc) => Tracked::Pair(Rc::downgrade(rc)),
+            Obj::Vector(ref rc) => Tracked::Vector(Rc::downgrade(rc)),
+            Obj::Cont(ref rc) => Tracked::Cont(Rc::downgrade(rc)),
+            Obj::Winder(ref rc) => Tracked::Winder(Rc::downgrade(rc)),
+        }
+    }
+
+    /// Pushes the objects this one refers to. Returns false if the object
+    /// is borrowed at the moment and cannot be looked into.
+    fn children(&self, out: &mut Vec<usize>) -> bool {
+        match *self {
+            Obj::Env(ref rc) => match rc.try_borrow() {
+                Ok(env) => {
+                    if let Some(ref parent) = env.parent {
+                        out.push(addr(parent));
+                    }
+                    for val in env.slots.iter().flatten() {
+                        expr_children(val, out);
+                    }
+                    true
+                }
+                Err(_) => false,
+            },
+            Obj::Pair(ref rc) => match rc.try_borrow() {
+                Ok(cell) => {
+                    expr_children(&cell[0], out);
+                    expr_children(&cell[1], out);
+                    true
+                }
+                Err(_) => false,
+            },
+            Obj::Vector(ref rc) => match rc.try_borrow() {
+                Ok(vec) => {
+                    for item in vec.iter() {
+                        expr_children(item, out);
+                    }
+                    true
+                }
+                Err(_) => false,
+            },
+            Obj::Cont(ref rc) => rc.trace(out),
+            Obj::Winder(ref rc) => {
+                rc.trace(out);
+                true
+            }
+        }
+    }
+
+    /// Empties the object, moving what it held into `trash` so that it is
+    /// dropped once the collector no longer looks at the heap.
+    fn clear(&self, trash: &mut Vec<Expr>, frames: &mut Vec<Frame>) {
+        match *self {
+            Obj::Env(ref rc) => {
+                if let Ok(mut env) = rc.try_borrow_mut() {
+                    trash.extend(env.slots.drain(..).flatten());
+                    env.parent = None;
+                }
+            }
+            Obj::Pair(ref rc) => {
+                if let Ok(mut cell) = rc.try_borrow_mut() {
+                    trash.push(mem::replace(&mut cell[0], Expr::Nil));
+                    trash.push(mem::replace(&mut cell[1], Expr::Nil));
+                }
+            }
+            Obj::Vector(ref rc) => {
+                if let Ok(mut vec) = rc.try_borrow_mut() {
+                    trash.append(&mut vec);
+                }
+            }
+            Obj::Cont(ref rc) => rc.clear(trash, frames),
+            // a winder is only held by continuations, frames and other
+            // winders, so every cycle through one is broken elsewhere
+            Obj::Winder(_) => {}
+        }
+    }
+}
+
+/// Frees every environment, pair and vector that can no longer be reached
+/// and returns how many there were.
+///
+/// Objects that nothing but other heap objects refer to are found without
+/// knowing the roots: an object whose reference count is higher than the
+/// number of references to it from the heap is held from outside (by the
+/// evaluator, the expander or a Rust caller) and is live, as is everything
+/// it reaches. The rest is garbage, which can only be kept alive by a
+/// cycle; emptying the garbage breaks the cycles and lets reference
+/// counting free it. This makes it safe to collect at any point, even in
+/// the middle of evaluating an expression.
+pub fn collect() -> usize {
+    let tracked = HEAP.with(|heap| mem::take(&mut heap.borrow_mut().objects));
+    let objects: Vec<Obj> = tracked.iter().filter_map(Tracked::upgrade).collect();
+    drop(tracked);
+
+    let index: HashMap<usize, usize> = objects
+        .iter()
+        .enumerate()
+        .map(|(i, obj)| (obj.addr(), i))
+        .collect();
+    let mut edges = Vec::with_capacity(objects.len());
+    let mut internal = vec![0; objects.len()];
+    let mut opaque = vec![false; objects.len()];
+    for (i, obj) in objects.iter().enumerate() {
+        let mut children = vec![];
+        opaque[i] = !obj.children(&mut children);
+        children.retain(|child| index.contains_key(child));
+        for child in &children {
+            internal[index[child]] += 1;
+        }
+        edges.push(children);
+    }
+
+    let mut marked = vec![false; objects.len()];
+    // `objects` holds one reference itself
+    let mut stack: Vec<usize> = (0..objects.len())
+        .filter(|&i| opaque[i] || objects[i].strong_count() - 1 > internal[i])
+        .collect();
+    while let Some(i) = stack.pop() {
+        if marked[i] {
+            continue;
+        }
+        marked[i] = true;
+        stack.extend(edges[i].iter().map(|child| index[child]));
+    }
+
+    let mut trash = vec![];
+    let mut frames = vec![];
+    let mut live = vec![];
+    let mut freed = 0;
+    for (i, obj) in objects.iter().enumerate() {
+        if marked[i] {
+            live.push(obj.downgrade());
+        } else {
+            obj.clear(&mut trash, &mut frames);
+            freed += 1;
+        }
+    }
+    HEAP.with(|heap| {
+        let mut heap = heap.borrow_mut();
+        // anything allocated while we were collecting is kept as well
+        live.append(&mut heap.objects);
+        heap.objects = live;
+        heap.allocated = 0;
+        heap.threshold = MIN_THRESHOLD.max(2 * heap.objects.len());
+        heap.collections += 1;
+        heap.freed += freed;
+    });
+    drop(trash);
+    drop(frames);
+    drop(objects);
+    freed
+}
+
+/// Collects if enough has been allocated since the last collection.
+pub fn maybe_collect() {
+    let due = HEAP.with(|heap| {
+        let heap = heap.borrow();
+        heap.allocated >= heap.threshold
+    });
+    if due {
+        collect();
+    }
+}
+
+pub fn stats() -> HeapStats {
+    HEAP.with(|heap| {
+        let heap = heap.borrow();
+        let mut stats = HeapStats {
+            collections: heap.collections,
+            freed: heap.freed,
+            allocations: heap.allocations,
+            ..HeapStats::default()
+        };
+        for obj in heap.objects.iter().filter_map(Tracked::upgrade) {
+            match obj {
+                Obj::Env(_) => stats.environments += 1,
+                Obj::Pair(_) => stats.pairs += 1,
+                Obj::Vector(_) => stats.vectors += 1,
+                Obj::Cont(_) => stats.continuations += 1,
+                Obj::Winder(_) => stats.winders += 1,
+            }
+        }
+        stats
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use compile::Lambda;
+    use interp::Interpreter;
+    use symbol::Symbol;
+
+    #[test]
+    fn test_collect_cycles() {
+        collect();
+        let before = stats();
+
+        // a pair whose cdr is itself
+        let pair = Expr::new_pair(Expr::Int(1), Expr::Nil);
+        pair.set_cdr(pair.clone()).unwrap();
+        drop(pair);
+        // a frame that binds a closure over itself, as a recursive
+        // procedure does
+        let top = Env::top();
+        for _ in 0..10 {
+            let frame = Env::extend(&top, Rc::new(vec![Symbol::intern("f")]), vec![]);
+            let closure = Expr::new_cproc(Rc::new(Lambda::default()), frame.clone());
+            frame.borrow_mut().set(0, 0, closure);
+        }
+        let vec = Expr::new_vector(vec![Expr::Nil]);
+        if let Expr::Vector(ref items) = vec {
+            items.borrow_mut()[0] = vec.clone();
+        }
+        drop(vec);
+        assert_eq!(stats().environments, before.environments + 10);
+
+        assert_eq!(collect(), 12);
+        let after = stats();
+        assert_eq!(after.environments, before.environments);
+        assert_eq!(after.pairs, before.pairs);
+        assert_eq!(after.vectors, before.vectors);
+        assert_eq!(after.collections, before.collections + 1);
+        assert_eq!(after.freed, before.freed + 12);
+    }
+
+    #[test]
+    fn test_collect_keeps_reachable() {
+        let pair = Expr::new_pair(Expr::Int(1), Expr::Nil);
+        pair.set_cdr(pair.clone()).unwrap();
+        let names = Rc::new(vec![Symbol::intern("f"), Symbol::intern("p")]);
+        let frame = Env::extend(&Env::top(), names, vec![]);
+        let closure = Expr::new_cproc(Rc::new(Lambda::default()), frame.clone());
+        frame.borrow_mut().set(0, 0, closure);
+        frame.borrow_mut().set(0, 1, pair.clone());
+        let closure = frame.borrow().get(0, 0).unwrap();
+        drop(frame);
+
+        collect();
+        assert_eq!(pair.c("dddda").unwrap(), Expr::Int(1));
+        let frame = match closure {
+            Expr::CompProc(_, ref env) => env.clone(),
+            _ => unreachable!(),
+        };
+        assert!(frame.borrow().get(0, 1).unwrap().eqv(&pair));
+        assert!(frame.borrow().get(0, 0).unwrap().eqv(&closure));
+    }
+
+    #[test]
+    fn test_collect_shared_winders() {
+        // Continuations share the chain of winders they capture. A garbage
+        // continuation that refers to itself, captured inside nested
+        // `dynamic-wind`s whose thunks close over `x`, must not make the
+        // collector take `x`'s frame for garbage.
+        let mut interp = Interpreter::new();
+        interp
+            .eval_str(
+                "(define (make-junk)
+                   (let ((k #f))
+                     (call/cc (lambda (c) (set! k c)))
+                     (set! k (list k k))
+                     'junk))
+                 (define saved #f)
+                 (define (body)
+                   (make-junk)
+                   (gc)
+                   (call/cc (lambda (k) (set! saved k)))
+                   'done)
+                 (define (middle) (dynamic-wind (lambda () 1) body (lambda () 2)))
+                 (define log '())
+                 (define (run n)
+                   (let ((x (list n)))
+                     (dynamic-wind
+                       (lambda () (set! log (cons (car x) log)))
+                       middle
+                       (lambda () (set! log (cons (car x) log))))))",
+            )
+            .unwrap();
+        assert_eq!(interp.eval_str("(run 1)"), Ok(Expr::new_sym("done")));
+        // going back in runs the `before` thunk, and leaving the `after`
+        assert_eq!(interp.eval_str("(saved 'again)"), Ok(Expr::new_sym("done")));
+        assert_eq!(interp.eval_str("log").unwrap().to_string(), "(1 1 1 1)");
+    }
+
+    #[test]
+    fn test_collect_winding_continuations() {
+        // continuations that refer to themselves, captured inside a
+        // `dynamic-wind`, are garbage once the call returns
+        let mut interp = Interpreter::new();
+        interp
+            .eval_str(
+                "(define (loop-back)
+                   (let ((k #f))
+                     (dynamic-wind
+                       (lambda () #f)
+                       (lambda () (call/cc (lambda (c) (set! k c))) (set! k (list k)))
+                       (lambda () #f))
+                     'back))",
+            )
+            .unwrap();
+        collect();
+        let before = stats();
+        for _ in 0..10 {
+            assert_eq!(interp.eval_str("(loop-back)"), Ok(Expr::new_sym("back")));
+        }
+        assert!(stats().continuations >= before.continuations + 10);
+        collect();
+        let after = stats();
+        assert_eq!(after.continuations, before.continuations);
+        assert_eq!(after.winders, before.winders);
+        assert_eq!(after.environments, before.environments);
+    }
+
+    #[test]
+    fn test_collect_during_escape() {
+        // collecting in an `after` thunk while a continuation escapes
+        // through it keeps the frames the escape still needs
+        let mut interp = Interpreter::new();
+        interp
+            .eval_str(
+                "(define (escape n)
+                   (let ((x (list n)))
+                     (call/cc
+                       (lambda (out)
+                         (dynamic-wind
+                           (lambda () #f)
+                           (lambda () (out (car x)))
+                           (lambda () (gc) (set-car! x (+ (car x) 1))))))))",
+            )
+            .unwrap();
+        assert_eq!(interp.eval_str("(escape 1)"), Ok(Expr::Int(1)));
+        assert_eq!(interp.eval_str("(escape 41)"), Ok(Expr::Int(41)));
+    }
+
+    #[test]
+    fn test_collect_automatically() {
+        collect();
+        for i in 0..2 * MIN_THRESHOLD {
+            let pair = Expr::new_pair(Expr::Int(i as isize), Expr::Nil);
+            pair.set_cdr(pair.clone()).unwrap();
+            maybe_collect();
+        }
+        let stats = stats();
+        assert!(stats.collections >= 2);
+        assert!(stats.pairs <= MIN_THRESHOLD);
+    }
+}
@@ -0,0 +1,527 @@
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use ast::*;
+use compile;
+use error::SchemeError;
+use expand::Expander;
+use num_traits::ToPrimitive;
+use parser::Parser;
+use symbol::Symbol;
+use vm::Vm;
+
+/// The standard procedures written in Scheme, such as `map`, `append` and
+/// `assoc`.
+pub const PRELUDE: &str = include_str!("prelude.scm");
+
+/// A Scheme interpreter to embed in a Rust program: a global environment
+/// with the builtins defined, and the macros defined so far.
+///
+/// ```
+/// use rust_scm::{Arity, Expr, Interpreter};
+/// use std::convert::TryFrom;
+///
+/// let mut interp = Interpreter::new();
+/// interp.define_global("limit", 10);
+/// interp.register_fn("double", Arity::Exactly(1), |_, args| {
+///     let n = i64::try_from(args[0].clone())?;
+///     Ok(Expr::from(n * 2))
+/// });
+/// interp.eval_str("(define (clamp n) (if (> n limit) limit n))").unwrap();
+/// let res = interp.call("clamp", vec![Expr::from(42)]).unwrap();
+/// assert_eq!(i64::try_from(res), Ok(10));
+/// assert_eq!(interp.eval_str("(double 21)"), Ok(Expr::from(42)));
+/// ```
+pub struct Interpreter {
+    expander: Expander,
+    /// The machine that runs the interpreter's code.
+    pub(crate) vm: Vm,
+    sources: HashMap<Symbol, Expr>,
+}
+
+impl Default for Interpreter {
+    fn default() -> Interpreter {
+        Interpreter::new()
+    }
+}
+
+impl Interpreter {
+    /// An interpreter with the builtins and the prelude defined.
+    pub fn new() -> Interpreter {
+        let mut interp = Interpreter::without_prelude();
+        let mut parser = Parser::new_from(io::empty(), false);
+        parser.load_file("prelude.scm", PRELUDE.to_string());
+        interp.eval_all(&mut parser).expect("the prelude evaluates");
+        interp
+    }
+
+    /// An interpreter with only the builtins defined, not the procedures
+    /// of the prelude, such as `map` and `assoc`.
+    pub fn without_prelude() -> Interpreter {
+        Interpreter {
+            expander: Expander::new(),
+            vm: Vm::new(),
+            sources: HashMap::new(),
+        }
+    }
+
+    /// Evaluates the forms in `code` in order and returns the value of the
+    /// last one, or the unspecified value if there are none. Definitions
+    /// made by earlier forms stay, even if a later one fails.
+    pub fn eval_str(&mut self, code: &str) -> Result<Expr, SchemeError> {
+        let mut parser = Parser::new_from(io::empty(), false);
+        parser.load(code.to_string());
+        self.eval_all(&mut parser)
+    }
+
+    /// Like `eval_str` on the contents of the file at `path`. Errors say
+    /// where in the file they happened.
+    pub fn eval_file<P: AsRef<Path>>(&mut self, path: P) -> Result<Expr, SchemeError> {
+        let mut parser = file_parser(path.as_ref())?;
+        self.eval_all(&mut parser)
+    }
+
+    /// Reads the forms in `code` without evaluating them, to check its
+    /// syntax.
+    pub fn read_str(code: &str) -> Result<Vec<Expr>, SchemeError> {
+        let mut parser = Parser::new_from(io::empty(), false);
+        parser.load(code.to_string());
+        read_all(&mut parser)
+    }
+
+    /// Like `read_str` on the contents of the file at `path`.
+    pub fn read_file<P: AsRef<Path>>(path: P) -> Result<Vec<Expr>, SchemeError> {
+        read_all(&mut file_parser(path.as_ref())?)
+    }
+
+    fn eval_all<R: io::Read>(&mut self, parser: &mut Parser<R>) -> Result<Expr, SchemeError> {
+        let mut res = Expr::new_sym("OK");
+        while let Some(exp) = parser.read_exp()? {
+            res = self
+                .eval_expr(&exp)
+                .map_err(|err| err.at(parser.last_span()))?;
+        }
+        Ok(res)
+    }
+
+    /// Evaluates a form that is already read, as if it appeared at top
+    /// level.
+    pub fn eval_expr(&mut self, exp: &Expr) -> Result<Expr, SchemeError> {
+        let code = self.expand(exp).and_then(|expanded| {
+            compile::compile(&expanded, &mut self.vm.globals).map_err(|err| err.at(expanded.span()))
+        });
+        self.expander.end_form();
+        let res = self.vm.run(code?)?;
+        if exp.is_def() {
+            if let Ok(Expr::Sym(name)) = exp.def_var() {
+                self.sources.insert(name, exp.clone());
+            }
+        }
+        Ok(res)
+    }
+
+    /// The core form `exp` expands to, with the macros defined so far.
+    pub fn expand(&mut self, exp: &Expr) -> Result<Expr, SchemeError> {
+        self.expander.expand(exp).map_err(|err| err.at(exp.span()))
+    }
+
+    /// The last top-level `define` form evaluated for the global `name`.
+    pub fn source(&self, name: &str) -> Option<&Expr> {
+        self.sources.get(&Symbol::intern(name))
+    }
+
+    /// Binds the global variable `name` to `val`, replacing any value it
+    /// had.
+    pub fn define_global<V: Into<Expr>>(&mut self, name: &str, val: V) {
+        self.vm.globals.str_def(name, val.into());
+    }
+
+    /// The value of the global variable `name`, if it is bound.
+    pub fn global(&self, name: &str) -> Option<Expr> {
+        self.vm.globals.str_lookup(name)
+    }
+
+    /// Defines `name` as a procedure implemented by `func`. Scheme code
+    /// can only call it with a number of arguments `arity` accepts, so
+    /// `func` may index its arguments without checking how many there are.
+    /// It gets the machine as well, to call procedures it is passed with
+    /// `Vm::call`.
+    pub fn register_fn<F>(&mut self, name: &str, arity: Arity, func: F)
+    where
+        F: Fn(&mut Vm, &[Expr]) -> Result<Expr, SchemeError> + 'static,
+    {
+        self.vm.globals.def_fn(name, arity, func);
+    }
+
+    /// Calls the procedure bound to the global `name` with `args`.
+    pub fn call(&mut self, name: &str, args: Vec<Expr>) -> Result<Expr, SchemeError> {
+        match self.global(name) {
+            Some(func) => self.vm.call(func, args),
+            None => Err(SchemeError::UnboundVariable(Expr::new_sym(name))),
+        }
+    }
+}
+
+fn read_all(parser: &mut Parser<io::Empty>) -> Result<Vec<Expr>, SchemeError> {
+    let mut forms = vec![];
+    while let Some(exp) = parser.read_exp()? {
+        forms.push(exp);
+    }
+    Ok(forms)
+}
+
+/// A parser over the contents of the file at `path`.
+fn file_parser(path: &Path) -> Result<Parser<io::Empty>, SchemeError> {
+    let code = fs::read_to_string(path)
+        .map_err(|err| SchemeError::IoError(format!("{}: {}", path.display(), err)))?;
+    let mut parser = Parser::new_from(io::empty(), false);
+    parser.load_file(&path.to_string_lossy(), code);
+    Ok(parser)
+}
+
+impl From<bool> for Expr {
+    fn from(val: bool) -> Expr {
+        Expr::Bool(val)
+    }
+}
+
+impl From<i64> for Expr {
+    fn from(val: i64) -> Expr {
+        match isize::try_from(val) {
+            Ok(val) => Expr::Int(val),
+            Err(_) => Expr::BigInt(val.into()),
+        }
+    }
+}
+
+impl From<f64> for Expr {
+    fn from(val: f64) -> Expr {
+        Expr::Float(val)
+    }
+}
+
+impl From<String> for Expr {
+    fn from(val: String) -> Expr {
+        Expr::Str(val)
+    }
+}
+
+impl<'a> From<&'a str> for Expr {
+    fn from(val: &'a str) -> Expr {
+        Expr::new_str(val)
+    }
+}
+
+impl From<Symbol> for Expr {
+    fn from(val: Symbol) -> Expr {
+        Expr::Sym(val)
+    }
+}
+
+/// A vector becomes a proper list of its converted elements.
+impl<T: Into<Expr>> From<Vec<T>> for Expr {
+    fn from(items: Vec<T>) -> Expr {
+        join_list(items.into_iter().map(Into::into).collect(), Expr::Nil)
+    }
+}
+
+/// Only `#t` and `#f` convert; use `Expr::is_true` for Scheme's idea of
+/// truth.
+impl TryFrom<Expr> for bool {
+    type Error = SchemeError;
+
+    fn try_from(exp: Expr) -> Result<bool, SchemeError> {
+        match exp {
+            Expr::Bool(val) => Ok(val),
+            _ => Err(SchemeError::wrong_type("boolean", &exp)),
+        }
+    }
+}
+
+/// Exact integers that fit convert.
+impl TryFrom<Expr> for i64 {
+    type Error = SchemeError;
+
+    fn try_from(exp: Expr) -> Result<i64, SchemeError> {
+        let val = match exp {
+            Expr::Int(val) => val.to_i64(),
+            Expr::BigInt(ref val) => val.to_i64(),
+            _ => None,
+        };
+        val.ok_or_else(|| SchemeError::wrong_type("64-bit integer", &exp))
+    }
+}
+
+/// Any real number converts, exact ones to the nearest float.
+impl TryFrom<Expr> for f64 {
+    type Error = SchemeError;
+
+    fn try_from(exp: Expr) -> Result<f64, SchemeError> {
+        let val = match exp {
+            Expr::Int(val) => val.to_f64(),
+            Expr::BigInt(ref val) => val.to_f64(),
+            Expr::Rational(ref val) => val.to_f64(),
+            Expr::Float(val) => Some(val),
+            _ => None,
+        };
+        val.ok_or_else(|| SchemeError::wrong_type("number", &exp))
+    }
+}
+
+/// Strings convert; symbols do not.
+impl TryFrom<Expr> for String {
+    type Error = SchemeError;
+
+    fn try_from(exp: Expr) -> Result<String, SchemeError> {
+        match exp {
+            Expr::Str(val) => Ok(val),
+            Expr::MutStr(ref val) => Ok(val.borrow().iter().collect()),
+            _ => Err(SchemeError::wrong_type("string", &exp)),
+        }
+    }
+}
+
+/// A proper list converts if each of its elements does.
+impl<T: TryFrom<Expr, Error = SchemeError>> TryFrom<Expr> for Vec<T> {
+    type Error = SchemeError;
+
+    fn try_from(exp: Expr) -> Result<Vec<T>, SchemeError> {
+        let (items, tail) = split_list(&exp)?;
+        if !tail.is_empty() {
+            return Err(SchemeError::wrong_type("list", &exp));
+        }
+        items.into_iter().map(T::try_from).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    #[test]
+    fn test_eval_str() {
+        let mut interp = Interpreter::new();
+        assert_eq!(interp.eval_str("(define x 2) (* x 21)"), Ok(Expr::Int(42)));
+        // definitions persist between calls, macros included
+        interp
+            .eval_str("(define-syntax twice (syntax-rules () ((_ e) (begin e e))))")
+            .unwrap();
+        assert_eq!(
+            interp.eval_str("(define n 0) (twice (set! n (+ n x))) n"),
+            Ok(Expr::Int(4))
+        );
+        assert_eq!(interp.eval_str(""), Ok(Expr::new_sym("OK")));
+        assert_eq!(interp.eval_str("(car"), Err(SchemeError::UnexpectedEof));
+        assert_eq!(Interpreter::read_str("(car x) y").unwrap().len(), 2);
+        assert_eq!(
+            Interpreter::read_str("(car"),
+            Err(SchemeError::UnexpectedEof)
+        );
+        assert!(interp.eval_file("/no/such/file.scm").is_err());
+    }
+
+    #[test]
+    fn test_prelude() {
+        let mut interp = Interpreter::new();
+        let mut eval = |code: &str| interp.eval_str(code).map(|val| val.to_string());
+        let cases = [
+            ("(list 1 (+ 1 1) 'c)", "(1 2 c)"),
+            ("(list)", "()"),
+            ("(list? '(1 2))", "#t"),
+            ("(list? '(1 . 2))", "#f"),
+            (
+                "(let ((x (list 1 2))) (set-cdr! (cdr x) x) (list? x))",
+                "#f",
+            ),
+            ("(length '(a b c))", "3"),
+            ("(append '(1) '(2 3) '() '(4 . 5))", "(1 2 3 4 . 5)"),
+            ("(append)", "()"),
+            ("(append '() 'a)", "a"),
+            ("(reverse '(1 2 3))", "(3 2 1)"),
+            ("(list-tail '(a b c d) 2)", "(c d)"),
+            ("(list-ref '(a b c d) 3)", "d"),
+            (
+                "(let* ((x '(1 2 . 3)) (y (list-copy x))) (list y (eq? x y)))",
+                "((1 2 . 3) #f)",
+            ),
+            ("(cadr '(1 2 3))", "2"),
+            ("(cdddr '(1 2 3 4))", "(4)"),
+            ("(memq 'c '(a b c d))", "(c d)"),
+            ("(memq 'z '(a b c d))", "#f"),
+            ("(memv 101 '(100 101 102))", "(101 102)"),
+            ("(member (list 'a) '(b (a) c))", "((a) c)"),
+            ("(member 2.0 '(1 2 3) =)", "(2 3)"),
+            ("(assq 'b '((a 1) (b 2)))", "(b 2)"),
+            ("(assv 5 '((2 3) (5 7) (11 13)))", "(5 7)"),
+            ("(assoc '(a) '(((a)) ((b))))", "((a))"),
+            ("(assoc 2.0 '((1 1) (2 4) (3 9)) =)", "(2 4)"),
+            ("(map cadr '((a b) (d e) (g h)))", "(b e h)"),
+            ("(map + '(1 2 3) '(10 20 30 40))", "(11 22 33)"),
+            (
+                "(map (lambda (x y z) (list x y z)) '(1 2) '(a b) '(p q))",
+                "((1 a p) (2 b q))",
+            ),
+            (
+                "(let ((n 0)) (for-each (lambda (x) (set! n (+ n x))) '(1 2 3)) n)",
+                "6",
+            ),
+            (
+                "(let ((n 0)) (for-each (lambda (x y) (set! n (+ n (* x y)))) '(1 2) '(3 4 5)) n)",
+                "11",
+            ),
+            ("(not 3)", "#f"),
+            ("(not #f)", "#t"),
+        ];
+        for &(code, expected) in cases.iter() {
+            assert_eq!(eval(code), Ok(expected.to_string()), "{}", code);
+        }
+        assert!(eval("(list-ref '(a b) 2)").is_err());
+
+        let mut bare = Interpreter::without_prelude();
+        assert!(bare.global("map").is_none());
+        assert!(bare.eval_str("(list 1)").is_err());
+        assert!(bare.global("car").is_some());
+    }
+
+    #[test]
+    fn test_cyclic_lists() {
+        let mut interp = Interpreter::new();
+        interp
+            .eval_str("(define x (list 1 2 3)) (set-cdr! (cddr x) x)")
+            .unwrap();
+        let expected = "wrong type: expected list, got #0=(1 2 3 . #0#)";
+        for code in ["(length x)", "(apply + x)", "(scm->json x)"].iter() {
+            let err = interp.eval_str(code).unwrap_err();
+            assert_eq!(err.unlocated().to_string(), expected, "{}", code);
+        }
+        assert!(interp.eval_str("(length '(1 . 2))").is_err());
+        assert_eq!(
+            interp.eval_str("(cons 0 x)").unwrap().to_string(),
+            "(0 . #0=(1 2 3 . #0#))"
+        );
+    }
+
+    #[test]
+    fn test_read_file() {
+        let path = std::env::temp_dir().join(format!("rust_scm_read_{}.scm", std::process::id()));
+        std::fs::write(&path, "#!/usr/bin/env rust-scm\n(car '(1)) undefined\n").unwrap();
+        let forms = Interpreter::read_file(&path).unwrap();
+        assert_eq!(forms.len(), 2);
+        // a bare symbol at top level is located by the reader
+        let err = Interpreter::new().eval_file(&path).unwrap_err().to_string();
+        assert!(err.starts_with("unbound variable undefined at "), "{}", err);
+        assert!(err.ends_with(".scm:2:12"), "{}", err);
+        std::fs::write(&path, "(car '(1)\n").unwrap();
+        let err = Interpreter::read_file(&path).unwrap_err();
+        assert_eq!(err.unlocated(), &SchemeError::UnexpectedEof);
+        std::fs::remove_file(&path).unwrap();
+        assert!(Interpreter::read_file(&path).is_err());
+    }
+
+    #[test]
+    fn test_globals_and_calls() {
+        let mut interp = Interpreter::new();
+        interp.define_global("greeting", "hello");
+        interp.define_global("items", vec![1, 2, 3]);
+        assert_eq!(
+            interp
+                .eval_str("(cons greeting (car items))")
+                .unwrap()
+                .to_string(),
+            "(\"hello\" . 1)"
+        );
+        assert_eq!(interp.global("greeting"), Some(Expr::from("hello")));
+        assert_eq!(interp.global("nothing"), None);
+        assert_eq!(interp.source("greeting"), None);
+
+        interp.eval_str("(define (add a b) (+ a b))").unwrap();
+        assert_eq!(
+            interp.source("add").map(Expr::to_string),
+            Some("(define (add a b) (+ a b))".to_string())
+        );
+        assert_eq!(
+            interp.call("add", vec![Expr::from(1), Expr::from(2)]),
+            Ok(Expr::Int(3))
+        );
+        assert_eq!(
+            interp.call("missing", vec![]),
+            Err(SchemeError::UnboundVariable(Expr::new_sym("missing")))
+        );
+        assert!(interp.call("add", vec![]).is_err());
+    }
+
+    #[test]
+    fn test_register_fn() {
+        let mut interp = Interpreter::new();
+        let log = Rc::new(RefCell::new(vec![]));
+        let sink = log.clone();
+        interp.register_fn("log!", Arity::Exactly(1), move |_, args| {
+            sink.borrow_mut().push(String::try_from(args[0].clone())?);
+            Ok(Expr::new_sym("OK"))
+        });
+        interp.eval_str("(log! \"a\") (log! \"b\")").unwrap();
+        assert_eq!(*log.borrow(), vec!["a", "b"]);
+        assert_eq!(
+            interp.eval_str("(log! 1)"),
+            Err(SchemeError::WrongType("string", Expr::Int(1)))
+        );
+        assert_eq!(
+            interp.eval_str("(log!)").unwrap_err().to_string(),
+            "log!: wrong number of arguments (expected 1, got 0)"
+        );
+
+        interp.register_fn("map1", Arity::Exactly(2), |vm, args| {
+            let (items, _) = split_list(&args[1])?;
+            let mut res = vec![];
+            for item in items {
+                res.push(vm.call(args[0].clone(), vec![item])?);
+            }
+            Ok(Expr::from(res))
+        });
+        assert_eq!(
+            interp
+                .eval_str("(map1 (lambda (x) (* x x)) '(1 2 3))")
+                .unwrap()
+                .to_string(),
+            "(1 4 9)"
+        );
+        // an error in the callback abandons the whole call
+        assert!(interp.eval_str("(map1 car '(1))").is_err());
+        assert_eq!(interp.eval_str("(+ 1 1)"), Ok(Expr::Int(2)));
+    }
+
+    #[test]
+    fn test_conversions() {
+        assert_eq!(Expr::from(true), Expr::Bool(true));
+        assert_eq!(Expr::from(7i64), Expr::Int(7));
+        assert_eq!(i64::try_from(Expr::from(i64::MAX)), Ok(i64::MAX));
+        assert_eq!(String::try_from(Expr::from("hi")), Ok("hi".to_string()));
+        assert_eq!(
+            String::try_from(Expr::new_mut_str("hi")),
+            Ok("hi".to_string())
+        );
+        assert_eq!(f64::try_from(Expr::Int(2)), Ok(2.0));
+        assert_eq!(Expr::from(vec!["a", "b"]).to_string(), "(\"a\" \"b\")");
+
+        let mut interp = Interpreter::new();
+        let list = interp.eval_str("'(1 2 3)").unwrap();
+        assert_eq!(Vec::<i64>::try_from(list), Ok(vec![1, 2, 3]));
+        let nested = interp.eval_str("'((#t) (#f #t))").unwrap();
+        assert_eq!(
+            Vec::<Vec<bool>>::try_from(nested),
+            Ok(vec![vec![true], vec![false, true]])
+        );
+        assert_eq!(
+            i64::try_from(interp.eval_str("(expt 2 100)").unwrap()).unwrap_err(),
+            SchemeError::wrong_type("64-bit integer", &interp.eval_str("(expt 2 100)").unwrap())
+        );
+        assert!(bool::try_from(Expr::Int(0)).is_err());
+        assert!(Vec::<i64>::try_from(interp.eval_str("'(1 . 2)").unwrap()).is_err());
+        assert!(Vec::<i64>::try_from(interp.eval_str("'(1 a)").unwrap()).is_err());
+    }
+}
@@ -0,0 +1,221 @@
+//! Converting between Scheme data and JSON.
+//!
+//! | JSON             | Scheme                                        |
+//! |------------------|-----------------------------------------------|
+//! | `null`           | the symbol `null`                             |
+//! | `true`, `false`  | `#t`, `#f`                                    |
+//! | number           | exact integer, or inexact real if it has a    |
+//! |                  | fraction or exponent                          |
+//! | string           | string                                        |
+//! | array            | vector                                        |
+//! | object           | association list with string keys, in key     |
+//! |                  | order; `{}` is `()`                           |
+//!
+//! Going the other way, symbols other than `null` and characters become
+//! strings, exact rationals become floats, and an association list may
+//! have symbol keys as well; as with `assoc`, the first entry for a key
+//! wins. Anything else, such as a procedure or an improper list, cannot be
+//! converted.
+//!
+//! `Expr` implements `Serialize` and `Deserialize` with this mapping, so
+//! host data can be handed to Scheme through any serde format that can
+//! describe itself, not only JSON.
+
+use ast::{join_list, split_list, Expr};
+use error::SchemeError;
+use num_traits::ToPrimitive;
+use serde::de::{Deserialize, Deserializer};
+use serde::ser::{Error, Serialize, Serializer};
+use serde_json::{self, Map, Number, Value};
+use string;
+
+/// The Scheme form of a JSON value.
+pub fn from_json(value: &Value) -> Expr {
+    match *value {
+        Value::Null => Expr::new_sym("null"),
+        Value::Bool(val) => Expr::Bool(val),
+        Value::Number(ref val) => match (val.as_i64(), val.as_u64()) {
+            (Some(val), _) => Expr::from(val),
+            (None, Some(val)) => Expr::BigInt(val.into()),
+            _ => Expr::Float(val.as_f64().unwrap_or(f64::NAN)),
+        },
+        Value::String(ref val) => Expr::new_str(val),
+        Value::Array(ref items) => Expr::new_vector(items.iter().map(from_json).collect()),
+        Value::Object(ref fields) => {
+            let entries = fields
+                .iter()
+                .map(|(key, val)| Expr::new_pair(Expr::new_str(key), from_json(val)))
+                .collect();
+            join_list(entries, Expr::Nil)
+        }
+    }
+}
+
+/// The JSON form of a Scheme value, or a `WrongType` error naming the
+/// part that has none.
+pub fn to_json(exp: &Expr) -> Result<Value, SchemeError> {
+    Ok(match *exp {
+        Expr::Bool(val) => Value::Bool(val),
+        Expr::Int(val) => Value::from(val as i64),
+        Expr::BigInt(ref val) => match (val.to_i64(), val.to_u64()) {
+            (Some(val), _) => Value::from(val),
+            (None, Some(val)) => Value::from(val),
+            _ => return Err(SchemeError::wrong_type("64-bit integer", exp)),
+        },
+        Expr::Rational(ref val) => float(val.to_f64(), exp)?,
+        Expr::Float(val) => float(Some(val), exp)?,
+        Expr::Str(ref val) => Value::String(val.clone()),
+        Expr::MutStr(ref val) => Value::String(val.borrow().iter().collect()),
+        Expr::Char(val) => Value::String(val.to_string()),
+        Expr::Sym(sym) if &*sym.name() == "null" => Value::Null,
+        Expr::Sym(sym) => Value::String(sym.name().to_string()),
+        Expr::Vector(ref items) => {
+            let items: Result<Vec<Value>, SchemeError> =
+                items.borrow().iter().map(to_json).collect();
+            Value::Array(items?)
+        }
+        Expr::Pair(..) | Expr::Nil => {
+            let (entries, tail) = split_list(exp)?;
+            if !tail.is_empty() {
+                return Err(SchemeError::wrong_type("association list", exp));
+            }
+            let mut fields = Map::new();
+            for entry in entries {
+                let key = match entry.car() {
+                    Ok(Expr::Str(key)) => key,
+                    Ok(Expr::Sym(key)) => key.name().to_string(),
+                    _ => return Err(SchemeError::wrong_type("association list", exp)),
+                };
+                if !fields.contains_key(&key) {
+                    fields.insert(key, to_json(&entry.cdr()?)?);
+                }
+            }
+            Value::Object(fields)
+        }
+        _ => return Err(SchemeError::wrong_type("value with a JSON form", exp)),
+    })
+}
+
+fn float(val: Option<f64>, exp: &Expr) -> Result<Value, SchemeError> {
+    val.and_then(Number::from_f64)
+        .map(Value::Number)
+        .ok_or_else(|| SchemeError::wrong_type("finite number", exp))
+}
+
+impl Serialize for Expr {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        to_json(self)
+            .map_err(S::Error::custom)?
+            .serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for Expr {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Expr, D::Error> {
+        Value::deserialize(deserializer).map(|value| from_json(&value))
+    }
+}
+
+/// `(json->scm string)` parses JSON text.
+pub fn json_to_scm(args: &[Expr]) -> Result<Expr, SchemeError> {
+    serde_json::from_str(&string::text(&args[0])?)
+        .map(|value| from_json(&value))
+        .map_err(|err| SchemeError::ReadError(format!("invalid JSON: {}", err)))
+}
+
+/// `(scm->json obj)` is the JSON text for `obj`.
+pub fn scm_to_json(args: &[Expr]) -> Result<Expr, SchemeError> {
+    Ok(Expr::Str(to_json(&args[0])?.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use eval::Evaler;
+
+    #[test]
+    fn test_from_json() {
+        let value: Value =
+            serde_json::from_str(r#"{"b": [1, 2.5, "x"], "a": {"ok": true, "none": null}}"#)
+                .unwrap();
+        assert_eq!(
+            from_json(&value).to_string(),
+            r#"(("a" ("none" . null) ("ok" . #t)) ("b" . #(1 2.5 "x")))"#
+        );
+        assert_eq!(
+            from_json(&Value::from(u64::MAX)),
+            Expr::BigInt(u64::MAX.into())
+        );
+        assert_eq!(from_json(&serde_json::json!({})), Expr::Nil);
+    }
+
+    #[test]
+    fn test_to_json() {
+        let mut evaler = Evaler::new();
+        let mut json = |code: &str| {
+            let exp = evaler.eval_from(code.to_string()).unwrap();
+            to_json(&exp).map(|value| value.to_string())
+        };
+        assert_eq!(
+            json("'((name . \"x\") (tags . #(a null 1/2)) (name . \"shadowed\"))"),
+            Ok(r#"{"name":"x","tags":["a",null,0.5]}"#.to_string())
+        );
+        assert_eq!(json("'()"), Ok("{}".to_string()));
+        assert_eq!(json("#\\a"), Ok("\"a\"".to_string()));
+        assert_eq!(
+            json("(string-append \"a\" \"b\")"),
+            Ok("\"ab\"".to_string())
+        );
+        assert_eq!(
+            json("(expt 2 64)"),
+            Err(SchemeError::wrong_type(
+                "64-bit integer",
+                &Expr::BigInt("18446744073709551616".parse().unwrap())
+            ))
+        );
+        assert!(json("'(1 2)").is_err());
+        assert!(json("'((a . 1) . 2)").is_err());
+        assert!(json("car").is_err());
+        assert!(json("(/ 1. 0.)").is_err());
+    }
+
+    #[test]
+    fn test_builtins() {
+        let mut evaler = Evaler::new();
+        assert_eq!(
+            evaler.eval_from(r#"(cdr (car (cdr (json->scm "{\"b\": 2, \"a\": 1}"))))"#.to_string()),
+            Ok(Expr::Int(2))
+        );
+        assert_eq!(
+            evaler.eval_from(r#"(scm->json '((ok . #t) (items . #(1 "two"))))"#.to_string()),
+            Ok(Expr::new_str(r#"{"items":[1,"two"],"ok":true}"#))
+        );
+        assert!(evaler
+            .eval_from(r#"(json->scm "{oops")"#.to_string())
+            .is_err());
+        // strings built at run time are mutable, and parse the same
+        let obj = evaler.eval_from(r#"(json->scm (string-append "{\"a\"" ":1}"))"#.to_string());
+        assert_eq!(obj.unwrap().to_string(), r#"(("a" . 1))"#);
+        assert_eq!(
+            evaler.eval_from(r#"(json->scm (string-copy "[1, 2]"))"#.to_string()),
+            Ok(Expr::new_vector(vec![Expr::Int(1), Expr::Int(2)]))
+        );
+        assert!(evaler.eval_from("(json->scm 1)".to_string()).is_err());
+        assert!(evaler.eval_from("(scm->json car)".to_string()).is_err());
+    }
+
+    #[test]
+    fn test_serde() {
+        let exp: Expr = serde_json::from_str(r#"{"port": 8080, "hosts": ["a", "b"]}"#).unwrap();
+        assert_eq!(
+            exp.to_string(),
+            r#"(("hosts" . #("a" "b")) ("port" . 8080))"#
+        );
+        assert_eq!(
+            serde_json::to_string(&exp).unwrap(),
+            r#"{"hosts":["a","b"],"port":8080}"#
+        );
+        let err = serde_json::to_string(&Expr::new_pair(Expr::Int(1), Expr::Nil)).unwrap_err();
+        assert!(err.to_string().contains("association list"));
+    }
+}
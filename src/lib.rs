@@ -0,0 +1,74 @@
+//! A Scheme interpreter that can be embedded in a Rust program.
+//!
+//! `Interpreter` is the way in: it evaluates source code, binds globals,
+//! exposes Rust functions to Scheme and calls Scheme procedures from
+//! Rust. Values cross between the two as `Expr`, which converts from and
+//! to `bool`, `i64`, `f64`, `String` and `Vec`s of those with the standard
+//! `From` and `TryFrom` traits, and to structured data through serde (see
+//! `json`).
+//!
+//! ```
+//! use rust_scm::{Arity, Expr, Interpreter};
+//! use std::cell::Cell;
+//! use std::convert::TryFrom;
+//! use std::rc::Rc;
+//!
+//! let mut interp = Interpreter::new();
+//!
+//! // a builtin that keeps state of its own
+//! let hits = Rc::new(Cell::new(0));
+//! let counter = hits.clone();
+//! interp.register_fn("hit!", Arity::Exactly(0), move |_, _| {
+//!     counter.set(counter.get() + 1);
+//!     Ok(Expr::from(counter.get()))
+//! });
+//!
+//! interp
+//!     .eval_str("(define (sum items) (if (null? items) 0 (+ (car items) (sum (cdr items)))))")
+//!     .unwrap();
+//! let total = interp.call("sum", vec![Expr::from(vec![1, 2, 3])]).unwrap();
+//! assert_eq!(i64::try_from(total), Ok(6));
+//!
+//! interp.eval_str("(hit!) (hit!)").unwrap();
+//! assert_eq!(hits.get(), 2);
+//! ```
+//!
+//! `Repl` runs an interactive session on top of an `Interpreter`. The
+//! interpreter's own parts, the reader, the macro expander, the compiler
+//! to bytecode and the machine that runs it, are private.
+
+extern crate ctrlc;
+extern crate num_bigint;
+extern crate num_integer;
+extern crate num_rational;
+extern crate num_traits;
+extern crate rustyline;
+extern crate serde;
+extern crate serde_json;
+
+mod ast;
+mod compile;
+// public only for the benchmarks
+#[doc(hidden)]
+pub mod env;
+mod error;
+#[cfg(test)]
+mod eval;
+mod expand;
+mod gc;
+mod interp;
+pub mod json;
+mod num;
+mod parser;
+mod repl;
+mod string;
+mod symbol;
+mod syntax_rules;
+mod vm;
+
+pub use ast::{Arity, Expr, Span};
+pub use error::SchemeError;
+pub use interp::Interpreter;
+pub use repl::Repl;
+pub use symbol::Symbol;
+pub use vm::Vm;
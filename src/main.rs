@@ -1,50 +1,177 @@
-//#![feature(test)]
-//extern crate test;
+extern crate rust_scm;
 
-use std::io::Read;
-use std::path::Path;
-use std::fs::File;
-use eval::Evaler;
+use rust_scm::Repl;
+use rust_scm::{Arity, Expr, Interpreter, SchemeError};
+use std::io::{self, Write};
+use std::process;
 
-mod ast;
-mod env;
-mod eval;
-mod parser;
+const USAGE: &str = "usage: rust-scm [options] [script [args...]]
 
-fn help() {
-    println!("rust-scm: prog");
+  -e EXPR   evaluate EXPR and print its value
+  -l FILE   load FILE before the script
+  -i        start the REPL after the script and expressions
+  --check   read the script and libraries without running them
+  --no-prelude
+            start without the prelude's procedures, such as map
+  -h, --help
+            show this message
+
+With no script and no -e, starts the REPL. The script's arguments are
+available as (command-line), after the script's own name.";
+
+/// Something to do before the script, in the order given.
+#[derive(Debug, PartialEq)]
+enum Step {
+    Eval(String),
+    Load(String),
 }
 
-#[allow(dead_code)]
-fn main() {
-    let mut args = std::env::args();
-    if args.len() < 2 {
-        let mut evaler = Evaler::new(std::io::stdin(), true);
-        let res = evaler.eval().unwrap();
-        res.print();
-        println!("");
-    } else if args.len() == 2 {
-        let program = args.nth(0).unwrap();
-        let mut code = String::new();
-        match args.nth(1) {
-            Some(arg) => {
-                let path = Path::new(arg.trim());
-                let mut f = File::open(&path).unwrap();
-                f.read_to_string(&mut code).unwrap();
-            },
-            None => {
-                //std::env::set_exit_status(1);
-                return
+#[derive(Debug, Default, PartialEq)]
+struct Options {
+    steps: Vec<Step>,
+    /// The script followed by its arguments.
+    script: Vec<String>,
+    interactive: bool,
+    check: bool,
+    no_prelude: bool,
+    help: bool,
+}
+
+/// Parses the arguments after the program name. Options stop at the
+/// script, or after `--`.
+fn parse_args(args: &[String]) -> Result<Options, String> {
+    let mut opts = Options::default();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let mut value = || {
+            args.next()
+                .cloned()
+                .ok_or_else(|| format!("{} needs an argument", arg))
+        };
+        match arg.as_str() {
+            "-e" => opts.steps.push(Step::Eval(value()?)),
+            "-l" => opts.steps.push(Step::Load(value()?)),
+            "-i" => opts.interactive = true,
+            "--check" => opts.check = true,
+            "--no-prelude" => opts.no_prelude = true,
+            "-h" | "--help" => opts.help = true,
+            "--" => {
+                opts.script = args.cloned().collect();
+                break;
+            }
+            _ if arg.starts_with('-') && arg != "-" => {
+                return Err(format!("unknown option {}", arg))
+            }
+            _ => {
+                opts.script.push(arg.clone());
+                opts.script.extend(args.cloned());
+                break;
+            }
+        }
+    }
+    if opts.check && opts.script.is_empty() && opts.steps.is_empty() {
+        return Err("--check needs a script".to_string());
+    }
+    Ok(opts)
+}
+
+/// Runs everything the options ask for, stopping at the first error.
+fn run(opts: Options) -> Result<(), SchemeError> {
+    if opts.check {
+        for step in &opts.steps {
+            match *step {
+                Step::Eval(ref code) => {
+                    Interpreter::read_str(code)?;
+                }
+                Step::Load(ref path) => {
+                    Interpreter::read_file(path)?;
+                }
             }
         }
-        println!("code:\n{}", program);
-        println!("code:\n{}", code);
-        let mut evaler = Evaler::new(std::io::stdin(), false);
-        let res = evaler.eval_from(code).unwrap();
-        res.print();
-        println!("");
+        if let Some(script) = opts.script.first() {
+            Interpreter::read_file(script)?;
+        }
+        return Ok(());
+    }
+
+    let mut interp = if opts.no_prelude {
+        Interpreter::without_prelude()
     } else {
-        help();
+        Interpreter::new()
+    };
+    let command_line = opts.script.clone();
+    interp.register_fn("command-line", Arity::Exactly(0), move |_, _| {
+        Ok(Expr::from(command_line.clone()))
+    });
+    for step in &opts.steps {
+        match *step {
+            Step::Eval(ref code) => println!("{}", interp.eval_str(code)?),
+            Step::Load(ref path) => {
+                interp.eval_file(path)?;
+            }
+        }
+    }
+    if let Some(script) = opts.script.first() {
+        interp.eval_file(script)?;
+    }
+    if opts.interactive || opts.script.is_empty() && opts.steps.is_empty() {
+        Repl::new(interp)
+            .run()
+            .map_err(|err| SchemeError::IoError(err.to_string()))?;
+    }
+    Ok(())
+}
+
+fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let opts = match parse_args(&args) {
+        Ok(opts) => opts,
+        Err(msg) => {
+            eprintln!("rust-scm: {}\n{}", msg, USAGE);
+            process::exit(2);
+        }
+    };
+    if opts.help {
+        println!("{}", USAGE);
+        return;
+    }
+    let res = run(opts);
+    let _ = io::stdout().flush();
+    if let Err(err) = res {
+        eprintln!("rust-scm: error: {}", err);
+        process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<Options, String> {
+        let args: Vec<String> = args.iter().map(|arg| arg.to_string()).collect();
+        parse_args(&args)
     }
 
+    #[test]
+    fn test_parse_args() {
+        assert_eq!(parse(&[]), Ok(Options::default()));
+        let opts = parse(&["-l", "lib.scm", "-e", "(f)", "-i", "run.scm", "-e", "x"]).unwrap();
+        assert_eq!(
+            opts.steps,
+            vec![Step::Load("lib.scm".into()), Step::Eval("(f)".into())]
+        );
+        assert!(opts.interactive);
+        // everything after the script is its own
+        assert_eq!(opts.script, vec!["run.scm", "-e", "x"]);
+        assert_eq!(parse(&["--", "-e"]).unwrap().script, vec!["-e"]);
+        assert!(parse(&["--check", "a.scm"]).unwrap().check);
+        assert!(parse(&["--no-prelude"]).unwrap().no_prelude);
+
+        assert_eq!(parse(&["-e"]), Err("-e needs an argument".to_string()));
+        assert_eq!(parse(&["-x"]), Err("unknown option -x".to_string()));
+        assert_eq!(
+            parse(&["--check"]),
+            Err("--check needs a script".to_string())
+        );
+    }
 }
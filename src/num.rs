@@ -0,0 +1,444 @@
+use ast::Expr;
+use error::SchemeError;
+use num_bigint::BigInt;
+use num_integer::Integer;
+use num_rational::BigRational;
+use num_traits::{FromPrimitive, Num, One, Signed, ToPrimitive, Zero};
+use std::cmp::{self, Ordering};
+
+/// Two operands promoted to a common representation following the R7RS
+/// contagion rules: an inexact operand makes the result inexact, otherwise a
+/// rational operand makes it rational, otherwise a bignum makes it a bignum.
+enum Operands {
+    Fix(isize, isize),
+    Big(BigInt, BigInt),
+    Rat(BigRational, BigRational),
+    Flo(f64, f64),
+}
+
+/// Integer division flavours shared by `quotient`, `remainder` and `modulo`.
+#[derive(Clone, Copy)]
+pub enum IntDiv {
+    Quotient,
+    Remainder,
+    Modulo,
+}
+
+fn rank(exp: &Expr) -> Result<u8, SchemeError> {
+    match *exp {
+        Expr::Int(_) => Ok(0),
+        Expr::BigInt(_) => Ok(1),
+        Expr::Rational(_) => Ok(2),
+        Expr::Float(_) => Ok(3),
+        _ => Err(SchemeError::wrong_type("number", exp)),
+    }
+}
+
+fn promote(a: &Expr, b: &Expr) -> Result<Operands, SchemeError> {
+    Ok(match cmp::max(rank(a)?, rank(b)?) {
+        0 => Operands::Fix(a.as_int()?, b.as_int()?),
+        1 => Operands::Big(to_big(a), to_big(b)),
+        2 => Operands::Rat(to_rat(a), to_rat(b)),
+        _ => Operands::Flo(to_f64(a), to_f64(b)),
+    })
+}
+
+fn to_big(exp: &Expr) -> BigInt {
+    match *exp {
+        Expr::Int(n) => BigInt::from(n),
+        Expr::BigInt(ref n) => n.clone(),
+        _ => unreachable!("not an exact integer"),
+    }
+}
+
+fn to_rat(exp: &Expr) -> BigRational {
+    match *exp {
+        Expr::Rational(ref r) => r.clone(),
+        _ => BigRational::from_integer(to_big(exp)),
+    }
+}
+
+fn to_f64(exp: &Expr) -> f64 {
+    match *exp {
+        Expr::Int(n) => n as f64,
+        Expr::BigInt(ref n) => n.to_f64().unwrap_or(f64::NAN),
+        Expr::Rational(ref r) => r.to_f64().unwrap_or(f64::NAN),
+        Expr::Float(f) => f,
+        _ => unreachable!("not a number"),
+    }
+}
+
+/// Demotes a bignum that fits in a fixnum.
+pub fn from_big(n: BigInt) -> Expr {
+    match n.to_isize() {
+        Some(n) => Expr::Int(n),
+        None => Expr::BigInt(n),
+    }
+}
+
+/// Demotes a rational with denominator one to an integer.
+pub fn from_rat(r: BigRational) -> Expr {
+    if r.is_integer() {
+        from_big(r.to_integer())
+    } else {
+        Expr::Rational(r)
+    }
+}
+
+pub fn add(a: &Expr, b: &Expr) -> Result<Expr, SchemeError> {
+    Ok(match promote(a, b)? {
+        Operands::Fix(x, y) => match x.checked_add(y) {
+            Some(n) => Expr::Int(n),
+            None => from_big(BigInt::from(x) + y),
+        },
+        Operands::Big(x, y) => from_big(x + y),
+        Operands::Rat(x, y) => from_rat(x + y),
+        Operands::Flo(x, y) => Expr::Float(x + y),
+    })
+}
+
+pub fn sub(a: &Expr, b: &Expr) -> Result<Expr, SchemeError> {
+    Ok(match promote(a, b)? {
+        Operands::Fix(x, y) => match x.checked_sub(y) {
+            Some(n) => Expr::Int(n),
+            None => from_big(BigInt::from(x) - y),
+        },
+        Operands::Big(x, y) => from_big(x - y),
+        Operands::Rat(x, y) => from_rat(x - y),
+        Operands::Flo(x, y) => Expr::Float(x - y),
+    })
+}
+
+/// `(- a)`. Unlike `0 - a`, it keeps the sign of an inexact zero.
+pub fn neg(a: &Expr) -> Result<Expr, SchemeError> {
+    match *a {
+        Expr::Float(x) => Ok(Expr::Float(-x)),
+        _ => sub(&Expr::Int(0), a),
+    }
+}
+
+pub fn mul(a: &Expr, b: &Expr) -> Result<Expr, SchemeError> {
+    Ok(match promote(a, b)? {
+        Operands::Fix(x, y) => match x.checked_mul(y) {
+            Some(n) => Expr::Int(n),
+            None => from_big(BigInt::from(x) * y),
+        },
+        Operands::Big(x, y) => from_big(x * y),
+        Operands::Rat(x, y) => from_rat(x * y),
+        Operands::Flo(x, y) => Expr::Float(x * y),
+    })
+}
+
+/// Exact division yields a rational; the caller must rule out an exact
+/// zero divisor first (see `is_exact_zero`).
+pub fn div(a: &Expr, b: &Expr) -> Result<Expr, SchemeError> {
+    Ok(match promote(a, b)? {
+        Operands::Fix(x, y) => from_rat(BigRational::new(BigInt::from(x), BigInt::from(y))),
+        Operands::Big(x, y) => from_rat(BigRational::new(x, y)),
+        Operands::Rat(x, y) => from_rat(x / y),
+        Operands::Flo(x, y) => Expr::Float(x / y),
+    })
+}
+
+/// `quotient`, `remainder` and `modulo` on integers, exact or inexact. The
+/// caller must rule out a zero divisor first (see `is_zero`).
+pub fn int_div(op: IntDiv, a: &Expr, b: &Expr) -> Result<Expr, SchemeError> {
+    for exp in &[a, b] {
+        if !exp.is_integer() {
+            return Err(SchemeError::wrong_type("integer", exp));
+        }
+    }
+    Ok(match promote(a, b)? {
+        Operands::Fix(x, y) if y != -1 => Expr::Int(match op {
+            IntDiv::Quotient => x / y,
+            IntDiv::Remainder => x % y,
+            IntDiv::Modulo => x.mod_floor(&y),
+        }),
+        Operands::Fix(x, _) => match op {
+            IntDiv::Quotient => from_big(-BigInt::from(x)),
+            _ => Expr::Int(0),
+        },
+        Operands::Big(x, y) => from_big(match op {
+            IntDiv::Quotient => x / y,
+            IntDiv::Remainder => x % y,
+            IntDiv::Modulo => x.mod_floor(&y),
+        }),
+        Operands::Flo(x, y) => Expr::Float(match op {
+            IntDiv::Quotient => (x / y).trunc(),
+            IntDiv::Remainder => x % y,
+            IntDiv::Modulo => x - y * (x / y).floor(),
+        }),
+        Operands::Rat(_, _) => unreachable!("rationals are never integers"),
+    })
+}
+
+/// Numeric ordering, or `None` when a NaN is involved.
+pub fn compare(a: &Expr, b: &Expr) -> Result<Option<Ordering>, SchemeError> {
+    Ok(match promote(a, b)? {
+        Operands::Fix(x, y) => Some(x.cmp(&y)),
+        Operands::Big(x, y) => Some(x.cmp(&y)),
+        Operands::Rat(x, y) => Some(x.cmp(&y)),
+        Operands::Flo(x, y) => x.partial_cmp(&y),
+    })
+}
+
+pub fn is_exact_zero(exp: &Expr) -> bool {
+    matches!(*exp, Expr::Int(0))
+}
+
+/// Whether `exp` is zero, exact or inexact. Unlike `/`, integer division
+/// fails on an inexact zero too.
+pub fn is_zero(exp: &Expr) -> bool {
+    match *exp {
+        Expr::Int(0) => true,
+        Expr::Float(f) => f == 0.0,
+        _ => false,
+    }
+}
+
+pub fn is_exact(exp: &Expr) -> Result<bool, SchemeError> {
+    Ok(rank(exp)? < 3)
+}
+
+pub fn is_integer(exp: &Expr) -> bool {
+    match *exp {
+        Expr::Int(_) | Expr::BigInt(_) => true,
+        Expr::Float(f) => f.is_finite() && f.fract() == 0.0,
+        _ => false,
+    }
+}
+
+pub fn to_inexact(exp: &Expr) -> Result<Expr, SchemeError> {
+    rank(exp)?;
+    Ok(Expr::Float(to_f64(exp)))
+}
+
+pub fn to_exact(exp: &Expr) -> Result<Expr, SchemeError> {
+    match *exp {
+        Expr::Float(f) => match BigRational::from_f64(f) {
+            Some(r) => Ok(from_rat(r)),
+            None => Err(SchemeError::wrong_type("finite number", exp)),
+        },
+        _ => {
+            rank(exp)?;
+            Ok(exp.clone())
+        }
+    }
+}
+
+/// `(expt base power)`: exact when the base is exact and the power is an
+/// exact integer, inexact otherwise. `None` when no number here can hold
+/// the result: an exact power too large to compute, or a complex root.
+pub fn expt(base: &Expr, power: &Expr) -> Result<Option<Expr>, SchemeError> {
+    let exact = is_exact(base)? && is_exact(power)? && power.is_integer();
+    if exact {
+        let p = to_big(power);
+        let base = to_rat(base);
+        if base.is_zero() && p.is_negative() {
+            return Err(SchemeError::DivisionByZero(Expr::new_pair(
+                Expr::new_sym("expt"),
+                Expr::new_pair(Expr::Int(0), Expr::new_pair(power.clone(), Expr::Nil)),
+            )));
+        }
+        return Ok(match p.to_i32() {
+            Some(p) => Some(from_rat(base.pow(p))),
+            // only 0, 1 and -1 have powers this large that fit in memory
+            None if base.is_zero() => Some(Expr::Int(0)),
+            None if base.is_one() => Some(Expr::Int(1)),
+            None if (-base).is_one() => Some(Expr::Int(if p.is_even() { 1 } else { -1 })),
+            None => None,
+        });
+    }
+    let (base, power) = (to_f64(base), to_f64(power));
+    let res = base.powf(power);
+    if res.is_nan() && !base.is_nan() && !power.is_nan() {
+        return Ok(None);
+    }
+    Ok(Some(Expr::Float(res)))
+}
+
+/// Exact square roots of exact perfect squares stay exact. `None` for a
+/// negative number, whose roots are complex.
+pub fn sqrt(exp: &Expr) -> Result<Option<Expr>, SchemeError> {
+    fn exact_root(n: &BigInt) -> Option<BigInt> {
+        if n.is_negative() {
+            return None;
+        }
+        let root = n.sqrt();
+        if &root * &root == *n {
+            Some(root)
+        } else {
+            None
+        }
+    }
+
+    if rank(exp)? < 3 {
+        let r = to_rat(exp);
+        if let (Some(n), Some(d)) = (exact_root(r.numer()), exact_root(r.denom())) {
+            return Ok(Some(from_rat(BigRational::new(n, d))));
+        }
+    }
+    let f = to_f64(exp);
+    if f < 0.0 {
+        return Ok(None);
+    }
+    Ok(Some(Expr::Float(f.sqrt())))
+}
+
+pub fn to_string_radix(exp: &Expr, radix: u32) -> Result<String, SchemeError> {
+    match *exp {
+        Expr::Float(f) if radix == 10 => Ok(format_float(f)),
+        Expr::Float(_) => Err(SchemeError::wrong_type("exact number", exp)),
+        Expr::Int(_) | Expr::BigInt(_) => Ok(to_big(exp).to_str_radix(radix)),
+        Expr::Rational(ref r) => Ok(format!(
+            "{}/{}",
+            r.numer().to_str_radix(radix),
+            r.denom().to_str_radix(radix)
+        )),
+        _ => Err(SchemeError::wrong_type("number", exp)),
+    }
+}
+
+/// Writes a flonum so that it always reads back as inexact: `1.0` rather
+/// than `1`, and `+inf.0`/`+nan.0` for the special values.
+pub fn format_float(f: f64) -> String {
+    if f.is_nan() {
+        "+nan.0".to_string()
+    } else if f.is_infinite() {
+        if f > 0.0 { "+inf.0" } else { "-inf.0" }.to_string()
+    } else if f.fract() == 0.0 && f.abs() < 1e16 {
+        format!("{:.1}", f)
+    } else if f.fract() == 0.0 {
+        format!("{:e}", f)
+    } else {
+        format!("{}", f)
+    }
+}
+
+/// Parses a numeric literal in the given radix, or returns `None` if the
+/// token is not a number (and so should be read as a symbol).
+pub fn parse_number(token: &str, radix: u32) -> Option<Expr> {
+    match token {
+        "+inf.0" => return Some(Expr::Float(f64::INFINITY)),
+        "-inf.0" => return Some(Expr::Float(f64::NEG_INFINITY)),
+        "+nan.0" | "-nan.0" => return Some(Expr::Float(f64::NAN)),
+        _ => {}
+    }
+    if let Some(pos) = token.find('/') {
+        let numer = parse_integer(&token[..pos], radix, true)?;
+        let denom = parse_integer(&token[pos + 1..], radix, false)?;
+        if denom.is_zero() {
+            return None;
+        }
+        return Some(from_rat(BigRational::new(numer, denom)));
+    }
+    if let Some(n) = parse_integer(token, radix, true) {
+        return Some(from_big(n));
+    }
+    let decimal = radix == 10
+        && token.chars().any(|c| c.is_ascii_digit())
+        && token
+            .chars()
+            .all(|c| c.is_ascii_digit() || matches!(c, '+' | '-' | '.' | 'e' | 'E'));
+    if decimal {
+        token.parse::<f64>().ok().map(Expr::Float)
+    } else {
+        None
+    }
+}
+
+fn parse_integer(token: &str, radix: u32, signed: bool) -> Option<BigInt> {
+    let digits = if signed {
+        token.trim_start_matches(['+', '-'])
+    } else {
+        token
+    };
+    if token.len() - digits.len() > 1
+        || digits.is_empty()
+        || !digits.chars().all(|c| c.is_digit(radix))
+    {
+        return None;
+    }
+    BigInt::from_str_radix(token, radix).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn num(s: &str) -> Expr {
+        parse_number(s, 10).unwrap()
+    }
+
+    #[test]
+    fn test_parse_number() {
+        assert_eq!(num("42"), Expr::Int(42));
+        assert_eq!(num("-7"), Expr::Int(-7));
+        assert_eq!(num("+7"), Expr::Int(7));
+        assert_eq!(num("3.25"), Expr::Float(3.25));
+        assert_eq!(num("1e10"), Expr::Float(1e10));
+        assert_eq!(num(".5"), Expr::Float(0.5));
+        assert_eq!(num("4/2"), Expr::Int(2));
+        assert_eq!(num("1/3").to_string(), "1/3");
+        assert_eq!(num("-2/6").to_string(), "-1/3");
+        assert!(num("123456789012345678901234567890").is_number());
+        assert_eq!(parse_number("ff", 16), Some(Expr::Int(255)));
+        assert_eq!(parse_number("101", 2), Some(Expr::Int(5)));
+        for bad in &[
+            "-", "+", "...", "1/0", "1/-2", "1+", "e10", "inf", "nan", "--1", "1_0",
+        ] {
+            assert_eq!(parse_number(bad, 10), None, "{}", bad);
+        }
+    }
+
+    #[test]
+    fn test_contagion() {
+        assert_eq!(add(&num("1"), &num("1/2")).unwrap().to_string(), "3/2");
+        assert_eq!(add(&num("1/2"), &num("1/2")).unwrap(), Expr::Int(1));
+        assert_eq!(add(&num("1/2"), &num("0.5")).unwrap(), Expr::Float(1.0));
+        assert_eq!(mul(&num("2"), &num("1.5")).unwrap(), Expr::Float(3.0));
+        assert_eq!(div(&num("6"), &num("4")).unwrap().to_string(), "3/2");
+        assert_eq!(div(&num("6"), &num("3")).unwrap(), Expr::Int(2));
+        assert!(add(&num("1"), &Expr::Bool(true)).is_err());
+    }
+
+    #[test]
+    fn test_overflow_promotes() {
+        let max = Expr::Int(isize::MAX);
+        let big = add(&max, &Expr::Int(1)).unwrap();
+        assert!(matches!(big, Expr::BigInt(_)));
+        assert_eq!(sub(&big, &Expr::Int(1)).unwrap(), max);
+        let min = Expr::Int(isize::MIN);
+        assert!(matches!(
+            int_div(IntDiv::Quotient, &min, &Expr::Int(-1)).unwrap(),
+            Expr::BigInt(_)
+        ));
+        assert_eq!(
+            int_div(IntDiv::Modulo, &min, &Expr::Int(-1)).unwrap(),
+            Expr::Int(0)
+        );
+    }
+
+    #[test]
+    fn test_compare() {
+        assert_eq!(
+            compare(&num("1/3"), &num("0.3")).unwrap(),
+            Some(Ordering::Greater)
+        );
+        assert_eq!(
+            compare(&num("2"), &num("2.0")).unwrap(),
+            Some(Ordering::Equal)
+        );
+        assert_eq!(compare(&num("+nan.0"), &num("1")).unwrap(), None);
+    }
+
+    #[test]
+    fn test_format_float() {
+        assert_eq!(format_float(1.0), "1.0");
+        assert_eq!(format_float(-0.5), "-0.5");
+        assert_eq!(format_float(3.25), "3.25");
+        assert_eq!(format_float(1e21), "1e21");
+        assert_eq!(format_float(f64::INFINITY), "+inf.0");
+        assert_eq!(format_float(f64::NAN), "+nan.0");
+    }
+}
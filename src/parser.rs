@@ -1,7 +1,12 @@
 use ast::*;
+use error::SchemeError;
+use num;
 #[cfg(test)]
 use std;
+use std::cell::RefCell;
 use std::io::Read;
+use std::rc::Rc;
+use std::str;
 
 #[derive(Clone, Debug)]
 pub struct Parser<R> {
@@ -9,19 +14,39 @@ pub struct Parser<R> {
     cur: usize,
     col: usize,
     line: usize,
-    iteractive: bool,
+    file: Option<Rc<str>>,
+    /// The span of the datum `read_exp` returned last.
+    last_span: Option<Rc<Span>>,
+    fold_case: bool,
+    interactive: bool,
     inner: R,
+    /// Bytes read from `inner` that do not make a whole character yet.
+    partial: Vec<u8>,
 }
 
+/// One element of the token stream inside a list: a complete datum, or
+/// one of the two tokens that only make sense there.
+enum Item {
+    Datum(Expr),
+    Close,
+    Dot,
+}
+
+type Mark = (usize, usize, usize);
+
 impl<R: Read> Parser<R> {
-    pub fn new_from(inner: R, iteractive: bool) -> Parser<R> {
+    pub fn new_from(inner: R, interactive: bool) -> Parser<R> {
         Parser {
             code: "".to_string(),
             line: 0,
             cur: 0,
             col: 0,
-            iteractive: iteractive,
-            inner: inner,
+            file: None,
+            last_span: None,
+            fold_case: false,
+            interactive,
+            inner,
+            partial: vec![],
         }
     }
 
@@ -30,192 +55,462 @@ impl<R: Read> Parser<R> {
         self.line = 1;
         self.cur = 0;
         self.col = 0;
+        self.file = None;
+        self.last_span = None;
+        self.fold_case = false;
+    }
+
+    /// Like `load`, but pairs read from `code` carry spans naming `file`.
+    pub fn load_file(&mut self, file: &str, code: String) {
+        self.load(code);
+        self.file = Some(Rc::from(file));
     }
 
-    pub fn read_exp(&mut self) -> Option<Expr> {
+    /// Reads the next datum, or `None` once only whitespace and comments
+    /// are left. Malformed input is reported as `ReadError`, and input
+    /// that stops in the middle of a datum as `UnexpectedEof`.
+    pub fn read_exp(&mut self) -> Result<Option<Expr>, SchemeError> {
+        self.skip_atmosphere()?;
         if self.eof() {
-            return None;
+            return Ok(None);
         }
-        self.skip_space();
-        let mut cur = self.readc();
-        if cur == '#' {
-            let next = self.readc();
-            match next {
-                't' => return Some(Expr::Bool(true)),
-                'f' => return Some(Expr::Bool(false)),
-                '\\' => return self.read_char(),
-                _ => panic!("error"),
-            }
-        } else if cur.is_numeric() || (cur == '-' && (self.peekc().is_numeric())) {
-            let mut sign = 1isize;
-            let mut num = 0isize;
-            if cur == '-' {
-                sign = -1;
-            } else {
-                self.unread();
-            }
-            loop {
-                cur = self.readc();
-                if !cur.is_numeric() {
-                    break;
+        let start = self.mark();
+        let exp = self.read_datum()?;
+        self.last_span = self.span_from(start);
+        Ok(Some(exp))
+    }
+
+    /// The span of the datum the last `read_exp` returned, when reading
+    /// from a named source. Unlike `Expr::span`, it locates atoms too.
+    pub fn last_span(&self) -> Option<Rc<Span>> {
+        self.last_span.clone()
+    }
+
+    //============= private methods =================
+    fn read_datum(&mut self) -> Result<Expr, SchemeError> {
+        self.skip_atmosphere()?;
+        let start = self.mark();
+        match self.read_item()? {
+            Item::Datum(exp) => Ok(exp),
+            Item::Close => Err(self.error("unexpected ')'", start)),
+            Item::Dot => Err(self.error("unexpected '.'", start)),
+        }
+    }
+
+    fn read_item(&mut self) -> Result<Item, SchemeError> {
+        self.skip_atmosphere()?;
+        let start = self.mark();
+        if self.eof() {
+            return Err(SchemeError::UnexpectedEof.at(self.span_from(start)));
+        }
+        let exp = match self.readc() {
+            '(' => self.read_list(start)?,
+            ')' => return Ok(Item::Close),
+            '\'' => self.read_abbrev("quote", start)?,
+            '`' => self.read_abbrev("quasiquote", start)?,
+            ',' => {
+                if self.peekc() == '@' {
+                    self.readc();
+                    self.read_abbrev("unquote-splicing", start)?
+                } else {
+                    self.read_abbrev("unquote", start)?
                 }
-                num = (num * 10isize) + (cur as isize - '0' as isize);
             }
-            num *= sign;
-            if self.is_delimiter(cur) {
-                self.unread();
-            }
-            return Some(Expr::Int(num));
-        } else if cur == '\"' {
-            let mut buf = String::new();
-            loop {
-                cur = self.readc();
-                if cur == '\"' {
-                    break;
+            '"' => Expr::new_str(&self.read_escaped('"', start)?),
+            '|' => Expr::new_sym(&self.read_escaped('|', start)?),
+            '#' => self.read_hash(start)?,
+            cur => {
+                let token = self.read_token(cur);
+                if token == "." {
+                    return Ok(Item::Dot);
                 }
-                buf.push(cur);
-            }
-            return Some(Expr::new_str(buf.trim()));
-        } else if cur == '(' && cur != ')' {
-            return self.read_pair();
-        } else if self.is_initial(cur) {
-            let mut buf = String::new();
-            buf.push(cur);
-            loop {
-                cur = self.readc();
-                if !(self.is_initial(cur) || cur.is_numeric()) {
-                    break;
+                match num::parse_number(&token, 10) {
+                    Some(exp) => exp,
+                    None if self.fold_case => Expr::new_sym(&token.to_lowercase()),
+                    None => Expr::new_sym(&token),
                 }
-                buf.push(cur);
             }
-            if self.is_delimiter(cur) {
-                self.unread();
-            }
-            return Some(Expr::new_sym(buf.trim()));
-        } else if cur == '\'' {
-            let quote_sym = Expr::new_sym("quote");
-            let quote_exp = Expr::new_pair(self.read_exp().unwrap(), Expr::Nil);
-            return Some(Expr::new_pair(quote_sym, quote_exp));
-        }
-        None
+        };
+        Ok(Item::Datum(exp))
     }
 
-    //============= private methods =================
-    fn read_pair(&mut self) -> Option<Expr> {
-        self.skip_space();
-        let mut cur = self.readc();
-        if cur == ')' {
-            return Some(Expr::Nil);
+    /// Reads the rest of a list after its `(`, including dotted tails.
+    /// Every pair gets the span from its element to the closing paren; the
+    /// first one starts at the paren itself.
+    fn read_list(&mut self, start: Mark) -> Result<Expr, SchemeError> {
+        let mut items = vec![];
+        let mut tail = Expr::Nil;
+        loop {
+            self.skip_atmosphere()?;
+            let item_start = self.mark();
+            match self.read_item()? {
+                Item::Close => break,
+                Item::Datum(exp) => items.push((exp, item_start)),
+                Item::Dot => {
+                    if items.is_empty() {
+                        return Err(self.error("bad dotted list", item_start));
+                    }
+                    tail = self.read_datum()?;
+                    self.skip_atmosphere()?;
+                    let close = self.mark();
+                    match self.read_item()? {
+                        Item::Close => break,
+                        _ => return Err(self.error("bad dotted list", close)),
+                    }
+                }
+            }
         }
-        self.unread();
-        let car_obj = self.read_exp();
-        self.skip_space();
-        cur = self.readc();
-        if cur != '.' {
-            self.unread();
-            let cdr_obj = self.read_pair();
-            return Some(Expr::new_pair(car_obj.unwrap(), cdr_obj.unwrap()));
-        } else {
-            return Some(Expr::Nil);
+        if let Some(first) = items.first_mut() {
+            first.1 = start;
         }
+        Ok(items.into_iter().rev().fold(tail, |acc, (exp, mark)| {
+            self.located(Expr::new_pair(exp, acc), mark)
+        }))
     }
 
-    fn is_delimiter(&self, ch: char) -> bool {
-        ch.is_whitespace() || {
-            match ch {
-                '\"' | '(' | ')' | ';' => true,
-                _ => false,
+    /// Reads the elements of a `#(...)` or `#u8(...)` literal.
+    fn read_elements(&mut self) -> Result<Vec<Expr>, SchemeError> {
+        let mut items = vec![];
+        loop {
+            self.skip_atmosphere()?;
+            let item_start = self.mark();
+            match self.read_item()? {
+                Item::Close => return Ok(items),
+                Item::Datum(exp) => items.push(exp),
+                Item::Dot => return Err(self.error("unexpected '.'", item_start)),
             }
         }
     }
 
-    fn is_initial(&self, ch: char) -> bool {
-        ch.is_alphabetic() || {
-            match ch {
-                '*' | '/' | '+' | '-' | '>' | '<' | '=' | '?' | '!' => true,
-                _ => false,
-            }
-        }
+    fn read_abbrev(&mut self, name: &str, start: Mark) -> Result<Expr, SchemeError> {
+        let exp = Expr::new_pair(self.read_datum()?, Expr::Nil);
+        Ok(self.located(Expr::new_pair(Expr::new_sym(name), exp), start))
     }
 
-    fn skip_space(&mut self) {
-        while self.peekc().is_whitespace() {
-            self.readc();
+    /// Reads the characters up to the next delimiter, starting with `first`.
+    fn read_token(&mut self, first: char) -> String {
+        let mut buf = first.to_string();
+        while !self.eof() && !is_delimiter(self.peekc()) {
+            buf.push(self.readc());
         }
+        buf
     }
 
-    fn eof(&mut self) -> bool {
-        if self.iteractive {
-            let mut vec: Vec<u8> = Vec::new();
-            match self.inner.read(&mut vec) {
-                Ok(_) => {
-                    for i in vec.into_iter() {
-                        self.code.push(i as char);
+    /// Reads the body of a string or `|symbol|` up to the closing `quote`,
+    /// handling R7RS escapes.
+    fn read_escaped(&mut self, quote: char, start: Mark) -> Result<String, SchemeError> {
+        let mut buf = String::new();
+        loop {
+            if self.eof() {
+                return Err(SchemeError::UnexpectedEof.at(self.span_from(start)));
+            }
+            let escape = self.mark();
+            let cur = self.readc();
+            if cur == quote {
+                return Ok(buf);
+            }
+            if cur != '\\' {
+                buf.push(cur);
+                continue;
+            }
+            match self.readc() {
+                'a' => buf.push('\u{7}'),
+                'b' => buf.push('\u{8}'),
+                't' => buf.push('\t'),
+                'n' => buf.push('\n'),
+                'r' => buf.push('\r'),
+                'x' | 'X' => {
+                    let mut hex = String::new();
+                    while !self.eof() && self.peekc() != ';' && hex.len() < 8 {
+                        hex.push(self.readc());
+                    }
+                    if self.readc() != ';' {
+                        return Err(self.error("missing ';' after \\x escape", escape));
+                    }
+                    match u32::from_str_radix(&hex, 16)
+                        .ok()
+                        .and_then(::std::char::from_u32)
+                    {
+                        Some(ch) => buf.push(ch),
+                        None => return Err(self.error("bad \\x escape", escape)),
+                    }
+                }
+                ch if ch == '"' || ch == '\\' || ch == '|' => buf.push(ch),
+                ch if ch == ' ' || ch == '\t' || ch == '\n' => {
+                    // a line continuation: \, optional blanks, a newline
+                    // and the leading blanks of the next line vanish
+                    let mut ch = ch;
+                    while ch == ' ' || ch == '\t' {
+                        ch = self.readc();
+                    }
+                    if ch != '\n' {
+                        return Err(self.error("bad line continuation", escape));
+                    }
+                    while self.peekc() == ' ' || self.peekc() == '\t' {
+                        self.readc();
                     }
                 }
-                Err(_) => {
-                    return true;
+                '\0' if self.eof() => {
+                    return Err(SchemeError::UnexpectedEof.at(self.span_from(start)))
                 }
+                ch => return Err(self.error(&format!("unknown escape \\{}", ch), escape)),
             }
         }
-        self.cur >= self.code.len()
     }
 
-    fn peekc(&mut self) -> char {
-        if self.cur >= self.code.len() {
-            if self.iteractive {
-                let mut vec: Vec<u8> = Vec::new();
-                match self.inner.read(&mut vec) {
-                    Ok(_) => {
-                        for i in vec.into_iter() {
-                            self.code.push(i as char);
-                        }
+    /// Reads whatever follows a `#`: booleans, characters, vectors,
+    /// bytevectors and numbers with radix or exactness prefixes.
+    fn read_hash(&mut self, start: Mark) -> Result<Expr, SchemeError> {
+        match self.readc() {
+            '(' => {
+                let items = self.read_elements()?;
+                Ok(Expr::new_vector(items))
+            }
+            '\\' => self.read_char(start),
+            'u' if self.peekc() == '8' => {
+                self.readc();
+                if self.readc() != '(' {
+                    return Err(self.error("bad bytevector syntax", start));
+                }
+                let mut bytes = vec![];
+                for item in self.read_elements()? {
+                    match item {
+                        Expr::Int(n) if (0..256).contains(&n) => bytes.push(n as u8),
+                        _ => return Err(self.error("bytevector element is not a byte", start)),
                     }
-                    Err(_) => {
-                        return 0 as char;
+                }
+                Ok(Expr::Bytevector(Rc::new(RefCell::new(bytes))))
+            }
+            cur => {
+                let token = self.read_token(cur);
+                match token.to_lowercase().as_str() {
+                    "t" | "true" => return Ok(Expr::Bool(true)),
+                    "f" | "false" => return Ok(Expr::Bool(false)),
+                    _ => {}
+                }
+                match parse_prefixed_number(&format!("#{}", token), 10) {
+                    Some(exp) => Ok(exp),
+                    None => Err(self.error(&format!("bad syntax #{}", token), start)),
+                }
+            }
+        }
+    }
+
+    fn read_char(&mut self, start: Mark) -> Result<Expr, SchemeError> {
+        if self.eof() {
+            return Err(SchemeError::UnexpectedEof.at(self.span_from(start)));
+        }
+        let first = self.readc();
+        let mut name = self.read_token(first);
+        if name.chars().count() == 1 {
+            return Ok(Expr::Char(first));
+        }
+        if self.fold_case {
+            name = name.to_lowercase();
+        }
+        let named = match name.as_str() {
+            "alarm" => Some('\u{7}'),
+            "backspace" => Some('\u{8}'),
+            "delete" => Some('\u{7f}'),
+            "escape" => Some('\u{1b}'),
+            "newline" => Some('\n'),
+            "null" => Some('\0'),
+            "return" => Some('\r'),
+            "space" => Some(' '),
+            "tab" => Some('\t'),
+            _ if first == 'x' || first == 'X' => u32::from_str_radix(&name[1..], 16)
+                .ok()
+                .and_then(::std::char::from_u32),
+            _ => None,
+        };
+        match named {
+            Some(ch) => Ok(Expr::Char(ch)),
+            None => Err(self.error(&format!("unknown character #\\{}", name), start)),
+        }
+    }
+
+    /// Skips whitespace, `;` line comments, nestable `#| |#` block
+    /// comments, `#;` datum comments, the `#!fold-case` and
+    /// `#!no-fold-case` directives, and a `#!/` or `#! ` line at the very
+    /// start, so a script can name its interpreter.
+    fn skip_atmosphere(&mut self) -> Result<(), SchemeError> {
+        loop {
+            let cur = self.peekc();
+            if cur.is_whitespace() {
+                self.readc();
+            } else if cur == ';' || self.cur == 0 && self.at_shebang() {
+                while !self.eof() && self.peekc() != '\n' {
+                    self.readc();
+                }
+            } else if cur == '#' && self.peek_next() == '|' {
+                self.skip_block_comment()?;
+            } else if cur == '#' && self.peek_next() == ';' {
+                self.readc();
+                self.readc();
+                self.read_datum()?;
+            } else if cur == '#' && self.peek_next() == '!' {
+                let start = self.mark();
+                self.readc();
+                let bang = self.readc();
+                let directive = self.read_token(bang);
+                match directive.as_str() {
+                    "!fold-case" => self.fold_case = true,
+                    "!no-fold-case" => self.fold_case = false,
+                    _ => {
+                        return Err(self.error(&format!("unknown directive #{}", directive), start))
                     }
                 }
+            } else {
+                return Ok(());
             }
         }
-        if self.cur >= self.code.len() {
-            return 0 as char;
+    }
+
+    fn at_shebang(&self) -> bool {
+        self.code.starts_with("#!/") || self.code.starts_with("#! ")
+    }
+
+    fn skip_block_comment(&mut self) -> Result<(), SchemeError> {
+        let start = self.mark();
+        self.readc();
+        self.readc();
+        let mut depth = 1;
+        while depth > 0 {
+            if self.eof() {
+                return Err(SchemeError::UnexpectedEof.at(self.span_from(start)));
+            }
+            let cur = self.readc();
+            if cur == '|' && self.peekc() == '#' {
+                self.readc();
+                depth -= 1;
+            } else if cur == '#' && self.peekc() == '|' {
+                self.readc();
+                depth += 1;
+            }
+        }
+        Ok(())
+    }
+
+    /// The current position as (byte offset, line, column).
+    fn mark(&self) -> Mark {
+        (self.cur, self.line, self.col + 1)
+    }
+
+    /// The span from `start` to the current position, when reading from a
+    /// named source.
+    fn span_from(&self, start: Mark) -> Option<Rc<Span>> {
+        let file = match self.file {
+            Some(ref file) => file.clone(),
+            None => return None,
+        };
+        let (start, line, col) = start;
+        Some(Rc::new(Span {
+            file,
+            line,
+            col,
+            start,
+            end: self.cur,
+        }))
+    }
+
+    /// Attaches the span from `start` to the current position to the pair
+    /// `exp`.
+    fn located(&self, exp: Expr, start: Mark) -> Expr {
+        match exp {
+            Expr::Pair(vec, _) => Expr::Pair(vec, Loc(self.span_from(start))),
+            exp => exp,
+        }
+    }
+
+    fn error(&self, msg: &str, start: Mark) -> SchemeError {
+        SchemeError::ReadError(msg.to_string()).at(self.span_from(start))
+    }
+    fn eof(&mut self) -> bool {
+        !self.fill()
+    }
+
+    /// Whether there is input left to read. In interactive mode, once the
+    /// code read so far is used up, this waits for more from `inner`.
+    fn fill(&mut self) -> bool {
+        let mut buf = [0u8; 1024];
+        while self.cur >= self.code.len() && self.interactive {
+            let n = match self.inner.read(&mut buf) {
+                Ok(0) | Err(_) => return false,
+                Ok(n) => n,
+            };
+            self.partial.extend_from_slice(&buf[..n]);
+            let valid = match str::from_utf8(&self.partial) {
+                Ok(text) => text.len(),
+                Err(err) if err.error_len().is_none() => err.valid_up_to(),
+                Err(_) => self.partial.len(),
+            };
+            let text = String::from_utf8_lossy(&self.partial[..valid]).into_owned();
+            self.code.push_str(&text);
+            self.partial.drain(..valid);
         }
-        self.code.chars().nth(self.cur).unwrap()
+        self.cur < self.code.len()
     }
 
-    fn prevc(&self) -> char {
-        if self.cur <= 0 {
-            panic!("invalid position");
+    fn peekc(&mut self) -> char {
+        if !self.fill() {
+            return 0 as char;
+        }
+        match self.code[self.cur..].chars().next() {
+            Some(ch) => ch,
+            None => 0 as char,
         }
-        self.code.chars().nth(self.cur - 1).unwrap()
     }
 
+    /// Reads the next character; `cur` is a byte offset into `code`.
     fn readc(&mut self) -> char {
-        if self.cur < self.code.len() {
+        if self.fill() {
             let res = self.peekc();
             if res == '\n' {
                 self.line += 1;
+                self.col = 0;
+            } else {
+                self.col += 1;
             }
-            self.cur += 1;
+            self.cur += res.len_utf8();
             res
         } else {
             0 as char
         }
     }
 
-    fn unread(&mut self) {
-        if self.cur == 0 {
-            panic!("error current position");
-        }
-        if self.prevc() == '\n' {
-            assert!(self.line >= 1);
-            self.line -= 1;
-        }
-        self.cur -= 1;
+    fn peek_next(&self) -> char {
+        self.code[self.cur..].chars().nth(1).unwrap_or(0 as char)
     }
+}
+
+fn is_delimiter(ch: char) -> bool {
+    ch.is_whitespace() || matches!(ch, '|' | '\"' | '(' | ')' | ';')
+}
 
-    fn read_char(&mut self) -> Option<Expr> {
-        Some(Expr::Char('a'))
+/// Parses a number with `#x`/`#b`/`#o`/`#d` radix and `#e`/`#i`
+/// exactness prefixes, such as `#x1F` or `#e1.5`. Without a radix prefix
+/// the digits are read in `radix`.
+pub fn parse_prefixed_number(token: &str, radix: u32) -> Option<Expr> {
+    let mut radix = radix;
+    let mut exact = None;
+    let mut rest = token;
+    while rest.starts_with('#') {
+        match rest[1..].chars().next()?.to_ascii_lowercase() {
+            'x' => radix = 16,
+            'b' => radix = 2,
+            'o' => radix = 8,
+            'd' => radix = 10,
+            'e' => exact = Some(true),
+            'i' => exact = Some(false),
+            _ => return None,
+        }
+        rest = &rest[2..];
+    }
+    let exp = num::parse_number(rest, radix)?;
+    match exact {
+        Some(true) => num::to_exact(&exp).ok(),
+        Some(false) => num::to_inexact(&exp).ok(),
+        None => Some(exp),
     }
 }
 
@@ -225,8 +520,8 @@ fn test_parser() {
         ($test_str:expr, $expect_type:ident, $expect_val:expr) => {{
             let mut parser = Parser::new_from(std::io::stdin(), false);
             parser.load($test_str.to_string());
-            let res = parser.read_exp().unwrap();
-            if res.$expect_type() != $expect_val {
+            let res = parser.read_exp().unwrap().unwrap();
+            if res.$expect_type().unwrap() != $expect_val {
                 assert!(false);
             }
         }};
@@ -236,12 +531,28 @@ fn test_parser() {
         ($test_str:expr) => {{
             let mut parser = Parser::new_from(std::io::stdin(), false);
             parser.load($test_str.to_string());
-            parser.read_exp().unwrap()
+            parser.read_exp().unwrap().unwrap()
         }};
     }
 
     test_case!("11", as_int, 11);
     test_case!("-11", as_int, -11);
+    test_case!("+11", as_int, 11);
+
+    assert_eq!(test_res!("3.25"), Expr::Float(3.25));
+    assert_eq!(test_res!("-2.5e3"), Expr::Float(-2500.0));
+    assert_eq!(test_res!("1e10"), Expr::Float(1e10));
+    assert_eq!(test_res!("1/3").to_string(), "1/3");
+    assert_eq!(test_res!("6/4").to_string(), "3/2");
+    assert_eq!(
+        test_res!("123456789012345678901234567890").to_string(),
+        "123456789012345678901234567890"
+    );
+    assert_eq!(test_res!("1+"), Expr::new_sym("1+"));
+
+    let res = test_res!("(1.5 2/3)");
+    assert_eq!(res.car().unwrap(), Expr::Float(1.5));
+    assert_eq!(res.c("da").unwrap().to_string(), "2/3");
     test_case!(r#""hello""#, as_str, "hello");
 
     let res = test_res!("()");
@@ -249,13 +560,178 @@ fn test_parser() {
 
     let res = test_res!("(1 2)");
     assert!(res.is_pair());
-    assert!(res.car().as_int() == 1);
-    assert!(res.cdr().car().as_int() == 2);
-    assert!(res.cdr().cdr().is_empty());
+    assert!(res.car().unwrap().as_int().unwrap() == 1);
+    assert!(res.c("da").unwrap().as_int().unwrap() == 2);
+    assert!(res.c("dd").unwrap().is_empty());
 
     let res = test_res!("(+ 1 2)");
     assert!(res.is_pair());
-    assert!(res.car().is_sym());
-    assert!(res.cdr().car().as_int() == 1);
-    assert!(res.cdr().cdr().car().as_int() == 2);
+    assert!(res.car().unwrap().is_sym());
+    assert!(res.c("da").unwrap().as_int().unwrap() == 1);
+    assert!(res.c("dda").unwrap().as_int().unwrap() == 2);
+
+    assert_eq!(test_res!("(_ x ...)").to_string(), "(_ x ...)");
+    assert_eq!(test_res!("(a . b)").to_string(), "(a . b)");
+    assert_eq!(test_res!("(a b . (c))").to_string(), "(a b c)");
+}
+
+#[test]
+fn test_parser_r7rs() {
+    fn read_all(code: &str) -> Result<Vec<Expr>, SchemeError> {
+        let mut parser = Parser::new_from(std::io::stdin(), false);
+        parser.load(code.to_string());
+        let mut res = vec![];
+        while let Some(exp) = parser.read_exp()? {
+            res.push(exp);
+        }
+        Ok(res)
+    }
+    fn read_one(code: &str) -> Expr {
+        let mut res = read_all(code).unwrap();
+        assert_eq!(res.len(), 1);
+        res.pop().unwrap()
+    }
+
+    assert_eq!(read_one("#\\a"), Expr::Char('a'));
+    assert_eq!(read_one("#\\space"), Expr::Char(' '));
+    assert_eq!(read_one("#\\x41"), Expr::Char('A'));
+    assert_eq!(read_one("#\\x"), Expr::Char('x'));
+    assert_eq!(read_one("#\\("), Expr::Char('('));
+    assert_eq!(read_one("#\\λ"), Expr::Char('λ'));
+    assert_eq!(read_one("#true"), Expr::Bool(true));
+    assert_eq!(read_one("#false"), Expr::Bool(false));
+
+    assert_eq!(
+        read_one(r#""a\tb\n\"q\" \\ \x3bb;""#),
+        Expr::new_str("a\tb\n\"q\" \\ λ")
+    );
+    assert_eq!(read_one("\"  padded  \""), Expr::new_str("  padded  "));
+    assert_eq!(read_one("\"one \\\n    two\""), Expr::new_str("one two"));
+    assert_eq!(read_one("|hello world|"), Expr::new_sym("hello world"));
+    assert_eq!(read_one("|a\\|b|"), Expr::new_sym("a|b"));
+
+    assert_eq!(read_one("#x1F"), Expr::Int(31));
+    assert_eq!(read_one("#b-101"), Expr::Int(-5));
+    assert_eq!(read_one("#e1.5").to_string(), "3/2");
+    assert_eq!(read_one("#i1/2"), Expr::Float(0.5));
+
+    let res = read_all("#!/usr/bin/env rust-scm\n1 #!fold-case A").unwrap();
+    assert_eq!(res, vec![Expr::Int(1), Expr::new_sym("a")]);
+    assert_eq!(read_all("#! rust-scm -i\n2").unwrap(), vec![Expr::Int(2)]);
+    assert!(read_all("1 #!/usr/bin/env rust-scm").is_err());
+
+    let res =
+        read_all("; comment\n1 #| block #| nested |# |# 2 #;(skipped datum) 3 ; end").unwrap();
+    assert_eq!(res, vec![Expr::Int(1), Expr::Int(2), Expr::Int(3)]);
+    assert_eq!(read_one("(1 #;2 3)").to_string(), "(1 3)");
+
+    assert_eq!(read_one("(1 . 2)").to_string(), "(1 . 2)");
+    assert_eq!(read_one("(1 2 . 3)").to_string(), "(1 2 . 3)");
+    assert_eq!(read_one("#(1 \"a\" (b))").to_string(), "#(1 \"a\" (b))");
+    assert!(read_one("#(1 2)").is_vector());
+    assert_eq!(read_one("#u8(0 255)").to_string(), "#u8(0 255)");
+    assert_eq!(
+        read_one("`(a ,b ,@c)").to_string(),
+        "(quasiquote (a (unquote b) (unquote-splicing c)))"
+    );
+
+    assert_eq!(read_one("#!fold-case ABC").to_string(), "abc");
+    assert_eq!(
+        read_one("#!fold-case #!no-fold-case ABC").to_string(),
+        "ABC"
+    );
+
+    // printing writes data back in a form the reader accepts
+    for code in &[
+        "\"tab\\there\"",
+        "#\\space",
+        "#\\newline",
+        "|two words|",
+        "#u8(1 2)",
+    ] {
+        assert_eq!(read_one(code).to_string(), *code);
+    }
+
+    let read_err = |code: &str| SchemeError::ReadError(code.to_string());
+    assert_eq!(read_all(")"), Err(read_err("unexpected ')'")));
+    assert_eq!(read_all("(1 . )"), Err(read_err("unexpected ')'")));
+    assert_eq!(read_all("( . 1)"), Err(read_err("bad dotted list")));
+    assert_eq!(read_all("(1 . 2 3)"), Err(read_err("bad dotted list")));
+    assert_eq!(
+        read_all("#\\bogus"),
+        Err(read_err("unknown character #\\bogus"))
+    );
+    assert_eq!(read_all("#q"), Err(read_err("bad syntax #q")));
+    assert_eq!(
+        read_all("#u8(256)"),
+        Err(read_err("bytevector element is not a byte"))
+    );
+    assert_eq!(read_all("\"\\q\""), Err(read_err("unknown escape \\q")));
+    assert_eq!(read_all("(1 2"), Err(SchemeError::UnexpectedEof));
+    assert_eq!(read_all("\"abc"), Err(SchemeError::UnexpectedEof));
+    assert_eq!(read_all("#| open"), Err(SchemeError::UnexpectedEof));
+    assert_eq!(read_all("'"), Err(SchemeError::UnexpectedEof));
+
+    let mut parser = Parser::new_from(std::io::stdin(), false);
+    parser.load_file("lib.scm", "(a\n  (b #\\nope))".to_string());
+    assert_eq!(
+        parser.read_exp().unwrap_err().to_string(),
+        "read error: unknown character #\\nope at lib.scm:2:6"
+    );
+}
+
+#[test]
+fn test_parser_spans() {
+    let mut parser = Parser::new_from(std::io::stdin(), false);
+    parser.load_file("lib.scm", "(a\n  (λ c)) 'd".to_string());
+    let res = parser.read_exp().unwrap().unwrap();
+    let span = res.span().unwrap();
+    assert_eq!(span.to_string(), "lib.scm:1:1");
+    assert_eq!((span.start, span.end), (0, 12));
+    let inner = res.c("da").unwrap().span().unwrap();
+    assert_eq!(inner.to_string(), "lib.scm:2:3");
+    assert_eq!((inner.start, inner.end), (5, 11));
+    let quoted = parser.read_exp().unwrap().unwrap().span().unwrap();
+    assert_eq!(quoted.to_string(), "lib.scm:2:10");
+
+    // spans do not take part in comparisons, and plain loads have none
+    let mut plain = Parser::new_from(std::io::stdin(), false);
+    plain.load("(a\n  (λ c))".to_string());
+    let other = plain.read_exp().unwrap().unwrap();
+    assert!(other.span().is_none());
+    assert_eq!(res, other);
+}
+
+#[test]
+fn test_parser_interactive() {
+    // in interactive mode, input is read from the stream as it is needed,
+    // even a character split across two reads
+    struct Chunks(Vec<Vec<u8>>);
+    impl Read for Chunks {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            if self.0.is_empty() {
+                return Ok(0);
+            }
+            let chunk = self.0.remove(0);
+            buf[..chunk.len()].copy_from_slice(&chunk);
+            Ok(chunk.len())
+        }
+    }
+    let lambda = "λ".as_bytes();
+    let chunks = vec![
+        b"(+ 1\n".to_vec(),
+        b" 2) \"".to_vec(),
+        lambda[..1].to_vec(),
+        lambda[1..].to_vec(),
+        b"\" x".to_vec(),
+    ];
+    let mut parser = Parser::new_from(Chunks(chunks), true);
+    let mut res = vec![];
+    while let Some(exp) = parser.read_exp().unwrap() {
+        res.push(exp.to_string());
+    }
+    assert_eq!(res, vec!["(+ 1 2)", "\"λ\"", "x"]);
+
+    let mut parser = Parser::new_from("(1".as_bytes(), true);
+    assert!(parser.read_exp().is_err());
 }
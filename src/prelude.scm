@@ -0,0 +1,150 @@
+;;; The prelude: the parts of the standard library written in Scheme,
+;;; evaluated by every new interpreter before anything else.
+
+(define (not x)
+  (if x #f #t))
+
+;;; Pairs and lists
+
+(define (caar x) (car (car x)))
+(define (cadr x) (car (cdr x)))
+(define (cdar x) (cdr (car x)))
+(define (cddr x) (cdr (cdr x)))
+(define (caaar x) (car (car (car x))))
+(define (caadr x) (car (car (cdr x))))
+(define (cadar x) (car (cdr (car x))))
+(define (caddr x) (car (cdr (cdr x))))
+(define (cdaar x) (cdr (car (car x))))
+(define (cdadr x) (cdr (car (cdr x))))
+(define (cddar x) (cdr (cdr (car x))))
+(define (cdddr x) (cdr (cdr (cdr x))))
+(define (caaaar x) (car (car (car (car x)))))
+(define (caaadr x) (car (car (car (cdr x)))))
+(define (caadar x) (car (car (cdr (car x)))))
+(define (caaddr x) (car (car (cdr (cdr x)))))
+(define (cadaar x) (car (cdr (car (car x)))))
+(define (cadadr x) (car (cdr (car (cdr x)))))
+(define (caddar x) (car (cdr (cdr (car x)))))
+(define (cadddr x) (car (cdr (cdr (cdr x)))))
+(define (cdaaar x) (cdr (car (car (car x)))))
+(define (cdaadr x) (cdr (car (car (cdr x)))))
+(define (cdadar x) (cdr (car (cdr (car x)))))
+(define (cdaddr x) (cdr (car (cdr (cdr x)))))
+(define (cddaar x) (cdr (cdr (car (car x)))))
+(define (cddadr x) (cdr (cdr (car (cdr x)))))
+(define (cdddar x) (cdr (cdr (cdr (car x)))))
+(define (cddddr x) (cdr (cdr (cdr (cdr x)))))
+
+(define (list . items) items)
+
+;; A proper list ends in (); one that runs into itself is not.
+(define (list? x)
+  (let loop ((slow x) (fast x))
+    (cond ((null? fast) #t)
+          ((not (pair? fast)) #f)
+          ((null? (cdr fast)) #t)
+          ((not (pair? (cdr fast))) #f)
+          (else
+           (let ((slow (cdr slow)) (fast (cddr fast)))
+             (if (eq? slow fast) #f (loop slow fast)))))))
+
+(define (reverse items)
+  (let loop ((items items) (out '()))
+    (if (null? items)
+        out
+        (loop (cdr items) (cons (car items) out)))))
+
+;; Every list but the last is copied; the result shares the last, which
+;; need not be a list at all.
+(define (append . lists)
+  (define (append2 front back)
+    (let loop ((rev (reverse front)) (out back))
+      (if (null? rev)
+          out
+          (loop (cdr rev) (cons (car rev) out)))))
+  (let loop ((lists lists))
+    (cond ((null? lists) '())
+          ((null? (cdr lists)) (car lists))
+          (else (append2 (car lists) (loop (cdr lists)))))))
+
+(define (list-tail items k)
+  (if (= k 0)
+      items
+      (list-tail (cdr items) (- k 1))))
+
+(define (list-ref items k)
+  (car (list-tail items k)))
+
+;; Copies the pairs of the list's spine; an improper tail is kept.
+(define (list-copy items)
+  (let loop ((items items) (rev '()))
+    (cond ((null? items) (reverse rev))
+          ((pair? items) (loop (cdr items) (cons (car items) rev)))
+          (else (append (reverse rev) items)))))
+
+;;; Searching
+
+(define (memq x items)
+  (cond ((null? items) #f)
+        ((eq? x (car items)) items)
+        (else (memq x (cdr items)))))
+
+(define (memv x items)
+  (cond ((null? items) #f)
+        ((eqv? x (car items)) items)
+        (else (memv x (cdr items)))))
+
+(define (member x items . compare)
+  (let ((same? (if (null? compare) equal? (car compare))))
+    (let loop ((items items))
+      (cond ((null? items) #f)
+            ((same? x (car items)) items)
+            (else (loop (cdr items)))))))
+
+(define (assq key alist)
+  (cond ((null? alist) #f)
+        ((eq? key (caar alist)) (car alist))
+        (else (assq key (cdr alist)))))
+
+(define (assv key alist)
+  (cond ((null? alist) #f)
+        ((eqv? key (caar alist)) (car alist))
+        (else (assv key (cdr alist)))))
+
+(define (assoc key alist . compare)
+  (let ((same? (if (null? compare) equal? (car compare))))
+    (let loop ((alist alist))
+      (cond ((null? alist) #f)
+            ((same? key (caar alist)) (car alist))
+            (else (loop (cdr alist)))))))
+
+;;; Mapping
+
+;; With several lists, map and for-each stop at the end of the shortest.
+;; The procedure is applied to the elements in order.
+(define (map proc items . more)
+  (define (map1 f items)
+    (let loop ((items items) (out '()))
+      (if (null? items)
+          (reverse out)
+          (loop (cdr items) (cons (f (car items)) out)))))
+  (if (null? more)
+      (map1 proc items)
+      (let loop ((lists (cons items more)) (out '()))
+        (if (memq #t (map1 null? lists))
+            (reverse out)
+            (loop (map1 cdr lists)
+                  (cons (apply proc (map1 car lists)) out))))))
+
+(define (for-each proc items . more)
+  (if (null? more)
+      (let loop ((items items))
+        (if (not (null? items))
+            (begin
+              (proc (car items))
+              (loop (cdr items)))))
+      (let loop ((lists (cons items more)))
+        (if (not (memq #t (map null? lists)))
+            (begin
+              (apply proc (map car lists))
+              (loop (map cdr lists)))))))
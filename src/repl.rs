@@ -0,0 +1,602 @@
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::env;
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::rc::Rc;
+use std::sync::atomic::Ordering;
+use std::time::Instant;
+
+use ast::{join_list, Arity, Expr};
+use ctrlc;
+use error::SchemeError;
+use gc;
+use interp::Interpreter;
+use parser::Parser;
+use rustyline::error::ReadlineError;
+use rustyline::DefaultEditor;
+use symbol::Symbol;
+
+const PROMPT: &str = "> ";
+const CONTINUE_PROMPT: &str = ". ";
+
+/// The history file, in the user's home directory.
+const HISTORY_FILE: &str = ".rust_scm_history";
+
+/// The meta-commands, with their arguments and what they do.
+const COMMANDS: &[(&str, &str, &str)] = &[
+    ("load", "file", "evaluate the forms in a file"),
+    (
+        "time",
+        "expr",
+        "evaluate expr, then show the time and allocation",
+    ),
+    ("expand", "expr", "show the core form expr expands to"),
+    (
+        "env",
+        "[text]",
+        "list the globals, or those whose name contains text",
+    ),
+    (
+        "describe",
+        "name",
+        "show a global's value, parameters and source",
+    ),
+    (
+        "trace",
+        "name ...",
+        "print the calls to procedures and their values",
+    ),
+    (
+        "untrace",
+        "[name ...]",
+        "stop tracing procedures, or all of them",
+    ),
+    ("help", "", "show this list"),
+];
+
+/// Why a meta-command failed.
+enum Failure {
+    /// It was given the wrong arguments.
+    Usage(&'static str),
+    Error(SchemeError),
+}
+
+impl From<SchemeError> for Failure {
+    fn from(err: SchemeError) -> Failure {
+        Failure::Error(err)
+    }
+}
+
+type SharedOutput = Rc<RefCell<Box<dyn Write>>>;
+
+/// An interactive session: reads lines, evaluates each form once it is
+/// complete and prints what it returns. An error is printed and the
+/// session carries on with everything defined so far.
+///
+/// A line starting with a comma is a meta-command, such as `,help`,
+/// followed by its arguments.
+pub struct Repl {
+    interp: Interpreter,
+    pending: String,
+    /// Where traced procedures print their calls.
+    trace_out: SharedOutput,
+    /// How many traced calls are running.
+    depth: Rc<Cell<usize>>,
+    /// The traced globals: the procedure each was bound to and the
+    /// procedure tracing it that replaced it.
+    traced: HashMap<Symbol, (Expr, Expr)>,
+}
+
+impl Repl {
+    pub fn new(interp: Interpreter) -> Repl {
+        Repl {
+            interp,
+            pending: String::new(),
+            trace_out: Rc::new(RefCell::new(Box::new(io::stdout()))),
+            depth: Rc::new(Cell::new(0)),
+            traced: HashMap::new(),
+        }
+    }
+
+    /// Sends the output of traced procedures to `out` rather than to
+    /// standard output.
+    pub fn set_trace_output(&mut self, out: Box<dyn Write>) {
+        *self.trace_out.borrow_mut() = out;
+    }
+
+    pub fn interp(&mut self) -> &mut Interpreter {
+        &mut self.interp
+    }
+
+    /// Whether the lines so far end in the middle of a form.
+    pub fn is_continuing(&self) -> bool {
+        !self.pending.is_empty()
+    }
+
+    /// Takes a line of input. Once the input holds only complete forms,
+    /// evaluates them in order and writes each value or error to `out`. A
+    /// form that is interrupted abandons the rest of the input. A
+    /// meta-command runs once its arguments are complete.
+    pub fn feed(&mut self, line: &str, out: &mut dyn Write) -> io::Result<()> {
+        if self.is_continuing() {
+            self.pending.push('\n');
+        }
+        self.pending.push_str(line);
+        let input = self.pending.clone();
+        let (command, code) = match input.trim_start().strip_prefix(',') {
+            Some(rest) => {
+                let end = rest.find(char::is_whitespace).unwrap_or(rest.len());
+                (Some(&rest[..end]), &rest[end..])
+            }
+            None => (None, &input[..]),
+        };
+        let mut parser = Parser::new_from(io::empty(), false);
+        parser.load(code.to_string());
+        let mut forms = vec![];
+        loop {
+            match parser.read_exp() {
+                Ok(Some(exp)) => forms.push(exp),
+                Ok(None) => break,
+                Err(ref err) if *err.unlocated() == SchemeError::UnexpectedEof => return Ok(()),
+                Err(err) => {
+                    self.pending.clear();
+                    return writeln!(out, "error: {}", err);
+                }
+            }
+        }
+        self.pending.clear();
+        let flag = self.interp.vm.interrupt_flag();
+        flag.store(false, Ordering::Relaxed);
+        if let Some(name) = command {
+            return match self.command(name, &forms) {
+                Ok(text) => write!(out, "{}", text),
+                Err(Failure::Error(err)) => writeln!(out, "error: {}", err),
+                Err(Failure::Usage(usage)) => writeln!(out, "usage: ,{} {}", name, usage),
+            };
+        }
+        for exp in forms {
+            match self.interp.eval_expr(&exp) {
+                Ok(val) => writeln!(out, "{}", val)?,
+                Err(err) => {
+                    writeln!(out, "error: {}", err)?;
+                    if *err.unlocated() == SchemeError::Interrupted {
+                        break;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Runs the meta-command `name` on `args` and returns what it prints.
+    fn command(&mut self, name: &str, args: &[Expr]) -> Result<String, Failure> {
+        let usage = match COMMANDS.iter().find(|&&(command, _, _)| command == name) {
+            Some(&(_, usage, _)) => usage,
+            None => {
+                return Ok(format!(
+                    "unknown command ,{}; ,help lists the commands\n",
+                    name
+                ))
+            }
+        };
+        let arg = |i: usize| -> Result<&Expr, Failure> { args.get(i).ok_or(Failure::Usage(usage)) };
+        match name {
+            "load" if args.len() == 1 => {
+                let val = self.interp.eval_file(&*arg(0)?.as_str()?)?;
+                Ok(format!("{}\n", val))
+            }
+            "time" if args.len() == 1 => {
+                let before = gc::stats();
+                let start = Instant::now();
+                let res = self.interp.eval_expr(arg(0)?);
+                let elapsed = start.elapsed();
+                let after = gc::stats();
+                let mut text = match res {
+                    Ok(val) => format!("{}\n", val),
+                    Err(err) => format!("error: {}\n", err),
+                };
+                text += &format!(
+                    ";; {:.3} ms, {} objects allocated, {} collections\n",
+                    elapsed.as_secs_f64() * 1000.0,
+                    after.allocations - before.allocations,
+                    after.collections - before.collections
+                );
+                Ok(text)
+            }
+            "expand" if args.len() == 1 => Ok(format!("{}\n", self.interp.expand(arg(0)?)?)),
+            "env" if args.len() <= 1 => {
+                let filter = match args.first() {
+                    Some(text) => text.as_str()?,
+                    None => String::new(),
+                };
+                let mut bindings = self.interp.vm.globals.bindings();
+                bindings.sort_by_key(|&(name, _)| name.name());
+                let mut text = String::new();
+                for (name, val) in bindings {
+                    if name.name().contains(&*filter) {
+                        text += &format!("{} = {}\n", name, val);
+                    }
+                }
+                Ok(text)
+            }
+            "describe" if args.len() == 1 => self.describe(arg(0)?.as_sym()?),
+            "trace" if !args.is_empty() => {
+                for name in args {
+                    self.trace(name.as_sym()?)?;
+                }
+                Ok(String::new())
+            }
+            "untrace" => {
+                let names = if args.is_empty() {
+                    self.traced.keys().cloned().collect()
+                } else {
+                    args.iter()
+                        .map(Expr::as_sym)
+                        .collect::<Result<Vec<_>, _>>()?
+                };
+                for name in names {
+                    if let Some((func, tracer)) = self.traced.remove(&name) {
+                        // leave alone a global that was defined again
+                        if self.interp.global(&name.name()) == Some(tracer) {
+                            self.interp.define_global(&name.name(), func);
+                        }
+                    }
+                }
+                Ok(String::new())
+            }
+            "help" if args.is_empty() => {
+                let mut text = String::new();
+                for &(command, usage, doc) in COMMANDS {
+                    let call = format!(",{} {}", command, usage);
+                    text += &format!("{:<22}{}\n", call, doc);
+                }
+                Ok(text)
+            }
+            _ => Err(Failure::Usage(usage)),
+        }
+    }
+
+    /// What `,describe` says about the global `name`.
+    fn describe(&mut self, name: Symbol) -> Result<String, Failure> {
+        let val = match self.traced.get(&name) {
+            Some((func, _)) => func.clone(),
+            None => self
+                .interp
+                .global(&name.name())
+                .ok_or_else(|| SchemeError::UnboundVariable(Expr::Sym(name)))?,
+        };
+        let mut text = match val {
+            Expr::Proc(ref builtin) => {
+                format!(
+                    "{}: builtin procedure, arguments: {}\n",
+                    name, builtin.arity
+                )
+            }
+            Expr::Control(_) => format!("{}: builtin procedure\n", name),
+            Expr::Cont(_) => format!("{}: continuation\n", name),
+            Expr::CompProc(ref lambda, _) => {
+                let clauses = if lambda.clauses.is_empty() {
+                    vec![lambda.clone()]
+                } else {
+                    lambda.clauses.clone()
+                };
+                let params: Vec<String> = clauses
+                    .iter()
+                    .map(|clause| {
+                        let names = clause.names.iter().map(|&name| Expr::Sym(name));
+                        let mut params: Vec<Expr> =
+                            names.take(clause.nparams + clause.rest as usize).collect();
+                        let rest = if clause.rest {
+                            params.pop().unwrap()
+                        } else {
+                            Expr::Nil
+                        };
+                        join_list(params, rest).to_string()
+                    })
+                    .collect();
+                format!("{}: procedure {}\n", name, params.join(" | "))
+            }
+            ref other => format!("{} = {}\n", name, other),
+        };
+        if self.traced.contains_key(&name) {
+            text += "traced\n";
+        }
+        if let Some(source) = self.interp.source(&name.name()) {
+            if let Some(span) = source.span() {
+                text += &format!("defined at {}\n", span);
+            }
+            text += &format!("{}\n", source);
+        }
+        Ok(text)
+    }
+
+    /// Replaces the procedure bound to the global `name` by one that calls
+    /// it and prints each call and what it returns, indented by how many
+    /// traced calls it is inside.
+    fn trace(&mut self, name: Symbol) -> Result<(), Failure> {
+        if self.traced.contains_key(&name) {
+            return Ok(());
+        }
+        let func = self
+            .interp
+            .global(&name.name())
+            .ok_or_else(|| SchemeError::UnboundVariable(Expr::Sym(name)))?;
+        if !func.is_procedure() {
+            return Err(SchemeError::wrong_type("procedure", &func).into());
+        }
+        let out = self.trace_out.clone();
+        let depth = self.depth.clone();
+        let traced = func.clone();
+        let tracer = Expr::new_proc(&name.name(), Arity::AtLeast(0), move |vm, args| {
+            let level = depth.get();
+            let indent = "| ".repeat(level);
+            let call = Expr::new_pair(Expr::Sym(name), join_list(args.to_vec(), Expr::Nil));
+            let _ = writeln!(out.borrow_mut(), "{}{}", indent, call);
+            depth.set(level + 1);
+            let res = vm.call(traced.clone(), args.to_vec());
+            depth.set(level);
+            if let Ok(ref val) = res {
+                let _ = writeln!(out.borrow_mut(), "{}{}", indent, val);
+            }
+            res
+        });
+        self.interp.define_global(&name.name(), tracer.clone());
+        self.traced.insert(name, (func, tracer));
+        Ok(())
+    }
+
+    /// Drops the lines of an unfinished form.
+    pub fn cancel(&mut self) {
+        self.pending.clear();
+    }
+
+    /// Runs the session on the terminal until the input ends, with line
+    /// editing and the history kept in `~/.rust_scm_history`. Ctrl-C at
+    /// the prompt drops the current input; while code runs, it interrupts
+    /// the code.
+    pub fn run(&mut self) -> Result<(), ReadlineError> {
+        let mut editor = DefaultEditor::new()?;
+        let history = history_path();
+        if let Some(ref path) = history {
+            let _ = editor.load_history(path);
+        }
+        // the editor reads Ctrl-C at the prompt as a key, so the signal
+        // only arrives while code is running
+        let flag = self.interp.vm.interrupt_flag();
+        if let Err(err) = ctrlc::set_handler(move || flag.store(true, Ordering::Relaxed)) {
+            eprintln!("warning: Ctrl-C will not interrupt running code: {}", err);
+        }
+
+        let mut entry = String::new();
+        loop {
+            let prompt = if self.is_continuing() {
+                CONTINUE_PROMPT
+            } else {
+                PROMPT
+            };
+            match editor.readline(prompt) {
+                Ok(line) => {
+                    if !entry.is_empty() {
+                        entry.push('\n');
+                    }
+                    entry.push_str(&line);
+                    self.feed(&line, &mut io::stdout())?;
+                    if !self.is_continuing() {
+                        if !entry.trim().is_empty() {
+                            let _ = editor.add_history_entry(entry.trim());
+                        }
+                        entry.clear();
+                    }
+                }
+                Err(ReadlineError::Interrupted) => {
+                    self.cancel();
+                    entry.clear();
+                }
+                Err(ReadlineError::Eof) => break,
+                Err(err) => return Err(err),
+            }
+        }
+        if let Some(ref path) = history {
+            let _ = editor.save_history(path);
+        }
+        Ok(())
+    }
+}
+
+fn history_path() -> Option<PathBuf> {
+    env::var_os("HOME").map(|home| PathBuf::from(home).join(HISTORY_FILE))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+
+    fn feed(repl: &mut Repl, line: &str) -> String {
+        let mut out = vec![];
+        repl.feed(line, &mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn test_feed() {
+        let mut repl = Repl::new(Interpreter::new());
+        assert_eq!(feed(&mut repl, "(+ 1 2) (* 2 3)"), "3\n6\n");
+        assert_eq!(feed(&mut repl, ""), "");
+
+        // a form spanning lines is evaluated once it is complete
+        assert_eq!(feed(&mut repl, "(define (f x)"), "");
+        assert!(repl.is_continuing());
+        assert_eq!(feed(&mut repl, "  (* x 10))"), "OK\n");
+        assert!(!repl.is_continuing());
+        assert_eq!(feed(&mut repl, "(f \"a"), "");
+        assert_eq!(
+            feed(&mut repl, "b\")"),
+            "error: wrong type: expected number, got \"a\\nb\"\n"
+        );
+
+        // errors are reported and the session goes on
+        assert_eq!(
+            feed(&mut repl, "(car 1) (f 2)"),
+            "error: wrong type: expected pair, got 1\n20\n"
+        );
+        assert_eq!(feed(&mut repl, ")"), "error: read error: unexpected ')'\n");
+        assert_eq!(feed(&mut repl, "(f 3)"), "30\n");
+
+        assert_eq!(feed(&mut repl, "(f"), "");
+        repl.cancel();
+        assert_eq!(feed(&mut repl, "(f 4)"), "40\n");
+    }
+
+    #[test]
+    fn test_interrupt() {
+        let mut repl = Repl::new(Interpreter::new());
+        let flag = repl.interp().vm.interrupt_flag();
+        repl.interp()
+            .register_fn("stop!", Arity::Exactly(0), move |_, _| {
+                flag.store(true, Ordering::Relaxed);
+                Ok(Expr::Nil)
+            });
+        assert_eq!(
+            feed(
+                &mut repl,
+                "(define (spin) (spin)) (begin (stop!) (spin)) 'after"
+            ),
+            "OK\nerror: interrupted\n"
+        );
+        // an interrupt left over from before does not stop the next input
+        repl.interp()
+            .vm
+            .interrupt_flag()
+            .store(true, Ordering::Relaxed);
+        assert_eq!(feed(&mut repl, "(car '(1))"), "1\n");
+    }
+
+    /// Output that a test can read after handing it to the repl.
+    #[derive(Clone, Default)]
+    struct Shared(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Shared {
+        fn take(&self) -> String {
+            String::from_utf8(self.0.borrow_mut().split_off(0)).unwrap()
+        }
+    }
+
+    #[test]
+    fn test_commands() {
+        let mut repl = Repl::new(Interpreter::new());
+        assert!(feed(&mut repl, ",help").contains(",describe name"));
+        assert_eq!(
+            feed(&mut repl, ",frob"),
+            "unknown command ,frob; ,help lists the commands\n"
+        );
+        assert_eq!(feed(&mut repl, ",expand"), "usage: ,expand expr\n");
+        assert_eq!(feed(&mut repl, ",help me"), "usage: ,help \n");
+
+        // arguments may span lines
+        assert_eq!(feed(&mut repl, ",expand (when (f x)"), "");
+        assert!(repl.is_continuing());
+        assert_eq!(feed(&mut repl, "x)"), "(if (f x) (begin x) (quote OK))\n");
+
+        let time = feed(&mut repl, ",time (cons 1 2)");
+        assert!(time.starts_with("(1 . 2)\n;; "), "{}", time);
+        assert!(time.contains(" objects allocated, "), "{}", time);
+        assert!(feed(&mut repl, ",time (car 1)").starts_with("error: wrong type"));
+
+        feed(&mut repl, "(define env-test-x 1) (define env-test-y 'b)");
+        assert_eq!(
+            feed(&mut repl, ",env env-test"),
+            "env-test-x = 1\nenv-test-y = b\n"
+        );
+        assert!(feed(&mut repl, ",env").contains("car = #<procedure>\n"));
+    }
+
+    #[test]
+    fn test_load() {
+        let path = env::temp_dir().join(format!("rust_scm_repl_load_{}.scm", std::process::id()));
+        fs::write(&path, "(define loaded 5) (* loaded 2)").unwrap();
+        let mut repl = Repl::new(Interpreter::new());
+        let command = format!(",load \"{}\"", path.display());
+        assert_eq!(feed(&mut repl, &command), "10\n");
+        assert_eq!(feed(&mut repl, "loaded"), "5\n");
+        fs::remove_file(&path).unwrap();
+        assert!(feed(&mut repl, &command).starts_with("error: "));
+        assert_eq!(
+            feed(&mut repl, ",load 1"),
+            "error: wrong type: expected string, got 1\n"
+        );
+    }
+
+    #[test]
+    fn test_describe() {
+        let mut repl = Repl::new(Interpreter::new());
+        assert_eq!(
+            feed(&mut repl, ",describe car"),
+            "car: builtin procedure, arguments: 1\n"
+        );
+        feed(&mut repl, "(define (add a b . more) (+ a b))");
+        assert_eq!(
+            feed(&mut repl, ",describe add"),
+            "add: procedure (a b . more)\n(define (add a b . more) (+ a b))\n"
+        );
+        feed(&mut repl, "(define pick (case-lambda ((a) a) (args args)))");
+        assert!(feed(&mut repl, ",describe pick").starts_with("pick: procedure (a) | args\n"));
+        feed(&mut repl, "(define n 3)");
+        assert_eq!(feed(&mut repl, ",describe n"), "n = 3\n(define n 3)\n");
+        assert_eq!(
+            feed(&mut repl, ",describe nowhere"),
+            "error: unbound variable nowhere\n"
+        );
+    }
+
+    #[test]
+    fn test_trace() {
+        let mut repl = Repl::new(Interpreter::new());
+        let out = Shared::default();
+        repl.set_trace_output(Box::new(out.clone()));
+        feed(
+            &mut repl,
+            "(define (fact n) (if (= n 0) 1 (* n (fact (- n 1)))))",
+        );
+        assert_eq!(feed(&mut repl, ",trace fact"), "");
+        assert_eq!(feed(&mut repl, "(fact 2)"), "2\n");
+        assert_eq!(
+            out.take(),
+            "(fact 2)\n| (fact 1)\n| | (fact 0)\n| | 1\n| 1\n2\n"
+        );
+        assert!(feed(&mut repl, ",describe fact").contains("traced\n"));
+
+        // an error leaves the depth where it was
+        assert!(feed(&mut repl, "(fact 'x)").starts_with("error: "));
+        out.take();
+        feed(&mut repl, "(fact 0)");
+        assert_eq!(out.take(), "(fact 0)\n1\n");
+
+        assert_eq!(feed(&mut repl, ",untrace"), "");
+        assert_eq!(feed(&mut repl, "(fact 3)"), "6\n");
+        assert_eq!(out.take(), "");
+
+        // a global defined again while traced is left alone
+        feed(&mut repl, ",trace fact");
+        feed(&mut repl, "(define (fact n) 0)");
+        feed(&mut repl, ",untrace fact");
+        assert_eq!(feed(&mut repl, "(fact 3)"), "0\n");
+
+        assert_eq!(
+            feed(&mut repl, ",trace 1"),
+            "error: wrong type: expected symbol, got 1\n"
+        );
+    }
+}
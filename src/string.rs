@@ -0,0 +1,544 @@
+//! The string procedures: those of R7RS, and `string-index`,
+//! `string-contains`, `string-split` and `string-join` after SRFI 13.
+//!
+//! Indexes count characters, that is Unicode scalar values, not bytes, so
+//! `(string-ref "λx" 1)` is `#\x`. Optional `start` and `end` arguments
+//! select the characters from `start` up to but not including `end`.
+//!
+//! Literals and `symbol->string` give immutable strings. Every procedure
+//! here that returns a string makes a new, mutable one, which
+//! `string-set!` and `string-fill!` can change.
+
+use ast::{join_list, split_list, Expr};
+use env;
+use error::SchemeError;
+use parser;
+use std::cell::RefCell;
+use std::cmp::Ordering;
+use std::rc::Rc;
+use vm::Vm;
+
+/// The text of a string of either kind.
+pub fn text(exp: &Expr) -> Result<String, SchemeError> {
+    match *exp {
+        Expr::Str(ref val) => Ok(val.clone()),
+        Expr::MutStr(ref val) => Ok(val.borrow().iter().collect()),
+        _ => Err(SchemeError::wrong_type("string", exp)),
+    }
+}
+
+fn chars(exp: &Expr) -> Result<Vec<char>, SchemeError> {
+    match *exp {
+        Expr::Str(ref val) => Ok(val.chars().collect()),
+        Expr::MutStr(ref val) => Ok(val.borrow().clone()),
+        _ => Err(SchemeError::wrong_type("string", exp)),
+    }
+}
+
+/// The number of characters in a string, counted without copying it.
+fn char_count(exp: &Expr) -> Result<usize, SchemeError> {
+    match *exp {
+        Expr::Str(ref val) => Ok(val.chars().count()),
+        Expr::MutStr(ref val) => Ok(val.borrow().len()),
+        _ => Err(SchemeError::wrong_type("string", exp)),
+    }
+}
+
+fn char_arg(exp: &Expr) -> Result<char, SchemeError> {
+    match *exp {
+        Expr::Char(ch) => Ok(ch),
+        _ => Err(SchemeError::wrong_type("char", exp)),
+    }
+}
+
+/// An index into `string` of at most `limit`.
+fn index(exp: &Expr, limit: usize, string: &Expr) -> Result<usize, SchemeError> {
+    match *exp {
+        Expr::Int(k) if k >= 0 && k as usize <= limit => Ok(k as usize),
+        Expr::Int(k) => Err(SchemeError::OutOfRange(k, string.clone())),
+        _ => Err(SchemeError::wrong_type("index", exp)),
+    }
+}
+
+/// The start and end of the characters of `string` selected by the
+/// optional indexes in `args`.
+fn bounds(string: &Expr, len: usize, args: &[Expr]) -> Result<(usize, usize), SchemeError> {
+    let end = match args.get(1) {
+        Some(end) => index(end, len, string)?,
+        None => len,
+    };
+    let start = match args.first() {
+        Some(start) => index(start, end, string)?,
+        None => 0,
+    };
+    Ok((start, end))
+}
+
+/// The characters of `args[0]` selected by the optional indexes that
+/// follow the first `skip` arguments.
+fn selected(args: &[Expr], skip: usize) -> Result<Vec<char>, SchemeError> {
+    let chars = chars(&args[0])?;
+    let (start, end) = bounds(&args[0], chars.len(), &args[skip..])?;
+    Ok(chars[start..end].to_vec())
+}
+
+fn new_str(chars: Vec<char>) -> Expr {
+    Expr::MutStr(Rc::new(RefCell::new(chars)))
+}
+
+/// Changes the text of the mutable string `exp` with `edit`.
+fn edit<F: FnOnce(&mut Vec<char>) -> Result<(), SchemeError>>(
+    exp: &Expr,
+    edit: F,
+) -> Result<Expr, SchemeError> {
+    match *exp {
+        Expr::MutStr(ref val) => {
+            edit(&mut val.borrow_mut())?;
+            Ok(Expr::new_sym("OK"))
+        }
+        _ => Err(SchemeError::wrong_type("mutable string", exp)),
+    }
+}
+
+/// `(make-string k)` and `(make-string k char)`; the fill is a space. A
+/// length there is no memory for is an error rather than an abort.
+pub fn make_string(args: &[Expr]) -> Result<Expr, SchemeError> {
+    let fill = match args.get(1) {
+        Some(fill) => char_arg(fill)?,
+        None => ' ',
+    };
+    let k = match args[0] {
+        Expr::Int(k) if k >= 0 => k as usize,
+        ref other => return Err(SchemeError::wrong_type("length", other)),
+    };
+    let mut chars = Vec::new();
+    if chars.try_reserve_exact(k).is_err() {
+        return Err(SchemeError::OutOfMemory(env::call_form(
+            "make-string",
+            args,
+        )));
+    }
+    chars.resize(k, fill);
+    Ok(new_str(chars))
+}
+
+/// `(string char ...)`
+pub fn string(args: &[Expr]) -> Result<Expr, SchemeError> {
+    let chars: Result<Vec<char>, SchemeError> = args.iter().map(char_arg).collect();
+    Ok(new_str(chars?))
+}
+
+pub fn string_length(args: &[Expr]) -> Result<Expr, SchemeError> {
+    Ok(Expr::Int(char_count(&args[0])? as isize))
+}
+
+pub fn string_ref(args: &[Expr]) -> Result<Expr, SchemeError> {
+    let k = index(&args[1], char_count(&args[0])?, &args[0])?;
+    let ch = match args[0] {
+        Expr::MutStr(ref val) => val.borrow().get(k).cloned(),
+        Expr::Str(ref val) => val.chars().nth(k),
+        _ => None,
+    };
+    ch.map(Expr::Char)
+        .ok_or_else(|| SchemeError::OutOfRange(k as isize, args[0].clone()))
+}
+
+pub fn string_set(args: &[Expr]) -> Result<Expr, SchemeError> {
+    let ch = char_arg(&args[2])?;
+    edit(&args[0], |chars| {
+        match index(&args[1], chars.len(), &args[0])? {
+            k if k < chars.len() => chars[k] = ch,
+            _ => return Err(SchemeError::OutOfRange(args[1].as_int()?, args[0].clone())),
+        }
+        Ok(())
+    })
+}
+
+/// `(string-fill! string char [start [end]])`
+pub fn string_fill(args: &[Expr]) -> Result<Expr, SchemeError> {
+    let ch = char_arg(&args[1])?;
+    edit(&args[0], |chars| {
+        let (start, end) = bounds(&args[0], chars.len(), &args[2..])?;
+        for slot in &mut chars[start..end] {
+            *slot = ch;
+        }
+        Ok(())
+    })
+}
+
+/// `(substring string start end)`
+pub fn substring(args: &[Expr]) -> Result<Expr, SchemeError> {
+    Ok(new_str(selected(args, 1)?))
+}
+
+/// `(string-copy string [start [end]])`
+pub fn string_copy(args: &[Expr]) -> Result<Expr, SchemeError> {
+    Ok(new_str(selected(args, 1)?))
+}
+
+pub fn string_append(args: &[Expr]) -> Result<Expr, SchemeError> {
+    let mut res = String::new();
+    for arg in args {
+        res += &text(arg)?;
+    }
+    Ok(Expr::new_mut_str(&res))
+}
+
+/// `(string->list string [start [end]])`
+pub fn string_to_list(args: &[Expr]) -> Result<Expr, SchemeError> {
+    let chars = selected(args, 1)?;
+    Ok(join_list(
+        chars.into_iter().map(Expr::Char).collect(),
+        Expr::Nil,
+    ))
+}
+
+pub fn list_to_string(args: &[Expr]) -> Result<Expr, SchemeError> {
+    let (items, tail) = split_list(&args[0])?;
+    if !tail.is_empty() {
+        return Err(SchemeError::wrong_type("list", &args[0]));
+    }
+    let chars: Result<Vec<char>, SchemeError> = items.iter().map(char_arg).collect();
+    Ok(new_str(chars?))
+}
+
+/// `(string->number string [radix])` is the number the string reads as,
+/// prefixes such as `#x` and `#e` included, or `#f`.
+pub fn string_to_number(args: &[Expr]) -> Result<Expr, SchemeError> {
+    let radix = match args.get(1) {
+        None => 10,
+        Some(&Expr::Int(r)) if r == 2 || r == 8 || r == 10 || r == 16 => r as u32,
+        Some(other) => return Err(SchemeError::wrong_type("radix 2, 8, 10 or 16", other)),
+    };
+    Ok(parser::parse_prefixed_number(&text(&args[0])?, radix).unwrap_or(Expr::Bool(false)))
+}
+
+pub fn string_upcase(args: &[Expr]) -> Result<Expr, SchemeError> {
+    Ok(Expr::new_mut_str(&text(&args[0])?.to_uppercase()))
+}
+
+pub fn string_downcase(args: &[Expr]) -> Result<Expr, SchemeError> {
+    Ok(Expr::new_mut_str(&text(&args[0])?.to_lowercase()))
+}
+
+/// The full case folding of `text`, which differs from lowercasing where a
+/// character folds to several, as `ß` does to `ss`, or where lowercase has
+/// more than one form of a letter.
+fn fold_case(text: &str) -> String {
+    let mut folded = String::new();
+    for ch in text.to_lowercase().chars() {
+        match ch {
+            'ß' => folded.push_str("ss"),
+            'ς' => folded.push('σ'),
+            'ſ' => folded.push('s'),
+            'ﬀ' => folded.push_str("ff"),
+            'ﬁ' => folded.push_str("fi"),
+            'ﬂ' => folded.push_str("fl"),
+            'ﬃ' => folded.push_str("ffi"),
+            'ﬄ' => folded.push_str("ffl"),
+            'ﬅ' | 'ﬆ' => folded.push_str("st"),
+            _ => folded.push(ch),
+        }
+    }
+    folded
+}
+
+/// Checks that `test` holds between each pair of adjacent strings,
+/// compared by their characters and, if `fold`, ignoring case.
+fn compare_chain(
+    args: &[Expr],
+    fold: bool,
+    test: fn(Ordering) -> bool,
+) -> Result<Expr, SchemeError> {
+    let mut texts = vec![];
+    for arg in args {
+        let text = text(arg)?;
+        texts.push(if fold { fold_case(&text) } else { text });
+    }
+    Ok(Expr::Bool(
+        texts.windows(2).all(|pair| test(pair[0].cmp(&pair[1]))),
+    ))
+}
+
+pub fn string_eq(args: &[Expr]) -> Result<Expr, SchemeError> {
+    compare_chain(args, false, |ord| ord == Ordering::Equal)
+}
+
+pub fn string_less(args: &[Expr]) -> Result<Expr, SchemeError> {
+    compare_chain(args, false, |ord| ord == Ordering::Less)
+}
+
+pub fn string_large(args: &[Expr]) -> Result<Expr, SchemeError> {
+    compare_chain(args, false, |ord| ord == Ordering::Greater)
+}
+
+pub fn string_less_eq(args: &[Expr]) -> Result<Expr, SchemeError> {
+    compare_chain(args, false, |ord| ord != Ordering::Greater)
+}
+
+pub fn string_large_eq(args: &[Expr]) -> Result<Expr, SchemeError> {
+    compare_chain(args, false, |ord| ord != Ordering::Less)
+}
+
+pub fn string_ci_eq(args: &[Expr]) -> Result<Expr, SchemeError> {
+    compare_chain(args, true, |ord| ord == Ordering::Equal)
+}
+
+pub fn string_ci_less(args: &[Expr]) -> Result<Expr, SchemeError> {
+    compare_chain(args, true, |ord| ord == Ordering::Less)
+}
+
+pub fn string_ci_large(args: &[Expr]) -> Result<Expr, SchemeError> {
+    compare_chain(args, true, |ord| ord == Ordering::Greater)
+}
+
+pub fn string_ci_less_eq(args: &[Expr]) -> Result<Expr, SchemeError> {
+    compare_chain(args, true, |ord| ord != Ordering::Greater)
+}
+
+pub fn string_ci_large_eq(args: &[Expr]) -> Result<Expr, SchemeError> {
+    compare_chain(args, true, |ord| ord != Ordering::Less)
+}
+
+/// `(string-index string pred [start [end]])` is the index of the first
+/// character that `pred` is true of, or `#f`. `pred` may also be a
+/// character to look for.
+pub fn string_index(vm: &mut Vm, args: &[Expr]) -> Result<Expr, SchemeError> {
+    let chars = chars(&args[0])?;
+    let (start, end) = bounds(&args[0], chars.len(), &args[2..])?;
+    for (i, &ch) in chars.iter().enumerate().take(end).skip(start) {
+        let found = match args[1] {
+            Expr::Char(wanted) => ch == wanted,
+            ref pred if pred.is_procedure() => {
+                vm.call(pred.clone(), vec![Expr::Char(ch)])?.is_true()
+            }
+            ref other => return Err(SchemeError::wrong_type("char or procedure", other)),
+        };
+        if found {
+            return Ok(Expr::Int(i as isize));
+        }
+    }
+    Ok(Expr::Bool(false))
+}
+
+/// `(string-contains string pattern)` is the index where `pattern` first
+/// occurs in `string`, or `#f`.
+pub fn string_contains(args: &[Expr]) -> Result<Expr, SchemeError> {
+    let (string, pattern) = (text(&args[0])?, text(&args[1])?);
+    Ok(match string.find(&*pattern) {
+        Some(at) => Expr::Int(string[..at].chars().count() as isize),
+        None => Expr::Bool(false),
+    })
+}
+
+/// `(string-split string)` is the list of the words of `string` between
+/// runs of whitespace. `(string-split string delimiter)` splits it at
+/// each occurrence of the delimiter, a character or a non-empty string,
+/// so empty strings are kept: `(string-split "a,,b" #\,)` is
+/// `("a" "" "b")`.
+pub fn string_split(args: &[Expr]) -> Result<Expr, SchemeError> {
+    let string = text(&args[0])?;
+    let parts: Vec<&str> = match args.get(1) {
+        None => string.split_whitespace().collect(),
+        Some(&Expr::Char(delimiter)) => string.split(delimiter).collect(),
+        Some(delimiter) => {
+            let delimiter = text(delimiter)?;
+            if delimiter.is_empty() {
+                return Err(SchemeError::wrong_type("non-empty delimiter", &args[1]));
+            }
+            string.split(&*delimiter).collect()
+        }
+    };
+    Ok(join_list(
+        parts.into_iter().map(Expr::new_mut_str).collect(),
+        Expr::Nil,
+    ))
+}
+
+/// `(string-join strings [delimiter])` joins a list of strings, with a
+/// space between them unless another delimiter is given.
+pub fn string_join(args: &[Expr]) -> Result<Expr, SchemeError> {
+    let (items, tail) = split_list(&args[0])?;
+    if !tail.is_empty() {
+        return Err(SchemeError::wrong_type("list", &args[0]));
+    }
+    let delimiter = match args.get(1) {
+        Some(delimiter) => text(delimiter)?,
+        None => " ".to_string(),
+    };
+    let texts: Result<Vec<String>, SchemeError> = items.iter().map(text).collect();
+    Ok(Expr::new_mut_str(&texts?.join(&delimiter)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use eval::Evaler;
+
+    fn eval(evaler: &mut Evaler, code: &str) -> Result<String, SchemeError> {
+        evaler
+            .eval_from(code.to_string())
+            .map(|val| val.to_string())
+    }
+
+    #[test]
+    fn test_strings() {
+        let mut evaler = Evaler::new();
+        let cases = [
+            ("(string-length \"λx→y\")", "4"),
+            ("(string-length \"\")", "0"),
+            ("(string-ref \"λx→y\" 2)", "#\\→"),
+            ("(substring \"héllo wörld\" 1 8)", "\"éllo wö\""),
+            ("(string-copy \"héllo\" 3)", "\"lo\""),
+            ("(string-append \"a\" \"\" \"βγ\")", "\"aβγ\""),
+            ("(string-append)", "\"\""),
+            ("(string #\\a #\\λ)", "\"aλ\""),
+            ("(make-string 3 #\\é)", "\"ééé\""),
+            ("(string->list \"aλb\")", "(#\\a #\\λ #\\b)"),
+            ("(string->list \"aλb\" 1 2)", "(#\\λ)"),
+            ("(list->string '(#\\x #\\→))", "\"x→\""),
+            ("(string->number \"42\")", "42"),
+            ("(string->number \"1/2\")", "1/2"),
+            ("(string->number \"ff\" 16)", "255"),
+            ("(string->number \"abc\")", "#f"),
+            ("(string->number \"#xff\")", "255"),
+            ("(string->number \"#e1.5\")", "3/2"),
+            ("(string->number \"#i1/2\" 16)", "0.5"),
+            ("(string->number \"#b101\" 16)", "5"),
+            ("(string->number \"#q1\")", "#f"),
+            ("(string-upcase \"straße\")", "\"STRASSE\""),
+            ("(string-downcase \"ÀB\")", "\"àb\""),
+            ("(string=? \"a\" \"a\" \"a\")", "#t"),
+            ("(string=? \"a\" \"A\")", "#f"),
+            ("(string<? \"abc\" \"abd\" \"b\")", "#t"),
+            ("(string<? \"b\" \"a\")", "#f"),
+            ("(string>? \"é\" \"z\")", "#t"),
+            ("(string<=? \"a\" \"a\" \"b\")", "#t"),
+            ("(string>=? \"a\" \"b\")", "#f"),
+            ("(string-ci=? \"Straße\" \"STRASSE\")", "#t"),
+            ("(string-ci=? \"ΣΟΦΟΣ\" \"σοφος\")", "#t"),
+            ("(string-ci=? \"ÉtÉ\" \"été\")", "#t"),
+            ("(string-ci<? \"apple\" \"Banana\")", "#t"),
+            ("(string<? \"apple\" \"Banana\")", "#f"),
+            ("(string-ci>? \"b\" \"A\")", "#t"),
+            ("(string-ci<=? \"A\" \"a\")", "#t"),
+            ("(string-ci>=? \"a\" \"B\")", "#f"),
+            ("(string-index \"héllo\" #\\l)", "2"),
+            ("(string-index \"héllo\" #\\z)", "#f"),
+            ("(string-index \"a1b2\" (lambda (c) (eqv? c #\\2)))", "3"),
+            ("(string-index \"lol\" #\\l 1)", "2"),
+            ("(string-contains \"ça va bien\" \"va\")", "3"),
+            ("(string-contains \"abc\" \"d\")", "#f"),
+            ("(string-split \"  a b\\tc \")", "(\"a\" \"b\" \"c\")"),
+            ("(string-split \"a,,β\" #\\,)", "(\"a\" \"\" \"β\")"),
+            ("(string-split \"a::b\" \"::\")", "(\"a\" \"b\")"),
+            ("(string-join '(\"a\" \"b\" \"c\"))", "\"a b c\""),
+            ("(string-join '(\"a\" \"b\") \", \")", "\"a, b\""),
+            ("(string-join '())", "\"\""),
+            ("(string? (string-append \"a\"))", "#t"),
+            ("(equal? (string-append \"a\" \"b\") \"ab\")", "#t"),
+            ("(eqv? (string-copy \"a\") (string-copy \"a\"))", "#f"),
+            ("(let ((s (string-copy \"a\"))) (eq? s s))", "#t"),
+            ("(string->symbol (string-append \"a\" \"b\"))", "ab"),
+        ];
+        for &(code, expected) in cases.iter() {
+            assert_eq!(
+                eval(&mut evaler, code),
+                Ok(expected.to_string()),
+                "{}",
+                code
+            );
+        }
+    }
+
+    #[test]
+    fn test_mutation() {
+        let mut evaler = Evaler::new();
+        assert_eq!(
+            eval(
+                &mut evaler,
+                "(define s (make-string 4 #\\-)) (string-set! s 1 #\\λ) s"
+            ),
+            Ok("\"-λ--\"".to_string())
+        );
+        assert_eq!(
+            eval(&mut evaler, "(string-set! s 3 #\\→) (string-ref s 3)"),
+            Ok("#\\→".to_string())
+        );
+        assert_eq!(
+            eval(&mut evaler, "(string-fill! s #\\x 2) s"),
+            Ok("\"-λxx\"".to_string())
+        );
+        assert_eq!(
+            eval(&mut evaler, "(string-fill! s #\\é) (string-length s)"),
+            Ok("4".to_string())
+        );
+        assert_eq!(eval(&mut evaler, "s"), Ok("\"éééé\"".to_string()));
+        // each access takes constant time, so a pass over a long string is
+        // quick
+        assert_eq!(
+            eval(
+                &mut evaler,
+                "(define long (make-string 100000 #\\a))
+                 (do ((i 0 (+ i 1))) ((= i (string-length long)))
+                   (string-set! long i (if (eqv? (string-ref long i) #\\a) #\\b #\\c)))
+                 (string-ref long 99999)"
+            ),
+            Ok("#\\b".to_string())
+        );
+
+        // literals cannot be changed
+        assert_eq!(
+            eval(&mut evaler, "(string-set! \"abc\" 0 #\\x)"),
+            Err(SchemeError::wrong_type(
+                "mutable string",
+                &Expr::new_str("abc")
+            ))
+        );
+        assert_eq!(
+            eval(&mut evaler, "(string-set! s 4 #\\x)"),
+            Err(SchemeError::OutOfRange(4, Expr::new_mut_str("éééé")))
+        );
+    }
+
+    #[test]
+    fn test_errors() {
+        let mut evaler = Evaler::new();
+        let mut fails = |code: &str| {
+            evaler
+                .eval_from(code.to_string())
+                .map_err(|err| err.unlocated().clone())
+        };
+        assert_eq!(
+            fails("(string-ref \"λ\" 1)"),
+            Err(SchemeError::OutOfRange(1, Expr::new_str("λ")))
+        );
+        assert_eq!(
+            fails("(substring \"abc\" 2 1)"),
+            Err(SchemeError::OutOfRange(2, Expr::new_str("abc")))
+        );
+        assert_eq!(
+            fails("(string-ref \"abc\" -1)"),
+            Err(SchemeError::OutOfRange(-1, Expr::new_str("abc")))
+        );
+        assert_eq!(
+            fails("(string-length 'abc)"),
+            Err(SchemeError::wrong_type("string", &Expr::new_sym("abc")))
+        );
+        assert_eq!(
+            fails("(string-append \"a\" #\\b)"),
+            Err(SchemeError::wrong_type("string", &Expr::Char('b')))
+        );
+        assert!(fails("(string-split \"abc\" \"\")").is_err());
+        assert!(fails("(string-index \"abc\" 1)").is_err());
+        assert!(fails("(list->string '(1))").is_err());
+        assert!(fails("(string-join '(\"a\" . \"b\"))").is_err());
+        assert!(fails("(make-string -1)").is_err());
+        match fails("(make-string 1000000000000)") {
+            Err(SchemeError::OutOfMemory(call)) => {
+                assert_eq!(call.to_string(), "(make-string 1000000000000)")
+            }
+            res => panic!("unexpected {:?}", res),
+        }
+    }
+}
@@ -0,0 +1,228 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt;
+use std::rc::Rc;
+
+/// An interned name. Symbols with the same name are the same `Symbol`, so
+/// comparing, hashing and copying one costs no more than for an integer.
+/// The names are kept once, in a table per thread.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Symbol(u32);
+
+struct Table {
+    names: Vec<Rc<str>>,
+    ids: HashMap<Rc<str>, Symbol>,
+    gensyms: usize,
+    /// The symbol each live one made by `fresh` stands for.
+    origins: HashMap<Symbol, Symbol>,
+    /// Released symbols, whose slots `push` reuses.
+    free: Vec<Symbol>,
+}
+
+macro_rules! predefined {
+    ($($id:ident: $name:expr,)*) => {
+        #[allow(non_camel_case_types, clippy::upper_case_acronyms)]
+        enum Predefined {
+            $($id,)*
+        }
+
+        $(pub const $id: Symbol = Symbol(Predefined::$id as u32);)*
+
+        const PREDEFINED: &[&str] = &[$($name,)*];
+    };
+}
+
+// The names the expander and the compiler dispatch on. They are interned
+// first, in this order, so that their symbols are known constants.
+predefined! {
+    QUOTE: "quote",
+    QUASIQUOTE: "quasiquote",
+    UNQUOTE: "unquote",
+    UNQUOTE_SPLICING: "unquote-splicing",
+    DEFINE: "define",
+    SET: "set!",
+    LAMBDA: "lambda",
+    CASE_LAMBDA: "case-lambda",
+    IF: "if",
+    BEGIN: "begin",
+    LET: "let",
+    LET_STAR: "let*",
+    LETREC: "letrec",
+    LETREC_STAR: "letrec*",
+    COND: "cond",
+    CASE: "case",
+    WHEN: "when",
+    UNLESS: "unless",
+    DO: "do",
+    AND: "and",
+    OR: "or",
+    DEFINE_SYNTAX: "define-syntax",
+    LET_SYNTAX: "let-syntax",
+    LETREC_SYNTAX: "letrec-syntax",
+    SYNTAX_RULES: "syntax-rules",
+    TOP: "#%top",
+    ELSE: "else",
+    ARROW: "=>",
+    ELLIPSIS: "...",
+    UNDERSCORE: "_",
+}
+
+impl Table {
+    fn new() -> Table {
+        let mut table = Table {
+            names: vec![],
+            ids: HashMap::new(),
+            gensyms: 0,
+            origins: HashMap::new(),
+            free: vec![],
+        };
+        for name in PREDEFINED {
+            table.intern(name);
+        }
+        table
+    }
+
+    fn intern(&mut self, name: &str) -> Symbol {
+        if let Some(&sym) = self.ids.get(name) {
+            return sym;
+        }
+        let sym = self.push(name.into());
+        self.ids.insert(self.names[sym.0 as usize].clone(), sym);
+        sym
+    }
+
+    fn push(&mut self, name: Rc<str>) -> Symbol {
+        if let Some(sym) = self.free.pop() {
+            self.names[sym.0 as usize] = name;
+            return sym;
+        }
+        self.names.push(name);
+        Symbol(self.names.len() as u32 - 1)
+    }
+
+    fn gensym(&mut self, prefix: &str) -> Symbol {
+        self.gensyms += 1;
+        let name = format!("{}{}", prefix, self.gensyms);
+        self.push(name.into())
+    }
+}
+
+thread_local! {
+    static TABLE: RefCell<Table> = RefCell::new(Table::new());
+}
+
+impl Symbol {
+    /// The symbol named `name`, which is made the first time it is asked
+    /// for.
+    pub fn intern(name: &str) -> Symbol {
+        TABLE.with(|table| table.borrow_mut().intern(name))
+    }
+
+    /// A new symbol that is not interned: it is different from every other
+    /// symbol, including any that `intern` later returns for its name. The
+    /// name is `prefix` followed by a counter.
+    pub fn gensym(prefix: &str) -> Symbol {
+        TABLE.with(|table| table.borrow_mut().gensym(prefix))
+    }
+
+    /// A new uninterned symbol that stands for `origin`, named like
+    /// `origin#3`. Unlike other symbols, it can be released.
+    pub fn fresh(origin: Symbol) -> Symbol {
+        let origin = origin.origin();
+        let prefix = format!("{}#", origin);
+        TABLE.with(|table| {
+            let mut table = table.borrow_mut();
+            let sym = table.gensym(&prefix);
+            table.origins.insert(sym, origin);
+            sym
+        })
+    }
+
+    /// The symbol a symbol made by `fresh` stands for, or else the symbol
+    /// itself.
+    pub fn origin(self) -> Symbol {
+        TABLE.with(|table| table.borrow().origins.get(&self).cloned().unwrap_or(self))
+    }
+
+    /// Lets the table reuse the slot of a symbol made by `fresh`, which
+    /// nothing may refer to any more. Other symbols are never released.
+    pub fn release(self) {
+        TABLE.with(|table| {
+            let mut table = table.borrow_mut();
+            if table.origins.remove(&self).is_some() {
+                table.free.push(self);
+            }
+        })
+    }
+
+    /// How many slots the table has, including released ones.
+    #[cfg(test)]
+    pub fn table_len() -> usize {
+        TABLE.with(|table| table.borrow().names.len())
+    }
+
+    pub fn name(self) -> Rc<str> {
+        TABLE.with(|table| table.borrow().names[self.0 as usize].clone())
+    }
+}
+
+impl fmt::Display for Symbol {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+impl fmt::Debug for Symbol {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}", self.name())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_intern() {
+        assert_eq!(Symbol::intern("hello"), Symbol::intern("hello"));
+        assert!(Symbol::intern("hello") != Symbol::intern("world"));
+        assert_eq!(&*Symbol::intern("hello").name(), "hello");
+        for (i, name) in PREDEFINED.iter().enumerate() {
+            assert_eq!(Symbol::intern(name), Symbol(i as u32));
+        }
+        assert_eq!(Symbol::intern("set!"), SET);
+        assert_eq!(Symbol::intern("#%top"), TOP);
+    }
+
+    #[test]
+    fn test_gensym() {
+        let a = Symbol::gensym("g");
+        let b = Symbol::gensym("g");
+        assert!(a != b);
+        assert!(Symbol::intern(&a.name()) != a);
+        assert!(a.name().starts_with('g'));
+    }
+
+    #[test]
+    fn test_fresh() {
+        let x = Symbol::intern("x");
+        let a = Symbol::fresh(x);
+        let b = Symbol::fresh(a);
+        assert!(a != b && a != x);
+        assert!(a.name().starts_with("x#") && b.name().starts_with("x#"));
+        assert_eq!((a.origin(), b.origin(), x.origin()), (x, x, x));
+
+        // a released slot is reused, under a new name
+        let name = a.name();
+        a.release();
+        let c = Symbol::fresh(x);
+        assert_eq!(c, a);
+        assert!(c.name() != name);
+        // interned symbols and gensyms stay
+        x.release();
+        let g = Symbol::gensym("g");
+        g.release();
+        assert!(Symbol::fresh(x) != x && Symbol::fresh(x) != g);
+        assert_eq!(Symbol::intern("x"), x);
+    }
+}
@@ -0,0 +1,367 @@
+use ast::{join_list, split_list, Expr};
+use error::SchemeError;
+use expand::{Expander, Scope};
+use std::collections::{HashMap, HashSet};
+use std::rc::Rc;
+use symbol::{self, Symbol};
+
+/// A `syntax-rules` transformer together with the scope it was defined in,
+/// which is where the free identifiers of its templates are looked up.
+pub struct Macro {
+    ellipsis: Symbol,
+    literals: Vec<Symbol>,
+    rules: Vec<(Expr, Expr)>,
+    scope: Rc<Scope>,
+}
+
+/// What a pattern variable matched: a single form, or one entry per
+/// repetition of the ellipsis it sits under.
+#[derive(Clone)]
+enum Match {
+    One(Expr),
+    Many(Vec<Match>),
+}
+
+type Bindings = HashMap<Symbol, Match>;
+
+fn sym_name(exp: &Expr) -> Option<Symbol> {
+    match *exp {
+        Expr::Sym(name) => Some(name),
+        _ => None,
+    }
+}
+
+impl Macro {
+    /// The scope the macro was defined in.
+    pub fn scope(&self) -> &Rc<Scope> {
+        &self.scope
+    }
+
+    /// Calls `f` on each identifier in the macro's rules and literals.
+    /// Some may be aliases made when another macro's template defined
+    /// this one, which the expander must keep as long as the macro.
+    pub fn each_symbol(&self, f: &mut dyn FnMut(Symbol)) {
+        f(self.ellipsis);
+        for &name in &self.literals {
+            f(name);
+        }
+        let mut seen = HashSet::new();
+        let mut exps: Vec<Expr> = vec![];
+        for (pat, tmpl) in &self.rules {
+            exps.push(pat.clone());
+            exps.push(tmpl.clone());
+        }
+        while let Some(exp) = exps.pop() {
+            match exp {
+                Expr::Sym(name) => f(name),
+                // templates read with datum labels may share or loop
+                Expr::Pair(ref cell, _) if seen.insert(Rc::as_ptr(cell)) => {
+                    exps.extend(cell.borrow().iter().cloned())
+                }
+                Expr::Vector(ref vec) => exps.extend(vec.borrow().iter().cloned()),
+                _ => {}
+            }
+        }
+    }
+
+    /// Parses `(syntax-rules (literal ...) (pattern template) ...)`, or the
+    /// R7RS form with a custom ellipsis identifier before the literals.
+    pub fn parse(spec: &Expr, scope: Rc<Scope>) -> Result<Macro, SchemeError> {
+        let bad = || SchemeError::BadSyntax(spec.clone());
+        let (mut args, tail) = split_list(&spec.cdr().map_err(|_| bad())?)?;
+        if !tail.is_empty() || args.is_empty() {
+            return Err(bad());
+        }
+        let mut ellipsis = symbol::ELLIPSIS;
+        if let Some(name) = sym_name(&args[0]) {
+            ellipsis = name;
+            args.remove(0);
+        }
+        if args.is_empty() {
+            return Err(bad());
+        }
+        let (lits, tail) = split_list(&args.remove(0))?;
+        if !tail.is_empty() {
+            return Err(bad());
+        }
+        let mut literals = vec![];
+        for lit in lits {
+            literals.push(sym_name(&lit).ok_or_else(bad)?);
+        }
+        let mut rules = vec![];
+        for rule in args {
+            let (parts, tail) = split_list(&rule)?;
+            if parts.len() != 2 || !tail.is_empty() || !parts[0].is_pair() {
+                return Err(bad());
+            }
+            rules.push((parts[0].clone(), parts[1].clone()));
+        }
+        Ok(Macro {
+            ellipsis,
+            literals,
+            rules,
+            scope,
+        })
+    }
+
+    /// Rewrites one use of the macro, `form`, which appears in `use_scope`.
+    pub fn expand(
+        &self,
+        ex: &mut Expander,
+        form: &Expr,
+        use_scope: &Rc<Scope>,
+    ) -> Result<Expr, SchemeError> {
+        for (pattern, template) in &self.rules {
+            let mut binds = Bindings::new();
+            // the keyword position is ignored
+            if self.match_pattern(ex, &pattern.cdr()?, &form.cdr()?, use_scope, &mut binds) {
+                let mut renames = HashMap::new();
+                return self.transcribe(ex, template, &binds, &mut renames, false);
+            }
+        }
+        Err(SchemeError::BadSyntax(form.clone()))
+    }
+
+    fn is_ellipsis(&self, ex: &Expander, exp: &Expr) -> bool {
+        match sym_name(exp) {
+            Some(name) => ex.base_name(name) == ex.base_name(self.ellipsis),
+            None => false,
+        }
+    }
+
+    fn is_literal(&self, name: Symbol) -> bool {
+        self.literals.contains(&name)
+    }
+
+    fn match_pattern(
+        &self,
+        ex: &Expander,
+        pat: &Expr,
+        input: &Expr,
+        use_scope: &Rc<Scope>,
+        binds: &mut Bindings,
+    ) -> bool {
+        match *pat {
+            Expr::Sym(name) => {
+                if self.is_literal(name) {
+                    input.is_sym() && ex.free_identifier_eq(input, use_scope, pat, &self.scope)
+                } else {
+                    if ex.base_name(name) != symbol::UNDERSCORE {
+                        binds.insert(name, Match::One(input.clone()));
+                    }
+                    true
+                }
+            }
+            Expr::Pair(..) => {
+                // a cyclic pattern or form matches nothing
+                let ((pats, pat_tail), (items, input_tail)) =
+                    match (split_list(pat), split_list(input)) {
+                        (Ok(pat), Ok(input)) => (pat, input),
+                        _ => return false,
+                    };
+                match pats.iter().position(|p| self.is_ellipsis(ex, p)) {
+                    Some(0) => false,
+                    Some(pos) => {
+                        let (before, after) = (&pats[..pos - 1], &pats[pos + 1..]);
+                        if items.len() < before.len() + after.len() {
+                            return false;
+                        }
+                        let repeat_end = items.len() - after.len();
+                        let fixed = before
+                            .iter()
+                            .zip(&items)
+                            .chain(after.iter().zip(&items[repeat_end..]));
+                        for (p, item) in fixed {
+                            if !self.match_pattern(ex, p, item, use_scope, binds) {
+                                return false;
+                            }
+                        }
+                        let sub = &pats[pos - 1];
+                        let mut matches = vec![];
+                        for item in &items[before.len()..repeat_end] {
+                            let mut sub_binds = Bindings::new();
+                            if !self.match_pattern(ex, sub, item, use_scope, &mut sub_binds) {
+                                return false;
+                            }
+                            matches.push(sub_binds);
+                        }
+                        for var in self.pattern_vars(ex, sub) {
+                            let seq = matches.iter().map(|m| m[&var].clone()).collect();
+                            binds.insert(var, Match::Many(seq));
+                        }
+                        self.match_pattern(ex, &pat_tail, &input_tail, use_scope, binds)
+                    }
+                    None => {
+                        if items.len() < pats.len()
+                            || (pat_tail.is_empty() && items.len() != pats.len())
+                        {
+                            return false;
+                        }
+                        for (p, item) in pats.iter().zip(&items) {
+                            if !self.match_pattern(ex, p, item, use_scope, binds) {
+                                return false;
+                            }
+                        }
+                        let rest = join_list(items[pats.len()..].to_vec(), input_tail);
+                        self.match_pattern(ex, &pat_tail, &rest, use_scope, binds)
+                    }
+                }
+            }
+            Expr::Vector(ref pats) => match *input {
+                Expr::Vector(ref items) => {
+                    let pats = join_list(pats.borrow().clone(), Expr::Nil);
+                    let items = join_list(items.borrow().clone(), Expr::Nil);
+                    self.match_pattern(ex, &pats, &items, use_scope, binds)
+                }
+                _ => false,
+            },
+            _ => *pat == *input,
+        }
+    }
+
+    fn pattern_vars(&self, ex: &Expander, pat: &Expr) -> Vec<Symbol> {
+        match *pat {
+            Expr::Sym(name) => {
+                if self.is_literal(name)
+                    || self.is_ellipsis(ex, pat)
+                    || ex.base_name(name) == symbol::UNDERSCORE
+                {
+                    vec![]
+                } else {
+                    vec![name]
+                }
+            }
+            Expr::Pair(..) => {
+                let (items, tail) = match split_list(pat) {
+                    Ok(list) => list,
+                    Err(_) => return vec![],
+                };
+                let mut vars = vec![];
+                for item in items.iter().chain(Some(&tail)) {
+                    vars.extend(self.pattern_vars(ex, item));
+                }
+                vars
+            }
+            Expr::Vector(ref pats) => {
+                self.pattern_vars(ex, &join_list(pats.borrow().clone(), Expr::Nil))
+            }
+            _ => vec![],
+        }
+    }
+
+    fn transcribe(
+        &self,
+        ex: &mut Expander,
+        tmpl: &Expr,
+        binds: &Bindings,
+        renames: &mut HashMap<Symbol, Symbol>,
+        escaped: bool,
+    ) -> Result<Expr, SchemeError> {
+        match *tmpl {
+            Expr::Sym(name) => match binds.get(&name) {
+                Some(Match::One(exp)) => Ok(exp.clone()),
+                Some(&Match::Many(_)) => Err(SchemeError::BadSyntax(tmpl.clone())),
+                None => {
+                    let alias = renames
+                        .entry(name)
+                        .or_insert_with(|| ex.make_alias(name, self.scope.clone()));
+                    Ok(Expr::Sym(*alias))
+                }
+            },
+            Expr::Pair(..) => {
+                let (items, tail) = split_list(tmpl)?;
+                // (... template) escapes the ellipsis inside template
+                if !escaped
+                    && items.len() == 2
+                    && tail.is_empty()
+                    && self.is_ellipsis(ex, &items[0])
+                {
+                    return self.transcribe(ex, &items[1], binds, renames, true);
+                }
+                let mut res = vec![];
+                let mut i = 0;
+                while i < items.len() {
+                    let mut depth = 0;
+                    while !escaped
+                        && i + depth + 1 < items.len()
+                        && self.is_ellipsis(ex, &items[i + depth + 1])
+                    {
+                        depth += 1;
+                    }
+                    if depth == 0 {
+                        res.push(self.transcribe(ex, &items[i], binds, renames, escaped)?);
+                    } else {
+                        self.transcribe_repeat(ex, &items[i], depth, binds, renames, &mut res)?;
+                    }
+                    i += depth + 1;
+                }
+                let tail = self.transcribe(ex, &tail, binds, renames, escaped)?;
+                Ok(join_list(res, tail))
+            }
+            Expr::Vector(ref items) => {
+                let tmpl = join_list(items.borrow().clone(), Expr::Nil);
+                let (items, _) = split_list(&self.transcribe(ex, &tmpl, binds, renames, escaped)?)?;
+                Ok(Expr::new_vector(items))
+            }
+            _ => Ok(tmpl.clone()),
+        }
+    }
+
+    /// Expands `tmpl` followed by `depth` ellipses once per repetition of
+    /// the pattern variables it uses, appending the results to `out`.
+    fn transcribe_repeat(
+        &self,
+        ex: &mut Expander,
+        tmpl: &Expr,
+        depth: usize,
+        binds: &Bindings,
+        renames: &mut HashMap<Symbol, Symbol>,
+        out: &mut Vec<Expr>,
+    ) -> Result<(), SchemeError> {
+        let mut vars = vec![];
+        template_syms(tmpl, &mut vars);
+        let seqs: Vec<(Symbol, &Vec<Match>)> = vars
+            .iter()
+            .filter_map(|var| match binds.get(var) {
+                Some(Match::Many(seq)) => Some((*var, seq)),
+                _ => None,
+            })
+            .collect();
+        if seqs.is_empty() {
+            return Err(SchemeError::BadSyntax(tmpl.clone()));
+        }
+        let len = seqs[0].1.len();
+        if seqs.iter().any(|&(_, seq)| seq.len() != len) {
+            return Err(SchemeError::BadSyntax(tmpl.clone()));
+        }
+        for i in 0..len {
+            let mut iter_binds = binds.clone();
+            for &(var, seq) in &seqs {
+                iter_binds.insert(var, seq[i].clone());
+            }
+            if depth > 1 {
+                self.transcribe_repeat(ex, tmpl, depth - 1, &iter_binds, renames, out)?;
+            } else {
+                out.push(self.transcribe(ex, tmpl, &iter_binds, renames, false)?);
+            }
+        }
+        Ok(())
+    }
+}
+
+fn template_syms(tmpl: &Expr, out: &mut Vec<Symbol>) {
+    match *tmpl {
+        Expr::Sym(name) => out.push(name),
+        Expr::Pair(ref cell, _) => {
+            let cell = cell.borrow();
+            template_syms(&cell[0], out);
+            template_syms(&cell[1], out);
+        }
+        Expr::Vector(ref items) => {
+            for item in items.borrow().iter() {
+                template_syms(item, out);
+            }
+        }
+        _ => {}
+    }
+}
@@ -0,0 +1,627 @@
+use std::cell::RefCell;
+use std::fmt;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use ast::*;
+use compile::{fill_quasi, Code, Op};
+use env::*;
+use error::SchemeError;
+use gc;
+
+/// Something waiting for a value on the machine's frame stack: a procedure
+/// call waiting for its callee to return, or a step of `dynamic-wind` or
+/// of entering a continuation. The frames, together with the value stack,
+/// are the continuation of the code running: `call/cc` copies them, and
+/// invoking a continuation puts the copies back.
+#[derive(Clone)]
+pub enum Frame {
+    /// Code to carry on with at `pc` in an environment, with the value
+    /// pushed on the stack.
+    Code(Rc<Code>, usize, Rc<RefCell<Env>>),
+    /// `dynamic-wind` once its `before` thunk has returned, with the
+    /// `before`, `thunk` and `after` arguments.
+    WindBefore(Expr, Expr, Expr),
+    /// `dynamic-wind` once its thunk has returned: the `after` thunk and
+    /// the winders to restore.
+    WindAfter(Expr, Winders),
+    /// Ignores what it is given and returns its own value instead.
+    Deliver(Expr),
+    /// On the way into a continuation: the `before` and `after` thunks
+    /// still to run (last first), each with the winders in effect while it
+    /// runs, then the winders and value the continuation gets.
+    Reroot(Vec<(Expr, Winders)>, Winders, Expr),
+}
+
+/// The `dynamic-wind` calls whose thunk is running, innermost first.
+pub type Winders = Option<Rc<Winder>>;
+
+/// One `dynamic-wind` call in a chain of winders. Continuations share the
+/// chains they capture, so each winder is an object on the collected heap
+/// of its own rather than part of the continuations that hold it.
+pub struct Winder {
+    before: Expr,
+    after: Expr,
+    parent: Winders,
+    depth: usize,
+}
+
+impl Winder {
+    /// Pushes the heap objects the winder refers to.
+    pub fn trace(&self, out: &mut Vec<usize>) {
+        gc::expr_children(&self.before, out);
+        gc::expr_children(&self.after, out);
+        trace_winders(&self.parent, out);
+    }
+}
+
+fn trace_winders(winders: &Winders, out: &mut Vec<usize>) {
+    if let Some(ref winder) = *winders {
+        out.push(gc::addr(winder));
+    }
+}
+
+fn winders_depth(winders: &Winders) -> usize {
+    winders.as_ref().map_or(0, |w| w.depth)
+}
+
+fn same_winders(a: &Winders, b: &Winders) -> bool {
+    match (a, b) {
+        (Some(a), Some(b)) => Rc::ptr_eq(a, b),
+        (None, None) => true,
+        _ => false,
+    }
+}
+
+/// The thunks to run when control moves from inside the winders `from` to
+/// inside `to`: the `after` thunks of the extents being left, innermost
+/// first, then the `before` thunks of those being entered, outermost first.
+/// Each comes with the winders in effect while it runs.
+fn wind_path(from: &Winders, to: &Winders) -> Vec<(Expr, Winders)> {
+    let (mut from, mut to) = (from.clone(), to.clone());
+    let mut leave = vec![];
+    let mut enter = vec![];
+    while winders_depth(&from) > winders_depth(&to) {
+        let w = from.unwrap();
+        leave.push((w.after.clone(), w.parent.clone()));
+        from = w.parent.clone();
+    }
+    while winders_depth(&to) > winders_depth(&from) {
+        let w = to.unwrap();
+        enter.push((w.before.clone(), w.parent.clone()));
+        to = w.parent.clone();
+    }
+    while !same_winders(&from, &to) {
+        let (f, t) = (from.unwrap(), to.unwrap());
+        leave.push((f.after.clone(), f.parent.clone()));
+        enter.push((t.before.clone(), t.parent.clone()));
+        from = f.parent.clone();
+        to = t.parent.clone();
+    }
+    leave.extend(enter.into_iter().rev());
+    leave
+}
+
+/// A continuation captured by `call/cc`: a copy of the machine's stacks
+/// and of the `dynamic-wind` state at that point.
+pub struct Continuation {
+    stack: RefCell<Vec<Expr>>,
+    frames: RefCell<Vec<Frame>>,
+    winders: RefCell<Winders>,
+}
+
+impl PartialEq for Continuation {
+    fn eq(&self, other: &Continuation) -> bool {
+        ::std::ptr::eq(self, other)
+    }
+}
+
+impl fmt::Debug for Continuation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "continuation")
+    }
+}
+
+impl Continuation {
+    /// Pushes the heap objects the continuation refers to. Returns false
+    /// if it is in use and cannot be looked into.
+    pub fn trace(&self, out: &mut Vec<usize>) -> bool {
+        let (stack, frames, winders) = match (
+            self.stack.try_borrow(),
+            self.frames.try_borrow(),
+            self.winders.try_borrow(),
+        ) {
+            (Ok(stack), Ok(frames), Ok(winders)) => (stack, frames, winders),
+            _ => return false,
+        };
+        for val in stack.iter() {
+            gc::expr_children(val, out);
+        }
+        for frame in frames.iter() {
+            let mut exps = vec![];
+            match *frame {
+                Frame::Code(_, _, ref env) => out.push(gc::addr(env)),
+                Frame::WindBefore(ref a, ref b, ref c) => {
+                    exps.push(a);
+                    exps.push(b);
+                    exps.push(c);
+                }
+                Frame::WindAfter(ref a, ref w) => {
+                    exps.push(a);
+                    trace_winders(w, out);
+                }
+                Frame::Deliver(ref a) => exps.push(a),
+                Frame::Reroot(ref steps, ref w, ref a) => {
+                    for (thunk, w) in steps {
+                        exps.push(thunk);
+                        trace_winders(w, out);
+                    }
+                    trace_winders(w, out);
+                    exps.push(a);
+                }
+            }
+            for exp in exps {
+                gc::expr_children(exp, out);
+            }
+        }
+        trace_winders(&winders, out);
+        true
+    }
+
+    /// Drops everything the continuation holds, to break a cycle it is in.
+    pub fn clear(&self, trash: &mut Vec<Expr>, frames: &mut Vec<Frame>) {
+        if let (Ok(mut stack), Ok(mut saved), Ok(mut winders)) = (
+            self.stack.try_borrow_mut(),
+            self.frames.try_borrow_mut(),
+            self.winders.try_borrow_mut(),
+        ) {
+            trash.append(&mut stack);
+            frames.append(&mut saved);
+            *winders = None;
+        }
+    }
+}
+
+/// The registers of the machine: the instruction to run next, the
+/// environment it runs in, the values computed so far and the frames
+/// waiting for the current code to return.
+struct State {
+    code: Rc<Code>,
+    pc: usize,
+    env: Rc<RefCell<Env>>,
+    stack: Vec<Expr>,
+    frames: Vec<Frame>,
+}
+
+/// The location of the instruction before `pc`, the one that was running.
+fn span_before(code: &Code, pc: usize) -> Option<Rc<Span>> {
+    if pc == 0 {
+        None
+    } else {
+        code.spans[pc - 1].clone()
+    }
+}
+
+/// A stack machine that runs compiled code. Procedure calls push a frame
+/// instead of recursing on the Rust stack, so deep recursion does not
+/// overflow it, and calls in tail position push none, so loops written as
+/// tail calls run in constant space.
+pub struct Vm {
+    pub globals: Globals,
+    top: Rc<RefCell<Env>>,
+    winders: Winders,
+    interrupted: Arc<AtomicBool>,
+}
+
+impl Default for Vm {
+    fn default() -> Vm {
+        Vm::new()
+    }
+}
+
+impl Vm {
+    pub fn new() -> Vm {
+        Vm {
+            globals: Globals::new(),
+            top: Env::top(),
+            winders: None,
+            interrupted: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    /// A flag that makes the code running stop with an `Interrupted` error
+    /// at its next procedure call once it is set. Every loop goes through
+    /// one, so this stops anything but a builtin that does not return.
+    /// Another thread, such as a Ctrl-C handler, can set it; the machine
+    /// clears it when it stops.
+    pub fn interrupt_flag(&self) -> Arc<AtomicBool> {
+        self.interrupted.clone()
+    }
+
+    /// Runs top-level `code` until it returns. An error is tagged with the
+    /// span of the innermost form being evaluated that has one; it abandons
+    /// the computation without running any `dynamic-wind` thunks.
+    pub fn run(&mut self, code: Rc<Code>) -> Result<Expr, SchemeError> {
+        gc::maybe_collect();
+        let mut st = self.state(code);
+        let res = self.finish(&mut st);
+        if res.is_err() {
+            self.winders = None;
+        }
+        res
+    }
+
+    /// Calls the procedure `func` with `args` and runs it until it returns,
+    /// as a Rust caller would. A builtin can use it to call back into
+    /// Scheme. An error abandons only the call.
+    pub fn call(&mut self, func: Expr, args: Vec<Expr>) -> Result<Expr, SchemeError> {
+        let winders = self.winders.clone();
+        let mut st = self.state(Rc::new(Code::default()));
+        let res = match self.enter(&mut st, func.clone(), args, &func) {
+            Ok(Some(val)) => Ok(val),
+            Ok(None) => self.finish(&mut st),
+            Err(err) => Err(err),
+        };
+        if res.is_err() {
+            self.winders = winders;
+        }
+        res
+    }
+
+    /// Fresh registers for running `code` at top level.
+    fn state(&self, code: Rc<Code>) -> State {
+        State {
+            code,
+            pc: 0,
+            env: self.top.clone(),
+            stack: vec![],
+            frames: vec![],
+        }
+    }
+
+    /// Steps until the code started in `st` returns. An error is tagged
+    /// with the span of the innermost form being evaluated that has one.
+    fn finish(&mut self, st: &mut State) -> Result<Expr, SchemeError> {
+        loop {
+            match self.step(st) {
+                Ok(None) => {}
+                Ok(Some(val)) => return Ok(val),
+                Err(err) => {
+                    let err = err.at(span_before(&st.code, st.pc));
+                    let frames = st.frames.iter().rev();
+                    return Err(frames.fold(err, |err, frame| match *frame {
+                        Frame::Code(ref code, pc, _) => err.at(span_before(code, pc)),
+                        _ => err,
+                    }));
+                }
+            }
+        }
+    }
+
+    /// Runs one instruction. Returns the final value once the code that
+    /// `run` started with has returned.
+    fn step(&mut self, st: &mut State) -> Result<Option<Expr>, SchemeError> {
+        let op = st.code.ops[st.pc];
+        st.pc += 1;
+        match op {
+            Op::Const(i) => st.stack.push(st.code.consts[i].clone()),
+            Op::Local(depth, index) => match st.env.borrow().get(depth, index) {
+                Some(val) => st.stack.push(val),
+                None => {
+                    let var = st.env.borrow().name(depth, index);
+                    return Err(SchemeError::UnboundVariable(Expr::Sym(var)));
+                }
+            },
+            Op::SetLocal(depth, index) => {
+                let val = st.stack.pop().unwrap();
+                st.env.borrow_mut().set(depth, index, val);
+            }
+            Op::Global(slot) => match self.globals.get(slot) {
+                Some(val) => st.stack.push(val.clone()),
+                None => {
+                    let var = self.globals.name(slot);
+                    return Err(SchemeError::UnboundVariable(Expr::Sym(var)));
+                }
+            },
+            Op::SetGlobal(slot) => {
+                let val = st.stack.pop().unwrap();
+                self.globals.set(slot, val);
+            }
+            Op::Pop => {
+                st.stack.pop();
+            }
+            Op::Dup => {
+                let val = st.stack.last().unwrap().clone();
+                st.stack.push(val);
+            }
+            Op::Swap => {
+                let len = st.stack.len();
+                st.stack.swap(len - 2, len - 1);
+            }
+            Op::Memv(i) => {
+                let key = st.stack.last().unwrap();
+                let (datums, _) = split_list(&st.code.consts[i])?;
+                let found = datums.iter().any(|datum| datum.eqv(key));
+                st.stack.push(Expr::Bool(found));
+            }
+            Op::Jump(target) => st.pc = target,
+            Op::JumpIfFalse(target) => {
+                if st.stack.pop().unwrap().is_false() {
+                    st.pc = target;
+                }
+            }
+            Op::AndJump(target) | Op::OrJump(target) => {
+                let is_and = op == Op::AndJump(target);
+                if st.stack.last().unwrap().is_true() != is_and {
+                    st.pc = target;
+                } else {
+                    st.stack.pop();
+                }
+            }
+            Op::Closure(i) => {
+                let lambda = st.code.lambdas[i].clone();
+                st.stack.push(Expr::new_cproc(lambda, st.env.clone()));
+            }
+            Op::Call(argc, name) | Op::TailCall(argc, name) => {
+                gc::maybe_collect();
+                if self.interrupted.load(Ordering::Relaxed) {
+                    self.interrupted.store(false, Ordering::Relaxed);
+                    return Err(SchemeError::Interrupted);
+                }
+                let args = st.stack.split_off(st.stack.len() - argc);
+                let func = st.stack.pop().unwrap();
+                let code = st.code.clone();
+                let tail = op == Op::TailCall(argc, name);
+                return self.apply(st, func, args, &code.consts[name], tail);
+            }
+            Op::Return => {
+                let val = st.stack.pop().unwrap();
+                return self.ret(st, val);
+            }
+            Op::Quasi(i, n) => {
+                let vals = st.stack.split_off(st.stack.len() - n);
+                let val = fill_quasi(&st.code.consts[i], 0, &mut vals.into_iter())?;
+                st.stack.push(val);
+            }
+        }
+        Ok(None)
+    }
+
+    /// Calls `func` with `args`. A call not in tail position pushes a frame
+    /// to come back to, except for builtins, which return right away.
+    /// `name` is what an arity error calls the procedure.
+    fn apply(
+        &mut self,
+        st: &mut State,
+        func: Expr,
+        args: Vec<Expr>,
+        name: &Expr,
+        tail: bool,
+    ) -> Result<Option<Expr>, SchemeError> {
+        if let Expr::Proc(ref builtin) = func {
+            let val = self.call_builtin(builtin, &args)?;
+            if tail {
+                return self.ret(st, val);
+            }
+            st.stack.push(val);
+            return Ok(None);
+        }
+        if !tail {
+            st.frames
+                .push(Frame::Code(st.code.clone(), st.pc, st.env.clone()));
+        }
+        self.enter(st, func, args, name)
+    }
+
+    /// Runs a builtin on `args` once their number is checked. An arity
+    /// error names the builtin itself.
+    fn call_builtin(&mut self, builtin: &Builtin, args: &[Expr]) -> Result<Expr, SchemeError> {
+        if !builtin.arity.accepts(args.len()) {
+            return Err(SchemeError::ArityMismatch(
+                Expr::Sym(builtin.name),
+                builtin.arity.clone(),
+                args.len(),
+            ));
+        }
+        (builtin.func)(self, args)
+    }
+
+    /// Runs `func` on `args` in place of the current code, so that its
+    /// value goes to the frame on top of the stack.
+    fn enter(
+        &mut self,
+        st: &mut State,
+        func: Expr,
+        mut args: Vec<Expr>,
+        name: &Expr,
+    ) -> Result<Option<Expr>, SchemeError> {
+        let check_arity = |expected: usize, args: &Vec<Expr>| {
+            if args.len() == expected {
+                Ok(())
+            } else {
+                Err(SchemeError::ArityMismatch(
+                    name.clone(),
+                    Arity::Exactly(expected),
+                    args.len(),
+                ))
+            }
+        };
+        match func {
+            Expr::Proc(ref builtin) => {
+                let val = self.call_builtin(builtin, &args)?;
+                self.ret(st, val)
+            }
+            Expr::CompProc(ref lambda, ref env) => {
+                let lambda = match lambda.select(args.len()) {
+                    Some(lambda) => lambda,
+                    None => {
+                        return Err(SchemeError::ArityMismatch(
+                            name.clone(),
+                            lambda.arity(),
+                            args.len(),
+                        ))
+                    }
+                };
+                if lambda.rest {
+                    let rest = args.split_off(lambda.nparams);
+                    args.push(join_list(rest, Expr::Nil));
+                }
+                st.env = Env::extend(env, lambda.names.clone(), args);
+                st.code = lambda.code.clone();
+                st.pc = 0;
+                Ok(None)
+            }
+            Expr::Control(Control::CallCc) => {
+                check_arity(1, &args)?;
+                let cont = Rc::new(Continuation {
+                    stack: RefCell::new(st.stack.clone()),
+                    frames: RefCell::new(st.frames.clone()),
+                    winders: RefCell::new(self.winders.clone()),
+                });
+                gc::track_cont(&cont);
+                let receiver = args.remove(0);
+                self.enter(st, receiver.clone(), vec![Expr::Cont(cont)], &receiver)
+            }
+            Expr::Control(Control::DynamicWind) => {
+                check_arity(3, &args)?;
+                let after = args.pop().unwrap();
+                let thunk = args.pop().unwrap();
+                let before = args.pop().unwrap();
+                st.frames
+                    .push(Frame::WindBefore(before.clone(), thunk, after));
+                self.enter(st, before, vec![], name)
+            }
+            Expr::Control(Control::Apply) => {
+                if args.len() < 2 {
+                    return Err(SchemeError::ArityMismatch(
+                        name.clone(),
+                        Arity::AtLeast(2),
+                        args.len(),
+                    ));
+                }
+                let func = args.remove(0);
+                let last = args.pop().unwrap();
+                let (rest, tail) = split_list(&last)?;
+                if !tail.is_empty() {
+                    return Err(SchemeError::wrong_type("list", &last));
+                }
+                args.extend(rest);
+                self.enter(st, func.clone(), args, &func)
+            }
+            Expr::Cont(ref cont) => {
+                check_arity(1, &args)?;
+                let winders = cont.winders.borrow().clone();
+                let mut thunks = wind_path(&self.winders, &winders);
+                thunks.reverse();
+                st.stack = cont.stack.borrow().clone();
+                st.frames = cont.frames.borrow().clone();
+                st.frames
+                    .push(Frame::Reroot(thunks, winders, args.remove(0)));
+                // the value goes to the frame just pushed, which ignores it
+                self.ret(st, Expr::Nil)
+            }
+            _ => Err(SchemeError::wrong_type("procedure", &func)),
+        }
+    }
+
+    /// Hands `val` to the frame on top of the stack. Returns it as the
+    /// final value if there is none.
+    fn ret(&mut self, st: &mut State, val: Expr) -> Result<Option<Expr>, SchemeError> {
+        let name = || Expr::new_sym("dynamic-wind");
+        match st.frames.pop() {
+            None => Ok(Some(val)),
+            Some(Frame::Code(code, pc, env)) => {
+                st.code = code;
+                st.pc = pc;
+                st.env = env;
+                st.stack.push(val);
+                Ok(None)
+            }
+            Some(Frame::WindBefore(before, thunk, after)) => {
+                let outer = self.winders.clone();
+                let winder = Rc::new(Winder {
+                    before,
+                    after: after.clone(),
+                    parent: outer.clone(),
+                    depth: winders_depth(&outer) + 1,
+                });
+                gc::track_winder(&winder);
+                self.winders = Some(winder);
+                st.frames.push(Frame::WindAfter(after, outer));
+                self.enter(st, thunk, vec![], &name())
+            }
+            Some(Frame::WindAfter(after, outer)) => {
+                self.winders = outer;
+                st.frames.push(Frame::Deliver(val));
+                self.enter(st, after, vec![], &name())
+            }
+            Some(Frame::Deliver(val)) => self.ret(st, val),
+            Some(Frame::Reroot(mut thunks, winders, val)) => match thunks.pop() {
+                Some((thunk, during)) => {
+                    self.winders = during;
+                    st.frames.push(Frame::Reroot(thunks, winders, val));
+                    self.enter(st, thunk, vec![], &name())
+                }
+                None => {
+                    self.winders = winders;
+                    self.ret(st, val)
+                }
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use compile::compile;
+    use parser::Parser;
+    use std;
+
+    fn run(vm: &mut Vm, code: &str) -> Result<Expr, SchemeError> {
+        let mut parser = Parser::new_from(std::io::stdin(), false);
+        parser.load(code.to_string());
+        let exp = parser.read_exp().unwrap().unwrap();
+        let code = compile(&exp, &mut vm.globals).unwrap();
+        vm.run(code)
+    }
+
+    #[test]
+    fn test_vm() {
+        let mut vm = Vm::new();
+        run(
+            &mut vm,
+            "(define (f n) (if (= n 0) '() (cons n (f (- n 1)))))",
+        )
+        .unwrap();
+        assert_eq!(run(&mut vm, "(f 3)").unwrap().to_string(), "(3 2 1)");
+        // the values pending in the caller survive the call
+        let res = run(&mut vm, "(+ 1 (* 2 ((lambda (x) (+ x 1)) 3)) 4)");
+        assert_eq!(res, Ok(Expr::Int(13)));
+        let res = run(&mut vm, "(cond (#f 1) ((car '(5))) (else 2))");
+        assert_eq!(res, Ok(Expr::Int(5)));
+        let res = run(&mut vm, "(or #f (and 1 #f) (and))");
+        assert_eq!(res, Ok(Expr::Bool(true)));
+        // an error leaves nothing behind for the next run
+        assert!(run(&mut vm, "(+ 1 (f 'x))").is_err());
+        assert_eq!(run(&mut vm, "(f 1)").unwrap().to_string(), "(1)");
+    }
+
+    #[test]
+    fn test_interrupt() {
+        let mut vm = Vm::new();
+        let flag = vm.interrupt_flag();
+        vm.globals.def_fn("stop!", Arity::Exactly(0), move |_, _| {
+            flag.store(true, Ordering::Relaxed);
+            Ok(Expr::Nil)
+        });
+        run(&mut vm, "(define (spin) (spin))").unwrap();
+        assert_eq!(
+            run(&mut vm, "(begin (stop!) (spin))"),
+            Err(SchemeError::Interrupted)
+        );
+        // the flag is cleared once it has stopped something
+        assert_eq!(run(&mut vm, "(car '(1))"), Ok(Expr::Int(1)));
+    }
+}